@@ -5,6 +5,7 @@ use crate::{dir, get_data, get_data_mut, save_data, sync_lang, data::THEMES};
 use anyhow::{Context, Result};
 use macroquad::prelude::*;
 use prpr::{
+    config::AspectMode,
     core::{ParticleEmitter, ResourcePack, JUDGE_LINE_PERFECT_COLOR, NOTE_WIDTH_RATIO_BASE},
     ext::{create_audio_manger, poll_future, LocalTask, RectExt, SafeTexture},
     scene::{request_file, return_file, show_error, show_message, take_file},
@@ -181,7 +182,12 @@ impl Page for SettingsPage {
                 ui.dy(r.h + s);
                 let r = ui.checkbox(tl!("double-tips"), &mut config.multiple_hint);
                 ui.dy(r.h + s);
-                let r = ui.checkbox(tl!("fixed-aspect-ratio"), &mut config.fix_aspect_ratio);
+                let fixed = config.aspect_mode == AspectMode::Fit;
+                let mut new_fixed = fixed;
+                let r = ui.checkbox(tl!("fixed-aspect-ratio"), &mut new_fixed);
+                if new_fixed != fixed {
+                    config.aspect_mode = if new_fixed { AspectMode::Fit } else { AspectMode::Auto };
+                }
                 ui.dy(r.h + s);
                 let r = ui.checkbox(tl!("time-adjustment"), &mut config.adjust_time);
                 ui.dy(r.h + s);
@@ -269,7 +269,6 @@ async fn the_main() -> Result<()> {
         move || *(*my_time).borrow()
     }));
     let fs = Box::new(PatchedFileSystem(fs, edit.to_patches().await?));
-    static MSAA: AtomicBool = AtomicBool::new(false);
     let mut main = Main::new(
         Box::new(LoadingScene::new(GameMode::Normal, edit.info, config, fs, (None, None), Some(Rc::new(move || (vw, vh))), None).await?),
         tm,
@@ -295,7 +294,6 @@ async fn the_main() -> Result<()> {
     const A: f64 = 0.7 + 0.3 + 0.4;
 
     let fps = v_config.fps;
-    let frame_delta = 1. / fps as f32;
 
     let codecs = String::from_utf8(Command::new(&ffmpeg).arg("-codecs").output().context("无法执行 ffmpeg")?.stdout)?;
     let use_cuda = v_config.hardware_accel && codecs.contains("h264_nvenc");
@@ -327,30 +325,16 @@ async fn the_main() -> Result<()> {
         .context("无法执行 ffmpeg")?;
     let mut input = proc.stdin.take().unwrap();
 
-    let mut bytes = vec![0; vw as usize * vh as usize * 3];
-
-    let frames = (video_length / frame_delta as f64).ceil() as u64;
+    let frames = frame_count(video_length, fps);
     let start_time = Instant::now();
 
-    for frame in 0..frames {
-        *my_time.borrow_mut() = (frame as f32 * frame_delta).max(0.) as f64;
-        gl.quad_gl.render_pass(Some(mst.output().render_pass));
-        clear_background(BLACK);
-        main.update()?;
-        main.render(&mut Ui::new(&mut painter))?;
-        // TODO magic. can't remove this line.
-        draw_rectangle(0., 0., 0., 0., Color::default());
-        gl.flush();
-
-        if MSAA.load(Ordering::SeqCst) {
-            mst.blit();
-        }
-        mst.output().texture.raw_miniquad_texture_handle().read_pixels(&mut bytes);
-        input.write_all(&bytes)?;
+    export_frames(&mut main, &mut painter, &mst, &my_time, fps, video_length, |frame, bytes| {
+        input.write_all(bytes)?;
         if frame % 100 == 0 {
             info!("{frame} / {frames}, {:.2}fps", frame as f64 / start_time.elapsed().as_secs_f64());
         }
-    }
+        Ok(())
+    })?;
     drop(input);
     proc.wait()?;
 
@@ -368,9 +352,78 @@ async fn the_main() -> Result<()> {
     Ok(())
 }
 
+static MSAA: AtomicBool = AtomicBool::new(false);
+
+/// Number of frames of a `length` seconds long video at `fps`.
+fn frame_count(length: f64, fps: u32) -> u64 {
+    (length * fps as f64).ceil() as u64
+}
+
+/// Calls `f` with the index and time of each frame of a `length` seconds long video at `fps`, in order.
+fn for_each_frame(length: f64, fps: u32, mut f: impl FnMut(u64, f64) -> Result<()>) -> Result<()> {
+    for frame in 0..frame_count(length, fps) {
+        f(frame, frame as f64 / fps as f64)?;
+    }
+    Ok(())
+}
+
+/// Renders `length` seconds of `main` onto `target` at a fixed framerate, driving the clock of `main` through `time`, and
+/// hands each frame to `on_frame` along with its index, as raw RGB pixels (bottom row first).
+fn export_frames(
+    main: &mut Main,
+    painter: &mut TextPainter,
+    target: &MSRenderTarget,
+    time: &RefCell<f64>,
+    fps: u32,
+    length: f64,
+    mut on_frame: impl FnMut(u64, &[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut gl = unsafe { get_internal_gl() };
+    let (width, height) = (target.output().texture.width() as usize, target.output().texture.height() as usize);
+    let mut bytes = vec![0; width * height * 3];
+    for_each_frame(length, fps, |frame, frame_time| {
+        *time.borrow_mut() = frame_time;
+        gl.quad_gl.render_pass(Some(target.output().render_pass));
+        clear_background(BLACK);
+        main.update()?;
+        main.render(&mut Ui::new(painter))?;
+        // TODO magic. can't remove this line.
+        draw_rectangle(0., 0., 0., 0., Color::default());
+        gl.flush();
+
+        if MSAA.load(Ordering::SeqCst) {
+            target.blit();
+        }
+        target.output().texture.raw_miniquad_texture_handle().read_pixels(&mut bytes);
+        on_frame(frame, &bytes)
+    })
+}
+
 #[macroquad::main(build_conf)]
 async fn main() {
     if let Err(err) = the_main().await {
         let _ = std::fs::write("错误信息.txt", format!("发生错误：{err:?}"));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_at_fixed_rate() {
+        let mut times = Vec::new();
+        for_each_frame(2., 60, |frame, time| {
+            assert_eq!(frame, times.len() as u64);
+            times.push(time);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(times.len(), 120);
+        assert_eq!(times[0], 0.);
+        assert!(times.windows(2).all(|it| it[0] < it[1]));
+        assert!(*times.last().unwrap() < 2.);
+        // a partial frame at the end still gets rendered
+        assert_eq!(frame_count(2.01, 60), 121);
+    }
+}
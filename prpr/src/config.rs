@@ -1,5 +1,5 @@
 use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 pub static TIPS: Lazy<Vec<String>> = Lazy::new(|| include_str!("tips.txt").split('\n').map(str::to_owned).collect());
 
@@ -14,30 +14,111 @@ pub enum ChallengeModeColor {
     Rainbow,
 }
 
+/// How the chart's aspect ratio is fitted to the screen's.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AspectMode {
+    /// Narrower screens show a narrower playfield, wider ones are pillarboxed
+    #[default]
+    Auto,
+    /// Always keeps the chart's aspect ratio, letterboxing or pillarboxing as needed
+    Fit,
+    /// Fills the screen, stretching the playfield
+    Stretch,
+}
+
+impl AspectMode {
+    /// Also accepts the `fixAspectRatio` flag this replaced, `true` meaning [`Self::Fit`], so that older configs keep their
+    /// setting.
+    fn deserialize_compat<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Compat {
+            Mode(AspectMode),
+            Fixed(bool),
+        }
+        Ok(match Compat::deserialize(deserializer)? {
+            Compat::Mode(mode) => mode,
+            Compat::Fixed(true) => Self::Fit,
+            Compat::Fixed(false) => Self::Auto,
+        })
+    }
+}
+
+/// How line inclines are applied to notes.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum InclineMode {
+    /// Notes further from the line are pulled towards (or pushed from) its center
+    #[default]
+    Shear,
+    /// Notes further from the line are drawn smaller as well, as if they were further away
+    Depth,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(default)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
     pub adjust_time: bool,
     pub aggressive: bool,
+    /// If set, a guide is drawn above judge lines where notes are this many beats ahead, for practicing.
+    pub approach_guide_beats: Option<f32>,
+    /// Notes are invisible this far (or further) from the line, and grow and fade in linearly to full size as they approach it.
+    pub approach_distance: Option<f32>,
+    /// If set, a soft tick of this volume (relative to `volume_sfx`) is played whenever a note comes within
+    /// `approach_distance` of its line (a screen height if unset), to help learning its timing.
+    pub approach_sound: Option<f32>,
+    #[serde(alias = "fixAspectRatio", deserialize_with = "AspectMode::deserialize_compat")]
+    pub aspect_mode: AspectMode,
     pub aspect_ratio: Option<f32>,
     pub audio_buffer_size: Option<u32>,
     pub autoplay: bool,
     pub challenge_color: ChallengeModeColor,
     pub challenge_rank: u32,
+    /// If set, an effect is fired every time the combo reaches a multiple of this
+    pub combo_milestone: Option<u32>,
     pub debug: bool,
+    /// Notes scroll slower through dense sections and faster through sparse ones, bringing dense notes closer together
+    /// on screen. Only affects rendering.
+    pub density_scroll: bool,
     pub disable_effect: bool,
     pub double_click_to_pause: bool,
-    pub fix_aspect_ratio: bool,
+    /// Notes within this distance of the top or bottom edge of the playfield fade out towards it, instead of being cut off.
+    pub edge_fade: Option<f32>,
+    /// Notes closer than this to the line fade out as they reach it, keeping the hit point clear.
+    pub fade_near_line: Option<f32>,
+    /// Maximum angle (in degrees) between a swipe and the direction of a directional flick for the swipe to hit it.
+    pub flick_tolerance: f32,
     pub fxaa: bool,
+    /// If set, overrides how long (in chart time) hit effects last, instead of the duration given by the resource pack.
+    pub hit_fx_duration: Option<f32>,
+    /// Holds released early are credited for the fraction they were held for, instead of counting for nothing.
+    pub hold_partial_credit: bool,
+    pub incline_mode: InclineMode,
     pub interactive: bool,
+    /// Judged notes shrink and fade out over this many seconds instead of vanishing at once.
+    pub judged_fade: f32,
+    /// Extra time after a note's bad window during which it can still be hit (as a bad) before it's missed.
+    pub late_grace: f32,
+    /// If set, touches further than this from a judge line (perpendicular to it, in the units of note positions) can't hit its notes.
+    /// Independent of the timing windows.
+    pub line_tolerance: Option<f32>,
+    pub max_frame_delta: Option<f32>,
+    /// Maximum magnitude of line inclines (in degrees), larger ones are clamped to avoid extreme skew.
+    pub max_incline: f32,
     pub multiple_hint: bool,
     pub note_scale: f32,
+    /// Notes of the same speed are drawn in time order, earlier ones beneath later ones, rather than by height.
+    pub note_time_order: bool,
     pub offset: f32,
     pub particle: bool,
     pub player_name: String,
     pub player_rks: f32,
+    /// Keeps the game time in `f64` when sampling animations, avoiding jitter far into long charts.
+    pub precise_time: bool,
     pub sample_count: u32,
+    pub show_early_late: bool,
     pub res_pack_path: Option<String>,
     pub speed: f32,
     pub volume_music: f32,
@@ -49,28 +130,68 @@ impl Default for Config {
         Self {
             adjust_time: true,
             aggressive: true,
+            approach_guide_beats: None,
+            approach_distance: None,
+            approach_sound: None,
+            aspect_mode: AspectMode::Auto,
             aspect_ratio: None,
             audio_buffer_size: None,
             autoplay: false,
             challenge_color: ChallengeModeColor::Golden,
             challenge_rank: 45,
+            combo_milestone: None,
             debug: false,
+            density_scroll: false,
             disable_effect: false,
             double_click_to_pause: true,
-            fix_aspect_ratio: false,
+            edge_fade: None,
+            fade_near_line: None,
+            flick_tolerance: 45.,
             fxaa: false,
+            hit_fx_duration: None,
+            hold_partial_credit: false,
+            incline_mode: InclineMode::Shear,
             interactive: true,
+            judged_fade: 0.,
+            late_grace: 0.,
+            line_tolerance: None,
+            max_frame_delta: None,
+            max_incline: 90.,
             multiple_hint: true,
             note_scale: 1.0,
+            note_time_order: false,
             offset: 0.,
             res_pack_path: None,
             particle: true,
             player_name: "Mivik".to_string(),
             player_rks: 15.,
+            precise_time: false,
             sample_count: 4,
+            show_early_late: false,
             speed: 1.,
             volume_music: 1.,
             volume_sfx: 1.,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aspect_mode_from_old_flag() {
+        let mode = |json: &str| serde_json::from_str::<Config>(json).unwrap().aspect_mode;
+        assert_eq!(mode(r#"{"fixAspectRatio":true}"#), AspectMode::Fit);
+        assert_eq!(mode(r#"{"fixAspectRatio":false}"#), AspectMode::Auto);
+        assert_eq!(mode(r#"{"aspectMode":"stretch"}"#), AspectMode::Stretch);
+        assert_eq!(mode("{}"), AspectMode::Auto);
+    }
+
+    #[test]
+    fn hit_fx_duration_overrides_pack() {
+        let config = |json: &str| serde_json::from_str::<Config>(json).unwrap().hit_fx_duration;
+        assert_eq!(config("{}"), None);
+        assert_eq!(config(r#"{"hitFxDuration":0.3}"#), Some(0.3));
+    }
+}
@@ -8,6 +8,65 @@ pub const EPS: f32 = 1e-5;
 pub const JUDGE_LINE_PERFECT_COLOR: Color = Color::new(1., 0.921875, 0.623, 0.8823529);
 pub const JUDGE_LINE_GOOD_COLOR: Color = Color::new(0.7058823, 0.8823529, 1., 0.9215686);
 
+/// Colors of hit effects by judgement, see [`Resource::hit_colors`].
+#[derive(Clone, Copy, Debug)]
+pub struct HitColors {
+    pub perfect: Color,
+    pub good: Color,
+}
+
+impl Default for HitColors {
+    fn default() -> Self {
+        Self {
+            perfect: JUDGE_LINE_PERFECT_COLOR,
+            good: JUDGE_LINE_GOOD_COLOR,
+        }
+    }
+}
+
+impl HitColors {
+    #[inline]
+    pub fn of(&self, perfect: bool) -> Color {
+        if perfect {
+            self.perfect
+        } else {
+            self.good
+        }
+    }
+}
+
+/// Colors of lines without a color of their own, by how the play has gone so far. See [`Resource::line_colors`].
+#[derive(Clone, Copy, Debug)]
+pub struct LineColors {
+    pub all_perfect: Color,
+    pub full_combo: Color,
+    pub normal: Color,
+}
+
+impl Default for LineColors {
+    fn default() -> Self {
+        Self {
+            all_perfect: JUDGE_LINE_PERFECT_COLOR,
+            full_combo: JUDGE_LINE_GOOD_COLOR,
+            normal: macroquad::color::WHITE,
+        }
+    }
+}
+
+impl LineColors {
+    /// The color for a play that's still `all_perfect` or `full_combo` (see [`crate::judge::Judge::full_combo`]).
+    #[inline]
+    pub fn of(&self, all_perfect: bool, full_combo: bool) -> Color {
+        if all_perfect {
+            self.all_perfect
+        } else if full_combo {
+            self.full_combo
+        } else {
+            self.normal
+        }
+    }
+}
+
 pub type Point = nalgebra::Point2<f32>;
 pub type Vector = nalgebra::Vector2<f32>;
 pub type Matrix = nalgebra::Matrix3<f32>;
@@ -16,17 +75,17 @@ mod anim;
 pub use anim::{Anim, AnimFloat, AnimVector, Keyframe};
 
 mod chart;
-pub use chart::{Chart, ChartExtra, ChartSettings};
+pub use chart::{Chart, ChartExtra, ChartSettings, EventsInOrder, Flash, LineProperty, TimedEvent, TimedEventKind};
 
 mod effect;
 pub use effect::{Effect, Uniform};
 
 mod line;
-pub use line::{JudgeLine, JudgeLineCache, JudgeLineKind, UIElement};
+pub use line::{BlendMode, JudgeLine, JudgeLineCache, JudgeLineKind, SpawnDirection, UIElement};
 
 mod note;
 use macroquad::prelude::set_pc_assets_folder;
-pub use note::{BadNote, Note, NoteKind, RenderConfig};
+pub use note::{BadNote, HitSoundParams, HoldKind, Note, NoteKind, RenderConfig};
 
 mod object;
 pub use object::{CtrlObject, Object};
@@ -35,7 +94,7 @@ mod render;
 pub use render::{copy_fbo, MSRenderTarget};
 
 mod resource;
-pub use resource::{ParticleEmitter, Resource, ResourcePack, DPI_VALUE};
+pub use resource::{hit_fx_step, NoteAtlas, ParticleEmitter, Resource, ResourcePack, Skin, SubdivisionStyle, DPI_VALUE};
 
 mod tween;
 pub use tween::{easing_from, BezierTween, ClampedTween, StaticTween, TweenFunction, TweenId, TweenMajor, TweenMinor, Tweenable, TWEEN_FUNCTIONS};
@@ -106,6 +165,15 @@ impl BpmList {
         time + (beats - start_beats) * (60. / bpm)
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// The `(beat, bpm)` ranges this list was built from.
+    pub fn ranges(&self) -> impl Iterator<Item = (f32, f32)> + '_ {
+        self.elements.iter().map(|(beats, _, bpm)| (*beats, *bpm))
+    }
+
     pub fn time(&mut self, triple: &Triple) -> f32 {
         self.time_beats(triple.beats())
     }
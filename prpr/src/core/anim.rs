@@ -1,4 +1,4 @@
-use super::{StaticTween, TweenFunction, TweenId, Tweenable, Vector};
+use super::{BpmList, StaticTween, TweenFunction, TweenId, Tweenable, Vector};
 use std::rc::Rc;
 
 #[derive(Clone)]
@@ -6,6 +6,8 @@ pub struct Keyframe<T> {
     pub time: f32,
     pub value: T,
     pub tween: Rc<dyn TweenFunction>,
+    /// If set, the keyframe is locked to this beat and `time` is derived from it, see [`Anim::resolve_beats`].
+    pub beat: Option<f32>,
 }
 
 impl<T> Keyframe<T> {
@@ -14,13 +16,21 @@ impl<T> Keyframe<T> {
             time,
             value,
             tween: StaticTween::get_rc(tween),
+            beat: None,
         }
     }
+
+    pub fn at_beat(mut self, beat: f32) -> Self {
+        self.beat = Some(beat);
+        self
+    }
 }
 
 #[derive(Clone)]
 pub struct Anim<T: Tweenable> {
     pub time: f32,
+    /// `time` in full precision, see [`Self::set_time_precise`]
+    precise_time: f64,
     pub keyframes: Box<[Keyframe<T>]>,
     pub cursor: usize,
     pub next: Option<Box<Anim<T>>>,
@@ -30,6 +40,7 @@ impl<T: Tweenable> Default for Anim<T> {
     fn default() -> Self {
         Self {
             time: 0.0,
+            precise_time: 0.0,
             keyframes: [].into(),
             cursor: 0,
             next: None,
@@ -38,22 +49,37 @@ impl<T: Tweenable> Default for Anim<T> {
 }
 
 impl<T: Tweenable> Anim<T> {
-    pub fn new(keyframes: Vec<Keyframe<T>>) -> Self {
+    pub fn new(mut keyframes: Vec<Keyframe<T>>) -> Self {
         assert!(!keyframes.is_empty());
         // assert_eq!(keyframes[0].time, 0.0);
         // assert_eq!(keyframes.last().unwrap().tween, 0);
+        Self::dedup_keyframes(&mut keyframes);
         Self {
             keyframes: keyframes.into_boxed_slice(),
             time: 0.0,
+            precise_time: 0.0,
             cursor: 0,
             next: None,
         }
     }
 
+    /// Of keyframes sharing the same time, only the first (the value reached when approaching) and the last (the value
+    /// from then on) affect sampling, so the ones in between are dropped.
+    fn dedup_keyframes(keyframes: &mut Vec<Keyframe<T>>) {
+        let times: Vec<_> = keyframes.iter().map(|it| it.time).collect();
+        let mut index = 0;
+        keyframes.retain(|_| {
+            let i = index;
+            index += 1;
+            !(i != 0 && i + 1 != times.len() && times[i - 1] == times[i] && times[i + 1] == times[i])
+        });
+    }
+
     pub fn fixed(value: T) -> Self {
         Self {
             keyframes: Box::new([Keyframe::new(0.0, value, 0)]),
             time: 0.0,
+            precise_time: 0.0,
             cursor: 0,
             next: None,
         }
@@ -81,6 +107,14 @@ impl<T: Tweenable> Anim<T> {
     }
 
     pub fn set_time(&mut self, time: f32) {
+        self.set_time_precise(time as f64);
+    }
+
+    /// Like [`Self::set_time`], but sampling keeps the full precision of `time`. Far into long charts, `f32` times are
+    /// too coarse to interpolate short keyframe spans smoothly.
+    pub fn set_time_precise(&mut self, precise: f64) {
+        let time = precise as f32;
+        self.precise_time = precise;
         if self.keyframes.is_empty() || time == self.time {
             self.time = time;
             return;
@@ -96,7 +130,7 @@ impl<T: Tweenable> Anim<T> {
         }
         self.time = time;
         if let Some(next) = &mut self.next {
-            next.set_time(time);
+            next.set_time_precise(precise);
         }
     }
 
@@ -109,7 +143,10 @@ impl<T: Tweenable> Anim<T> {
         } else {
             let kf1 = &self.keyframes[self.cursor];
             let kf2 = &self.keyframes[self.cursor + 1];
-            let t = (self.time - kf1.time) / (kf2.time - kf1.time);
+            if kf2.time <= kf1.time {
+                return Some(kf2.value.clone());
+            }
+            let t = ((self.precise_time - kf1.time as f64) / (kf2.time - kf1.time) as f64) as f32;
             T::tween(&kf1.value, &kf2.value, kf1.tween.y(t))
         })
     }
@@ -125,6 +162,23 @@ impl<T: Tweenable> Anim<T> {
         })
     }
 
+    /// Recomputes the times of beat-locked keyframes from the given BPM list, so that they stay synced to the music after
+    /// BPMs change.
+    pub fn resolve_beats(&mut self, bpm_list: &mut BpmList) {
+        for kf in self.keyframes.iter_mut() {
+            if let Some(beat) = kf.beat {
+                kf.time = bpm_list.time_beats(beat);
+            }
+        }
+        if let Some(next) = &mut self.next {
+            next.resolve_beats(bpm_list);
+        }
+        // forces the cursor to be recomputed
+        self.time = f32::NAN;
+        self.cursor = 0;
+        self.set_time_precise(self.precise_time);
+    }
+
     pub fn map_value(&mut self, mut f: impl FnMut(T) -> T) {
         self.keyframes.iter_mut().for_each(|it| it.value = f(it.value.clone()));
         if let Some(next) = &mut self.next {
@@ -140,7 +194,41 @@ impl<T: Tweenable + Default> Anim<T> {
 }
 
 pub type AnimFloat = Anim<f32>;
-#[derive(Default)]
+
+impl AnimFloat {
+    /// The value at `time`, without moving the cursor like [`Anim::set_time`] does.
+    pub fn value_at(&self, time: f32) -> f32 {
+        let kfs = &self.keyframes;
+        let index = kfs.partition_point(|it| it.time <= time);
+        let now = if kfs.is_empty() {
+            0.
+        } else if index == 0 {
+            kfs[0].value
+        } else if index == kfs.len() {
+            kfs[index - 1].value
+        } else {
+            let (kf1, kf2) = (&kfs[index - 1], &kfs[index]);
+            f32::tween(&kf1.value, &kf2.value, kf1.tween.y((time - kf1.time) / (kf2.time - kf1.time)))
+        };
+        now + self.next.as_ref().map_or(0., |it| it.value_at(time))
+    }
+
+    /// The rate of change (per second) at `time`, `0.` before the first or after the last keyframe. At a keyframe, it's
+    /// the one of the segment starting there.
+    pub fn derivative(&self, time: f32) -> f32 {
+        let kfs = &self.keyframes;
+        let index = kfs.partition_point(|it| it.time <= time);
+        let now = if index == 0 || index == kfs.len() {
+            0.
+        } else {
+            let (kf1, kf2) = (&kfs[index - 1], &kfs[index]);
+            let span = kf2.time - kf1.time;
+            kf1.tween.dy((time - kf1.time) / span) * (kf2.value - kf1.value) / span
+        };
+        now + self.next.as_ref().map_or(0., |it| it.derivative(time))
+    }
+}
+#[derive(Clone, Default)]
 pub struct AnimVector(pub AnimFloat, pub AnimFloat);
 
 impl AnimVector {
@@ -153,6 +241,16 @@ impl AnimVector {
         self.1.set_time(time);
     }
 
+    pub fn set_time_precise(&mut self, time: f64) {
+        self.0.set_time_precise(time);
+        self.1.set_time_precise(time);
+    }
+
+    pub fn resolve_beats(&mut self, bpm_list: &mut BpmList) {
+        self.0.resolve_beats(bpm_list);
+        self.1.resolve_beats(bpm_list);
+    }
+
     pub fn now(&self) -> Vector {
         Vector::new(self.0.now(), self.1.now())
     }
@@ -161,3 +259,116 @@ impl AnimVector {
         Vector::new(self.0.now_opt().unwrap_or(x), self.1.now_opt().unwrap_or(y))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_keyframes_collapse() {
+        let mut anim = AnimFloat::new(vec![
+            Keyframe::new(0., 0., 2),
+            Keyframe::new(1., 1., 2),
+            Keyframe::new(1., 5., 2),
+            Keyframe::new(1., 3., 2),
+            Keyframe::new(1., 2., 2),
+            Keyframe::new(2., 3., 2),
+        ]);
+        let values = anim.keyframes.iter().map(|it| it.value).collect::<Vec<_>>();
+        assert_eq!(values, [0., 1., 2., 3.]);
+        // approaching the jump reaches the first value, and it's the last one from then on
+        anim.set_time(1. - 1e-4);
+        assert!((anim.now() - 1.).abs() < 1e-3);
+        anim.set_time(1.);
+        assert_eq!(anim.now(), 2.);
+        anim.set_time(1.5);
+        assert!((anim.now() - 2.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn derivative_of_segments() {
+        // linear from 0 to 4 over 2 seconds, then held
+        let anim = AnimFloat::new(vec![Keyframe::new(0., 0., 2), Keyframe::new(2., 4., 0), Keyframe::new(3., 6., 0)]);
+        for time in [0.1, 1., 1.9] {
+            assert!((anim.derivative(time) - 2.).abs() < 1e-3);
+        }
+        assert_eq!(anim.derivative(2.5), 0.);
+        assert_eq!(anim.derivative(5.), 0.);
+    }
+
+    #[test]
+    fn beat_locked_keyframes_follow_bpm() {
+        let mut anim = AnimFloat::new(vec![Keyframe::new(0., 0., 2), Keyframe::new(1., 4., 2).at_beat(2.)]);
+        anim.resolve_beats(&mut BpmList::new(vec![(0., 60.)]));
+        assert_eq!(anim.keyframes[0].time, 0.);
+        assert_eq!(anim.keyframes[1].time, 2.);
+        anim.set_time(1.);
+        assert!((anim.now() - 2.).abs() < 1e-4);
+
+        // slows down from the first beat on
+        anim.resolve_beats(&mut BpmList::new(vec![(0., 60.), (1., 30.)]));
+        assert_eq!(anim.keyframes[1].time, 3.);
+        assert!((anim.now() - 4. / 3.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn value_at_leaves_cursor() {
+        let mut anim = AnimFloat::new(vec![Keyframe::new(0., 0., 2), Keyframe::new(2., 4., 2), Keyframe::new(4., 0., 0)]);
+        anim.set_time(1.);
+        for time in [0., 0.5, 2., 3., 5.] {
+            let mut other = anim.clone();
+            other.set_time(time);
+            assert!((anim.value_at(time) - other.now()).abs() < 1e-4);
+        }
+        assert_eq!(anim.now(), 2.);
+    }
+
+    #[test]
+    fn precise_time_far_into_charts() {
+        // f32 times are 1/128 s apart here
+        let mut anim = AnimFloat::new(vec![Keyframe::new(65536., 0., 2), Keyframe::new(65537., 1., 2)]);
+        let time = 65536.001;
+        anim.set_time(time as f32);
+        assert_eq!(anim.now(), 0.);
+        anim.set_time_precise(time);
+        assert!((anim.now() - 0.001).abs() < 1e-5);
+    }
+
+    #[test]
+    fn derivative_at_segment_bounds() {
+        use crate::core::{easing_from, TweenMajor, TweenMinor, TWEEN_FUNCTIONS};
+        // quadratic ease in from 0 to 1 over a second, then linear to 3
+        let quad_in = easing_from(TweenMajor::Quad, TweenMinor::In);
+        let anim = AnimFloat::new(vec![Keyframe::new(0., 0., quad_in), Keyframe::new(1., 1., 2), Keyframe::new(2., 3., 0)]);
+        assert_eq!(anim.derivative(0.), 0.);
+        assert!((anim.derivative(0.999) - 2.).abs() < 1e-2);
+        // right on a keyframe, the slope is the one of the segment starting there
+        assert_eq!(anim.derivative(1.), 2.);
+        assert_eq!(anim.derivative(2.), 0.);
+
+        // the exact slopes agree with the values of every tween
+        const H: f32 = 1e-3;
+        for (tween, f) in TWEEN_FUNCTIONS.iter().enumerate() {
+            let dy = StaticTween(tween as TweenId);
+            for x in [0.1, 0.3, 0.6, 0.9] {
+                let estimate = (f(x + H) - f(x - H)) / (H * 2.);
+                assert!((dy.dy(x) - estimate).abs() <= 1e-2 * estimate.abs().max(1.), "tween {tween} at {x}");
+            }
+        }
+    }
+
+    #[test]
+    fn precise_time_doesnt_drift() {
+        // ten minutes of 60 fps frames, accumulated the way `res.time` is
+        let (mut time, mut precise) = (0f32, 0f64);
+        for _ in 0..36000 {
+            time += 1. / 60.;
+            precise += 1. / 60.;
+        }
+        let mut anim = AnimFloat::new(vec![Keyframe::new(0., 0., 2), Keyframe::new(1200., 1200., 2)]);
+        anim.set_time(time);
+        assert!((anim.now() - 600.).abs() > 0.05);
+        anim.set_time_precise(precise);
+        assert!((anim.now() - 600.).abs() < 1e-3);
+    }
+}
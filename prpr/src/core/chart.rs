@@ -1,19 +1,137 @@
-use super::{BpmList, Effect, JudgeLine, Matrix, Resource, UIElement, Vector, Video};
-use crate::{judge::JudgeStatus, ui::Ui};
+use super::{
+    Anim, BpmList, Effect, JudgeLine, JudgeLineCache, JudgeLineKind, Matrix, Note, NoteKind, Point, Resource, Tweenable, UIElement, Vector, Video,
+};
+use crate::{ext::NotNanExt, judge::JudgeStatus, ui::Ui};
+use anyhow::{bail, Result};
 use macroquad::prelude::*;
-use std::cell::RefCell;
+use ordered_float::NotNan;
+use std::{cell::RefCell, cmp::Reverse, collections::BinaryHeap, ops::Range};
+
+/// A full-screen flash of color, fading out over `duration`.
+#[derive(Clone, Copy)]
+pub struct Flash {
+    pub time: f32,
+    pub duration: f32,
+    pub color: Color,
+    /// If set, the flash is drawn behind lines and notes instead of over them
+    pub behind: bool,
+}
+
+impl Flash {
+    /// The color of the flash at `time`, `None` if it isn't showing.
+    pub fn color_at(&self, time: f32) -> Option<Color> {
+        let progress = (time - self.time) / self.duration;
+        if !(0. ..1.).contains(&progress) {
+            return None;
+        }
+        Some(Color {
+            a: self.color.a * (1. - progress),
+            ..self.color
+        })
+    }
+
+    fn render(&self, res: &Resource) {
+        if let Some(color) = self.color_at(res.time) {
+            let top = 1. / res.aspect_ratio;
+            draw_rectangle(-1., -top, 2., top * 2., color);
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct ChartExtra {
     pub effects: Vec<Effect>,
     pub global_effects: Vec<Effect>,
     pub videos: Vec<Video>,
+    pub flashes: Vec<Flash>,
+    /// Named sections of the chart (e.g. "chorus") by their start times, sorted by time
+    pub markers: Vec<(f32, String)>,
+    /// Time ranges (e.g. cutscenes) whose notes are judged automatically, as perfects, whether autoplay is on or not
+    pub auto_sections: Vec<Range<f32>>,
 }
 
-#[derive(Default)]
 pub struct ChartSettings {
     pub pe_alpha_extension: bool,
     pub hold_partial_cover: bool,
+    /// Uniform scale applied to the rendered size of notes, on top of [`crate::config::Config::note_scale`]. Judging is not
+    /// affected.
+    pub note_scale: f32,
+    /// If set, judge lines are pinned to their transform at this time, while notes keep scrolling.
+    pub frozen_line_time: Option<f32>,
+    /// Color of lines without a color animation, instead of the one of the resource pack.
+    pub line_color: Option<Color>,
+    /// Length (in seconds) of the countdown shown before the first note, `0` for none.
+    pub countdown: u32,
+}
+
+impl Default for ChartSettings {
+    fn default() -> Self {
+        Self {
+            pe_alpha_extension: false,
+            hold_partial_cover: false,
+            note_scale: 1.,
+            frozen_line_time: None,
+            line_color: None,
+            countdown: 0,
+        }
+    }
+}
+
+impl ChartSettings {
+    /// The time used to sample judge line transforms.
+    #[inline]
+    pub fn line_time(&self, time: f32) -> f32 {
+        self.frozen_line_time.unwrap_or(time)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineProperty {
+    Alpha,
+    ScaleX,
+    ScaleY,
+    Rotation,
+    TranslationX,
+    TranslationY,
+    Height,
+    Incline,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TimedEventKind {
+    /// A note, given by its index in `JudgeLine::notes`
+    Note(usize),
+    Keyframe(LineProperty),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TimedEvent {
+    pub time: f32,
+    pub line: usize,
+    pub kind: TimedEventKind,
+}
+
+/// Iterator over the events of all lines in time order, see [`Chart::events_in_order`].
+pub struct EventsInOrder {
+    lines: Vec<Vec<TimedEvent>>,
+    heap: BinaryHeap<Reverse<(NotNan<f32>, usize, usize)>>, // (time, line, position)
+}
+
+impl Iterator for EventsInOrder {
+    type Item = TimedEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, line, pos)) = self.heap.pop()?;
+        if let Some(next) = self.lines[line].get(pos + 1) {
+            self.heap.push(Reverse((next.time.not_nan(), line, pos + 1)));
+        }
+        Some(self.lines[line][pos])
+    }
+}
+
+/// How far `note` is drawn from `line` (before it's transformed) at the line's current time, see [`Chart::note_position`].
+fn note_distance(line: &JudgeLine, note: &Note, aspect_ratio: f32) -> f32 {
+    (note.height - line.height.now()) / aspect_ratio * note.speed * line.note_speed_scale + line.note_offset
 }
 
 pub struct Chart {
@@ -25,10 +143,13 @@ pub struct Chart {
 
     pub order: Vec<usize>,
     pub attach_ui: [Option<usize>; 7],
+
+    /// Seed that randomized changes to the chart (e.g. [`Self::thin_notes`]) derive from, so that they're the same across runs
+    seed: u64,
 }
 
 impl Chart {
-    pub fn new(offset: f32, lines: Vec<JudgeLine>, bpm_list: BpmList, settings: ChartSettings, extra: ChartExtra) -> Self {
+    fn order_lines(lines: &[JudgeLine]) -> (Vec<usize>, [Option<usize>; 7]) {
         let mut attach_ui = [None; 7];
         let mut order = (0..lines.len())
             .filter(|it| {
@@ -40,7 +161,12 @@ impl Chart {
                 }
             })
             .collect::<Vec<_>>();
-        order.sort_by_key(|it| (lines[*it].z_index, *it));
+        order.sort_by_key(|it| (lines[*it].now_z_index(), *it));
+        (order, attach_ui)
+    }
+
+    pub fn new(offset: f32, lines: Vec<JudgeLine>, bpm_list: BpmList, settings: ChartSettings, extra: ChartExtra) -> Self {
+        let (order, attach_ui) = Self::order_lines(&lines);
         Self {
             offset,
             lines,
@@ -50,14 +176,109 @@ impl Chart {
 
             order,
             attach_ui,
+
+            seed: 0,
         }
     }
 
+    /// The seed of [`Self::note_random`].
+    #[inline]
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    #[inline]
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// A random value in `[0, 1)` for the given note, fully determined by the chart seed.
+    ///
+    /// `salt` tells apart different random properties of the same note.
+    pub fn note_random(&self, line: usize, note: usize, salt: u64) -> f32 {
+        // splitmix64
+        let mut x =
+            self.seed ^ (line as u64).wrapping_mul(0x9e3779b97f4a7c15) ^ (note as u64).wrapping_mul(0xc2b2ae3d27d4eb4f) ^ salt.rotate_left(32);
+        for _ in 0..2 {
+            x = x.wrapping_add(0x9e3779b97f4a7c15);
+            x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+            x ^= x >> 31;
+        }
+        (x >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Hash of what judging the chart depends on: the timing, kind and position of every note. Unlike `std`'s hashers, it's
+    /// the same across runs and builds, so that it can be stored. See [`crate::judge::Replay::chart_hash`].
+    pub fn content_hash(&self) -> u64 {
+        // FNV-1a
+        fn feed(hash: &mut u64, bytes: &[u8]) {
+            for byte in bytes {
+                *hash ^= *byte as u64;
+                *hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        let mut hash = 0xcbf29ce484222325;
+        feed(&mut hash, &(self.lines.len() as u64).to_le_bytes());
+        for line in &self.lines {
+            feed(&mut hash, &(line.notes.len() as u64).to_le_bytes());
+            for note in &line.notes {
+                feed(&mut hash, &note.time.to_bits().to_le_bytes());
+                match &note.kind {
+                    NoteKind::Click => feed(&mut hash, &[0]),
+                    NoteKind::Hold { end_time, kind, .. } => {
+                        feed(&mut hash, &[1, *kind as u8]);
+                        feed(&mut hash, &end_time.to_bits().to_le_bytes());
+                    }
+                    NoteKind::Flick => feed(&mut hash, &[2]),
+                    NoteKind::Drag => feed(&mut hash, &[3]),
+                }
+                feed(&mut hash, &[note.above as u8, note.fake as u8]);
+                for kf in &note.object.translation.0.keyframes {
+                    feed(&mut hash, &kf.time.to_bits().to_le_bytes());
+                    feed(&mut hash, &kf.value.to_bits().to_le_bytes());
+                }
+            }
+        }
+        hash
+    }
+
+    /// Mutable access to the chart offset, e.g. for live calibration. Game time is derived from it every frame,
+    /// so changes take effect immediately without reparsing.
+    #[inline]
+    pub fn offset_mut(&mut self) -> &mut f32 {
+        &mut self.offset
+    }
+
+    /// Chart time at `music` seconds into the track, with an `extra` offset (e.g. the player's) on top of the chart's own.
+    #[inline]
+    pub fn time_at(&self, music: f64, extra: f32) -> f64 {
+        (music - (self.offset + extra) as f64).max(0.)
+    }
+
+    /// World position of the `note`-th note of the `line`-th line at the current time, as it's drawn (apart from spawning
+    /// animations). `None` if there's no such note.
+    pub fn note_position(&self, res: &Resource, line: usize, note: usize) -> Option<Point> {
+        let judge_line = self.lines.get(line)?;
+        let note = judge_line.notes.get(note)?;
+        let mut tr = judge_line.now_transform(res, &self.lines);
+        if !note.above {
+            tr = tr.prepend_nonuniform_scaling(&Vector::new(1., -1.));
+        }
+        let incline_sin = judge_line.incline.now_opt().map(|it| it.to_radians().sin()).unwrap_or_default();
+        let base = note_distance(judge_line, note, res.aspect_ratio);
+        tr *= note.now_transform(res, &judge_line.ctrl_obj.borrow(), base, incline_sin);
+        Some(tr.transform_point(&Point::origin()))
+    }
+
     #[inline]
     pub fn with_element<R>(&self, ui: &mut Ui, res: &Resource, element: UIElement, f: impl FnOnce(&mut Ui, Color, Matrix) -> R) -> R {
         if let Some(id) = self.attach_ui[element as usize] {
             let obj = &self.lines[id].object;
-            let mut tr = obj.now_translation(res);
+            let mut tr = match self.lines[id].attach_note.and_then(|(line, note)| self.note_position(res, line, note)) {
+                Some(pt) => pt.coords,
+                None => obj.now_translation(res),
+            };
             tr.y = -tr.y;
             let mut color = self.lines[id].color.now_opt().unwrap_or(WHITE);
             color.a *= obj.now_alpha().max(0.);
@@ -67,25 +288,448 @@ impl Chart {
         }
     }
 
-    pub fn reset(&mut self) {
+    /// Finds out the beat subdivision of every note, so that notes can be drawn with textures of their subdivision.
+    pub fn assign_subdivisions(&mut self) {
+        const SUBDIVISIONS: [u32; 8] = [1, 2, 3, 4, 6, 8, 12, 16];
+        const TOLERANCE: f32 = 1e-3;
+        let mut bpm_list = self.bpm_list.borrow_mut();
+        if bpm_list.is_empty() {
+            return;
+        }
+        for note in self.lines.iter_mut().flat_map(|it| it.notes.iter_mut()) {
+            let beat = bpm_list.beat(note.time);
+            note.subdivision = SUBDIVISIONS
+                .into_iter()
+                .find(|it| {
+                    let v = beat * *it as f32;
+                    (v - v.round()).abs() < TOLERANCE * *it as f32
+                })
+                .unwrap_or_default();
+        }
+    }
+
+    /// All keyframes and notes of every line, merged into a single stream in time order.
+    pub fn events_in_order(&self) -> EventsInOrder {
+        fn push_anim<T: Tweenable>(events: &mut Vec<TimedEvent>, line: usize, property: LineProperty, mut anim: &Anim<T>) {
+            loop {
+                events.extend(anim.keyframes.iter().map(|kf| TimedEvent {
+                    time: kf.time,
+                    line,
+                    kind: TimedEventKind::Keyframe(property),
+                }));
+                let Some(next) = &anim.next else {
+                    break;
+                };
+                anim = next;
+            }
+        }
+        let lines: Vec<_> = self
+            .lines
+            .iter()
+            .enumerate()
+            .map(|(id, line)| {
+                let mut events = Vec::new();
+                let obj = &line.object;
+                push_anim(&mut events, id, LineProperty::Alpha, &obj.alpha);
+                push_anim(&mut events, id, LineProperty::ScaleX, &obj.scale.0);
+                push_anim(&mut events, id, LineProperty::ScaleY, &obj.scale.1);
+                push_anim(&mut events, id, LineProperty::Rotation, &obj.rotation);
+                push_anim(&mut events, id, LineProperty::TranslationX, &obj.translation.0);
+                push_anim(&mut events, id, LineProperty::TranslationY, &obj.translation.1);
+                push_anim(&mut events, id, LineProperty::Height, &line.height);
+                push_anim(&mut events, id, LineProperty::Incline, &line.incline);
+                events.extend(line.notes.iter().enumerate().map(|(index, note)| TimedEvent {
+                    time: note.time,
+                    line: id,
+                    kind: TimedEventKind::Note(index),
+                }));
+                events.sort_by_key(|it| it.time.not_nan());
+                events
+            })
+            .collect();
+        let heap = lines
+            .iter()
+            .enumerate()
+            .filter_map(|(id, events)| events.first().map(|it| Reverse((it.time.not_nan(), id, 0))))
+            .collect();
+        EventsInOrder { lines, heap }
+    }
+
+    /// Sorted hit times (in seconds, taking line delays into account) of every note that's judged, one per click of a
+    /// click track. Times are in chart time, i.e. [`Self::offset`] isn't applied.
+    pub fn click_track(&self) -> Vec<f32> {
+        let mut times: Vec<f32> = self
+            .lines
+            .iter()
+            .flat_map(|line| line.notes.iter().filter(|it| !it.fake).map(|it| it.time + line.time_offset))
+            .collect();
+        times.sort_by_key(|it| it.not_nan());
+        times
+    }
+
+    /// Time (taking line delays into account) of the earliest note that's judged, if any.
+    pub fn first_note_time(&self) -> Option<f32> {
         self.lines
-            .iter_mut()
-            .flat_map(|it| it.notes.iter_mut())
-            .for_each(|note| note.judge = JudgeStatus::NotJudged);
+            .iter()
+            .flat_map(|line| line.notes.iter().filter(|it| !it.fake).map(|it| it.time + line.time_offset))
+            .min_by_key(|it| it.not_nan())
+    }
+
+    /// The countdown number shown at `time` and its alpha, see [`ChartSettings::countdown`]. Each number fades out during
+    /// its second.
+    pub fn countdown(&self, time: f32) -> Option<(u32, f32)> {
+        if self.settings.countdown == 0 {
+            return None;
+        }
+        let left = self.first_note_time()? - time;
+        if left > 0. && left <= self.settings.countdown as f32 {
+            let number = left.ceil();
+            Some((number as u32, left - (number - 1.)))
+        } else {
+            None
+        }
+    }
+
+    /// Named time markers of the chart, sorted by time. See [`Self::section_range`].
+    pub fn markers(&self) -> &[(f32, String)] {
+        &self.extra.markers
+    }
+
+    /// Time range of the section starting at the `index`-th marker, which lasts until the next marker (or forever).
+    pub fn section_range(&self, index: usize) -> Option<Range<f32>> {
+        let markers = &self.extra.markers;
+        let start = markers.get(index)?.0;
+        Some(start..markers.get(index + 1).map_or(f32::INFINITY, |it| it.0))
+    }
+
+    /// Estimates [`Self::offset`] from the music, given as mono samples: the lag (within a second either way) at which the
+    /// notes line up best with the onsets detected in the audio. `None` if there are no notes or the audio is too short.
+    pub fn suggest_offset(&self, samples: &[f32], sample_rate: u32) -> Option<f32> {
+        const HOP: usize = 256;
+        const WINDOW: usize = 1024;
+        const MAX_OFFSET: f32 = 1.;
+        let times = self.click_track();
+        if times.is_empty() || samples.len() < WINDOW * 2 {
+            return None;
+        }
+        let frame_time = HOP as f32 / sample_rate as f32;
+        // onset strength: the rise of the energy from one frame to the next
+        let energy: Vec<f32> = (0..=(samples.len() - WINDOW) / HOP)
+            .map(|i| samples[i * HOP..i * HOP + WINDOW].iter().map(|it| it * it).sum::<f32>().sqrt())
+            .collect();
+        let onsets: Vec<f32> = std::iter::once(0.).chain(energy.windows(2).map(|it| (it[1] - it[0]).max(0.))).collect();
+        let strength = |time: f32| {
+            // the energy rises as soon as an onset enters a window, i.e. at its end
+            let pos = (time - WINDOW as f32 / sample_rate as f32) / frame_time;
+            if pos < 0. {
+                return 0.;
+            }
+            let index = pos.round() as usize;
+            // a frame to both sides, since onsets rarely fall right onto a frame
+            let end = (index + 2).min(onsets.len());
+            onsets[index.saturating_sub(1).min(end)..end].iter().copied().fold(0., f32::max)
+        };
+        let steps = (MAX_OFFSET / frame_time).ceil() as i32;
+        (-steps..=steps)
+            .map(|step| {
+                let offset = step as f32 * frame_time;
+                let score: f32 = times.iter().map(|time| strength(time + offset)).sum();
+                // ties go to the smallest correction
+                (score, -step.abs(), offset)
+            })
+            .max_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+            .filter(|it| it.0 > 0.)
+            .map(|it| it.2)
+    }
+
+    /// Writes [`Self::click_track`] as text, one time per line.
+    pub fn write_click_track(&self, mut w: impl std::io::Write) -> Result<()> {
+        for time in self.click_track() {
+            writeln!(w, "{time:.6}")?;
+        }
+        Ok(())
+    }
+
+    /// Deep copy of the `index`-th line, with a freshly built cache. The copy is detached from its parent unless
+    /// `keep_parent` is set.
+    pub fn clone_line(&self, index: usize, keep_parent: bool) -> JudgeLine {
+        let line = &self.lines[index];
+        let mut notes = line.notes.clone();
+        let cache = JudgeLineCache::new(&mut notes);
+        JudgeLine {
+            object: line.object.clone(),
+            ctrl_obj: RefCell::new(line.ctrl_obj.borrow().clone()),
+            kind: line.kind.clone(),
+            height: line.height.clone(),
+            incline: line.incline.clone(),
+            notes,
+            color: line.color.clone(),
+            parent: if keep_parent { line.parent } else { None },
+            z_index: line.z_index,
+            z_index_anim: line.z_index_anim.clone(),
+            show_below: line.show_below,
+            attach_ui: line.attach_ui,
+            blend_mode: line.blend_mode,
+            spawn_from: line.spawn_from,
+            time_offset: line.time_offset,
+            notes_disabled: line.notes_disabled,
+            hide_when_empty: line.hide_when_empty,
+            tile_texture: line.tile_texture,
+            mirrored: line.mirrored,
+            clip_to_playfield: line.clip_to_playfield,
+            appear_distance: line.appear_distance,
+            reverse_notes: line.reverse_notes,
+            attach_note: line.attach_note,
+            cull_back_face: line.cull_back_face,
+            note_speed_scale: line.note_speed_scale,
+            note_offset: line.note_offset,
+            visible: line.visible.clone(),
+            note_tints: line.note_tints,
+            cache,
+        }
+    }
+
+    /// Reorders the lines so that the `i`-th one becomes the `map[i]`-th one of before, remapping parents and anchors
+    /// accordingly. Fails if `map` isn't a permutation of the lines' indices.
+    ///
+    /// A [`crate::judge::Judge`] created earlier keeps the old order, so a new one should be created.
+    pub fn transpose_lines(&mut self, map: &[usize]) -> Result<()> {
+        let mut inverse = vec![usize::MAX; self.lines.len()];
+        if map.len() != self.lines.len() {
+            bail!("expected a permutation of {} lines, got {} indices", self.lines.len(), map.len());
+        }
+        for (new, old) in map.iter().enumerate() {
+            if *old >= inverse.len() || inverse[*old] != usize::MAX {
+                bail!("invalid permutation, index {old} is out of range or repeated");
+            }
+            inverse[*old] = new;
+        }
+        let mut old_lines: Vec<_> = std::mem::take(&mut self.lines).into_iter().map(Some).collect();
+        self.lines = map.iter().map(|old| old_lines[*old].take().unwrap()).collect();
+        for line in &mut self.lines {
+            if let Some(parent) = &mut line.parent {
+                *parent = inverse[*parent];
+            }
+            if let Some((target, _)) = &mut line.attach_note {
+                *target = inverse.get(*target).copied().unwrap_or(*target);
+            }
+            for note in &mut line.notes {
+                if let Some((target, _)) = &mut note.anchor {
+                    *target = inverse.get(*target).copied().unwrap_or(*target);
+                }
+            }
+        }
+        (self.order, self.attach_ui) = Self::order_lines(&self.lines);
+        Ok(())
+    }
+
+    /// Removes notes until only about `keep_fraction` of the judged ones are left, to make an easier difficulty out of the
+    /// chart. Drags go first, and notes on strong beats (every other beat, the 1st and 3rd of 4/4 bars) are kept. Which
+    /// notes are removed is decided by the chart seed. Note indices (anchors and attached notes) are remapped, or cleared
+    /// if their note is removed.
+    ///
+    /// Like with [`Self::transpose_lines`], a [`crate::judge::Judge`] created earlier has to be replaced.
+    pub fn thin_notes(&mut self, keep_fraction: f32) {
+        const TOLERANCE: f32 = 1e-3;
+        const STRONG_BEAT: f32 = 2.;
+        const SALT: u64 = 0x7417;
+        let mut bpm_list = self.bpm_list.borrow_mut();
+        let mut candidates = Vec::new();
+        let mut total = 0;
+        for (line_id, line) in self.lines.iter().enumerate() {
+            for (id, note) in line.notes.iter().enumerate().filter(|it| !it.1.fake) {
+                total += 1;
+                if !bpm_list.is_empty() {
+                    let beat = bpm_list.beat(note.time) / STRONG_BEAT;
+                    if (beat - beat.round()).abs() < TOLERANCE / STRONG_BEAT {
+                        continue;
+                    }
+                }
+                let drag = matches!(note.kind, NoteKind::Drag);
+                candidates.push((!drag, self.note_random(line_id, id, SALT).not_nan(), line_id, id));
+            }
+        }
+        drop(bpm_list);
+        let remove = total - (total as f32 * keep_fraction.clamp(0., 1.)).round() as usize;
+        candidates.sort_unstable();
+        let mut removed = vec![Vec::new(); self.lines.len()];
+        for (.., line_id, id) in candidates.into_iter().take(remove) {
+            removed[line_id].push(id);
+        }
+        removed.iter_mut().for_each(|it| it.sort_unstable());
+        // anchors refer to notes by their position in time order rather than by index
+        let removed_ranks: Vec<Vec<usize>> = self
+            .lines
+            .iter()
+            .zip(&removed)
+            .map(|(line, removed)| {
+                let mut order: Vec<usize> = (0..line.notes.len()).collect();
+                order.sort_by_key(|id| line.notes[*id].time.not_nan());
+                order
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, id)| removed.binary_search(id).is_ok())
+                    .map(|(rank, _)| rank)
+                    .collect()
+            })
+            .collect();
+        let remap = |removed: &[Vec<usize>], (line, note): (usize, usize)| {
+            // invalid indices are left as they are
+            let Some(removed) = removed.get(line) else {
+                return Some((line, note));
+            };
+            let kept = removed.binary_search(&note).is_err();
+            kept.then(|| (line, note - removed.partition_point(|it| *it < note)))
+        };
+        for line in &mut self.lines {
+            line.attach_note = line.attach_note.and_then(|it| remap(&removed, it));
+            for note in &mut line.notes {
+                note.anchor = note.anchor.and_then(|it| remap(&removed_ranks, it));
+            }
+        }
+        for (line, removed) in self.lines.iter_mut().zip(removed) {
+            if removed.is_empty() {
+                continue;
+            }
+            let mut index = 0;
+            line.notes.retain(|_| {
+                index += 1;
+                removed.binary_search(&(index - 1)).is_err()
+            });
+            // removing notes keeps them sorted, so unlike `JudgeLineCache::new` this doesn't move notes, and the indices
+            // remapped above stay valid
+            line.cache.reset(&mut line.notes);
+        }
+    }
+
+    /// Replaces the BPM list, moving the beat-locked keyframes of lines so that they stay on their beats.
+    pub fn set_bpm_list(&mut self, bpm_list: BpmList) {
+        let r = self.bpm_list.get_mut();
+        *r = bpm_list;
+        for line in &mut self.lines {
+            line.object.resolve_beats(r);
+            line.incline.resolve_beats(r);
+            line.z_index_anim.resolve_beats(r);
+            line.color.resolve_beats(r);
+            line.visible.resolve_beats(r);
+            match &mut line.kind {
+                JudgeLineKind::Text(anim, rotation) => {
+                    anim.resolve_beats(r);
+                    rotation.resolve_beats(r);
+                }
+                JudgeLineKind::Paint(anim, _) => anim.resolve_beats(r),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.lines.iter_mut().flat_map(|it| it.notes.iter_mut()).for_each(|note| {
+            note.judge = JudgeStatus::NotJudged;
+            note.last_real_time = 0.;
+            note.judged_time = None;
+            note.approach_inside = None;
+        });
         for line in &mut self.lines {
             line.cache.reset(&mut line.notes);
         }
     }
 
+    /// Brings the chart back to how it was right after parsing, so that it can be replayed without reparsing.
+    /// Score and combo are kept by [`crate::judge::Judge`] and have to be reset there.
+    pub fn reset_play_state(&mut self) {
+        self.reset();
+        for line in &mut self.lines {
+            line.object.set_time(0.);
+            line.height.set_time(0.);
+            line.incline.set_time(0.);
+            line.z_index_anim.set_time(0.);
+            line.color.set_time(0.);
+            line.visible.set_time(0.);
+            match &mut line.kind {
+                JudgeLineKind::Text(anim, rotation) => {
+                    anim.set_time(0.);
+                    rotation.set_time(0.);
+                }
+                JudgeLineKind::Paint(anim, ..) => anim.set_time(0.),
+                _ => {}
+            }
+            for note in &mut line.notes {
+                note.object.set_time(0.);
+            }
+        }
+    }
+
+    /// The part of [`Self::warmup`] that doesn't need resources: caches are rebuilt and every animation of lines and notes is
+    /// sampled at `time`, so that the first update doesn't have to walk through all the keyframes before it.
+    pub fn prime(&mut self, time: f32) {
+        self.reset();
+        let line_time = self.settings.line_time(time);
+        for line in &mut self.lines {
+            let time = time - line.time_offset;
+            line.object.set_time(line_time - line.time_offset);
+            line.height.set_time(time);
+            line.incline.set_time(time);
+            line.color.set_time(time);
+            line.z_index_anim.set_time(time);
+            line.visible.set_time(time);
+            for note in &mut line.notes {
+                note.object.set_time(time);
+            }
+        }
+    }
+
+    /// Does ahead of time what would otherwise be done during the first frames and stall them, which is most noticeable on
+    /// mobile: line caches are rebuilt, every animation is sampled at the current time, and all note and line textures
+    /// are drawn once (fully transparent) so that they're ready on the GPU.
+    ///
+    /// Meant to be called right after loading, as it resets the judge status of notes.
+    pub fn warmup(&mut self, res: &mut Resource) {
+        self.prime(res.time);
+        self.update(res);
+        let pack = &res.res_pack;
+        let mut textures = Vec::new();
+        for style in [&pack.note_style, &pack.note_style_mh] {
+            textures.extend([&style.click, &style.hold, &style.flick, &style.drag]);
+            textures.extend(style.hold_body.as_ref());
+        }
+        for style in pack.subdivision_styles.values() {
+            textures.extend([&style.click, &style.drag, &style.flick]);
+        }
+        textures.extend(pack.note_atlas.as_ref().map(|it| &it.texture));
+        textures.extend(self.lines.iter().filter_map(|line| match &line.kind {
+            JudgeLineKind::Texture(texture) => Some(texture),
+            _ => None,
+        }));
+        for texture in textures {
+            draw_texture(**texture, 0., 0., Color::new(1., 1., 1., 0.));
+        }
+    }
+
     pub fn update(&mut self, res: &mut Resource) {
+        let precise = res.precise_time();
+        let line_time = self.settings.frozen_line_time.map_or(precise, |it| it as f64);
+        let mut z_animated = false;
         for line in &mut self.lines {
-            line.object.set_time(res.time);
+            line.object.set_time_precise(line_time - line.time_offset as f64);
+            if !line.z_index_anim.keyframes.is_empty() {
+                line.z_index_anim.set_time(res.time - line.time_offset);
+                z_animated = true;
+            }
+        }
+        if z_animated {
+            self.order.sort_by_key(|it| (self.lines[*it].now_z_index(), *it));
         }
         // TODO optimize
         let trs = self.lines.iter().map(|it| it.now_transform(res, &self.lines)).collect::<Vec<_>>();
+        let time = res.time;
         for (line, tr) in self.lines.iter_mut().zip(trs) {
+            res.time = time - line.time_offset;
+            res.time_precise = precise - line.time_offset as f64;
             line.update(res, tr);
         }
+        res.time = time;
+        res.time_precise = precise;
         for effect in &mut self.extra.effects {
             effect.update(res);
         }
@@ -101,12 +745,22 @@ impl Chart {
             video.render(res);
         }
         res.apply_model_of(&Matrix::identity().append_nonuniform_scaling(&Vector::new(1.0, -1.0)), |res| {
+            for flash in self.extra.flashes.iter().filter(|it| it.behind) {
+                flash.render(res);
+            }
             let mut guard = self.bpm_list.borrow_mut();
+            let time = res.time;
             for id in &self.order {
-                self.lines[*id].render(ui, res, &self.lines, &mut guard, &self.settings, *id);
+                let line = &self.lines[*id];
+                res.time = time - line.time_offset;
+                line.render(ui, res, &self.lines, &mut guard, &self.settings, *id);
             }
+            res.time = time;
             drop(guard);
             res.note_buffer.borrow_mut().draw_all();
+            for flash in self.extra.flashes.iter().filter(|it| !it.behind) {
+                flash.render(res);
+            }
             if res.config.sample_count > 1 {
                 unsafe { get_internal_gl() }.flush();
                 if let Some(target) = &res.chart_target {
@@ -121,3 +775,292 @@ impl Chart {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_pec;
+
+    fn chart(notes: &str) -> Chart {
+        parse_pec(&format!("0\nbp 0 120\n{notes}"), ChartExtra::default()).unwrap()
+    }
+
+    #[test]
+    fn seed_determines_randomness() {
+        // off beats, so that every note can be thinned out
+        let notes: String = (0..40).map(|i| format!("n1 0 {i}.5 0 1 0\n")).collect();
+        let thinned = |seed| {
+            let mut chart = chart(&notes);
+            chart.set_seed(seed);
+            chart.thin_notes(0.5);
+            chart.lines[0].notes.iter().map(|it| it.time).collect::<Vec<_>>()
+        };
+        assert_eq!(thinned(1), thinned(1));
+        assert_ne!(thinned(1), thinned(2));
+
+        let mut chart = chart(&notes);
+        chart.set_seed(1);
+        let first = chart.note_random(0, 3, 0);
+        assert_eq!(first, chart.note_random(0, 3, 0));
+        chart.set_seed(2);
+        assert_ne!(first, chart.note_random(0, 3, 0));
+    }
+
+    #[test]
+    fn thin_notes_keeps_strong_beats() {
+        // a note every half beat for 16 beats
+        let notes: String = (0..32).map(|i| format!("n1 0 {} 0 1 0\n", i as f32 / 2.)).collect();
+        let mut chart = chart(&notes);
+        let times = |chart: &Chart| chart.lines[0].notes.iter().map(|it| it.time).collect::<Vec<_>>();
+        let before = times(&chart);
+        chart.lines[0].attach_note = Some((0, 31));
+        for note in &mut chart.lines[0].notes {
+            note.anchor = Some((0, 29));
+        }
+        let mut sorted = before.clone();
+        sorted.sort_by_key(|it| it.not_nan());
+        chart.thin_notes(0.5);
+        let after = times(&chart);
+        assert_eq!(after.len(), 16);
+        for time in before.iter().step_by(4) {
+            assert!(after.contains(time));
+        }
+        // indices still point to the same notes, or are cleared along with them
+        let mut sorted_after = after.clone();
+        sorted_after.sort_by_key(|it| it.not_nan());
+        match chart.lines[0].attach_note {
+            Some((line, id)) => assert_eq!(chart.lines[line].notes[id].time, before[31]),
+            None => assert!(!after.contains(&before[31])),
+        }
+        // anchors count in time order, the first note is on a strong beat and kept
+        let note = chart.lines[0].notes.iter().find(|it| it.time == sorted[0]).unwrap();
+        match note.anchor {
+            Some((_, k)) => assert_eq!(sorted_after[k], sorted[29]),
+            None => assert!(!after.contains(&sorted[29])),
+        }
+    }
+
+    #[test]
+    fn reset_clears_hold_state() {
+        let mut chart = chart("n2 0 1 2 0 1 0\n");
+        let note = &mut chart.lines[0].notes[0];
+        note.judge = JudgeStatus::Hold(true, 0.5, 0., false, f32::INFINITY);
+        note.last_real_time = 0.8;
+        chart.reset();
+        let note = &chart.lines[0].notes[0];
+        assert!(matches!(note.judge, JudgeStatus::NotJudged));
+        assert_eq!(note.last_real_time, 0.);
+    }
+
+    #[test]
+    fn subdivisions_of_notes() {
+        let mut chart = chart("n1 0 1 0 1 0\nn1 0 1.5 0 1 0\nn1 0 1.25 0 1 0\nn1 0 1.3333333 0 1 0\nn1 0 1.1 0 1 0\n");
+        chart.assign_subdivisions();
+        let mut notes = chart.lines[0].notes.iter().collect::<Vec<_>>();
+        notes.sort_by_key(|it| it.time.not_nan());
+        assert_eq!(notes.into_iter().map(|it| it.subdivision).collect::<Vec<_>>(), [1, 0, 4, 3, 2]);
+    }
+
+    #[test]
+    fn events_in_time_order() {
+        let chart = chart("n1 0 3 0 1 0\nn1 0 1 0 1 0\nn1 1 2 0 1 0\nn2 1 0.5 4 0 1 0\n");
+        let events = chart.events_in_order().collect::<Vec<_>>();
+        assert!(events.windows(2).all(|it| it[0].time <= it[1].time));
+        let mut notes = events
+            .iter()
+            .filter_map(|it| match it.kind {
+                TimedEventKind::Note(id) => Some((it.line, id)),
+                TimedEventKind::Keyframe(_) => None,
+            })
+            .collect::<Vec<_>>();
+        notes.sort_unstable();
+        assert_eq!(notes, [(0, 0), (0, 1), (1, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn cloned_line_is_independent() {
+        let mut chart = chart("n1 0 1 0 1 0\nn1 0 2 100 1 0\n");
+        let mut clone = chart.clone_line(0, false);
+        let notes = |line: &JudgeLine| line.notes.iter().map(|it| (it.time, it.height)).collect::<Vec<_>>();
+        assert_eq!(notes(&clone), notes(&chart.lines[0]));
+        clone.notes[0].judge = JudgeStatus::Judged;
+        clone.notes[1].time = 5.;
+        assert!(matches!(chart.lines[0].notes[0].judge, JudgeStatus::NotJudged));
+        assert_ne!(chart.lines[0].notes[1].time, 5.);
+        chart.lines[0].notes.clear();
+        assert_eq!(clone.notes.len(), 2);
+    }
+
+    #[test]
+    fn cloned_line_gets_fresh_cache() {
+        let mut chart = chart("n1 0 1 0 1 0\nn1 0 2 0 1 0\nn1 0 3 0 1 0\nn1 1 1 0 1 0\n");
+        chart.lines[1].parent = Some(0);
+        // notes out of the order the cache expects, as an editor may leave them
+        chart.lines[0].notes.reverse();
+        let clone = chart.clone_line(0, false);
+        let times = clone.notes.iter().map(|it| it.time).collect::<Vec<_>>();
+        assert_eq!(times, [0.5, 1., 1.5]);
+        assert_eq!(chart.lines[0].notes[0].time, 1.5);
+
+        assert_eq!(chart.clone_line(1, false).parent, None);
+        assert_eq!(chart.clone_line(1, true).parent, Some(0));
+    }
+
+    #[test]
+    fn reset_play_state_matches_fresh_parse() {
+        let notes = "n2 0 1 3 0 1 0\nn1 0 2 0 1 0\n";
+        let mut chart = chart(notes);
+        for line in &mut chart.lines {
+            line.object.set_time(10.);
+            line.height.set_time(10.);
+            for note in &mut line.notes {
+                note.judge = JudgeStatus::Judged;
+                note.object.set_time(10.);
+            }
+        }
+        chart.reset_play_state();
+        let fresh = self::chart(notes);
+        for (a, b) in chart.lines.iter().zip(&fresh.lines) {
+            assert_eq!(a.object.translation.0.time, b.object.translation.0.time);
+            assert_eq!(a.height.now(), b.height.now());
+            for (a, b) in a.notes.iter().zip(&b.notes) {
+                assert!(matches!(a.judge, JudgeStatus::NotJudged));
+                assert_eq!(a.object.translation.0.time, b.object.translation.0.time);
+            }
+        }
+    }
+
+    #[test]
+    fn transposed_lines_keep_relations() {
+        let mut chart = chart("n1 0 1 0 1 0\nn1 1 2 0 1 0\nn1 2 3 0 1 0\n");
+        chart.lines[2].parent = Some(0);
+        chart.lines[1].notes[0].anchor = Some((2, 0));
+        chart.transpose_lines(&[2, 0, 1]).unwrap();
+        let times = chart.lines.iter().map(|it| it.notes[0].time).collect::<Vec<_>>();
+        assert_eq!(times, [1.5, 0.5, 1.]);
+        assert_eq!(chart.lines[0].parent, Some(1));
+        assert_eq!(chart.lines[2].notes[0].anchor, Some((0, 0)));
+
+        assert!(chart.transpose_lines(&[0, 0, 1]).is_err());
+        assert!(chart.transpose_lines(&[0, 1]).is_err());
+    }
+
+    #[test]
+    fn click_track_of_judged_notes() {
+        let mut chart = chart("n1 0 2 0 1 0\nn1 0 1 0 1 1\nn1 1 1 0 1 0\nn2 1 3 4 0 1 0\n");
+        chart.lines[1].time_offset = 0.25;
+        assert_eq!(chart.click_track(), [0.75, 1., 1.75]);
+        let mut out = Vec::new();
+        chart.write_click_track(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "0.750000\n1.000000\n1.750000\n");
+    }
+
+    #[test]
+    fn countdown_before_first_note() {
+        let mut chart = chart("n1 0 8 0 1 0\nn1 0 6 0 1 1\n");
+        assert_eq!(chart.countdown(2.), None);
+        chart.settings.countdown = 3;
+        // the first judged note is at 4s
+        assert_eq!(chart.countdown(0.5), None);
+        assert_eq!(chart.countdown(1.), Some((3, 1.)));
+        assert_eq!(chart.countdown(2.5), Some((2, 0.5)));
+        assert_eq!(chart.countdown(3.75), Some((1, 0.25)));
+        assert_eq!(chart.countdown(4.), None);
+    }
+
+    #[test]
+    fn animated_z_index_reorders_lines() {
+        use crate::core::{AnimFloat, Keyframe};
+        let mut chart = chart("n1 0 1 0 1 0\nn1 1 1 0 1 0\n");
+        chart.lines[0].z_index = -1;
+        assert_eq!(Chart::order_lines(&chart.lines).0, [0, 1]);
+        let line = &mut chart.lines[0];
+        line.z_index_anim = AnimFloat::new(vec![Keyframe::new(0., 0., 2), Keyframe::new(2., 2., 2)]);
+        line.z_index_anim.set_time(0.6);
+        // overrides the static one, rounded
+        assert_eq!(line.now_z_index(), 1);
+        assert_eq!(Chart::order_lines(&chart.lines).0, [1, 0]);
+    }
+
+    #[test]
+    fn flash_fades_out() {
+        let flash = Flash {
+            time: 1.,
+            duration: 0.5,
+            color: Color::new(1., 0., 0., 0.8),
+            behind: false,
+        };
+        assert_eq!(flash.color_at(0.9), None);
+        assert_eq!(flash.color_at(1.), Some(flash.color));
+        assert!((flash.color_at(1.25).unwrap().a - 0.4).abs() < 1e-6);
+        assert_eq!(flash.color_at(1.5), None);
+    }
+
+    #[test]
+    fn reset_forgets_approach_state() {
+        let mut chart = chart("n1 0 1 0 1 0\n");
+        chart.lines[0].notes[0].approach_inside = Some(false);
+        chart.reset();
+        // the note isn't known to have been outside, so it won't tick if it's already inside after a seek
+        assert_eq!(chart.lines[0].notes[0].approach_inside, None);
+    }
+
+    #[test]
+    fn offset_from_synthetic_clicks() {
+        const SAMPLE_RATE: u32 = 8000;
+        let chart = chart("n1 0 1 0 1 0\nn1 0 2 0 1 0\nn1 0 3 0 1 0\nn1 0 4 0 1 0\nn1 0 5 0 1 0\n");
+        for lag in [0., 0.2, -0.1, 0.05] {
+            // 25ms clicks, `lag` after the notes
+            let mut samples = vec![0.; SAMPLE_RATE as usize * 4];
+            for time in chart.click_track() {
+                let start = ((time + lag) * SAMPLE_RATE as f32) as usize;
+                samples[start..start + 200].fill(1.);
+            }
+            let offset = chart.suggest_offset(&samples, SAMPLE_RATE).unwrap();
+            // within about a frame of analysis
+            assert!((offset - lag).abs() < 0.04, "{offset} vs {lag}");
+        }
+        assert_eq!(chart.suggest_offset(&[0.; 8000], SAMPLE_RATE), None);
+        assert_eq!(chart.suggest_offset(&[0.; 100], SAMPLE_RATE), None);
+    }
+
+    #[test]
+    fn frozen_line_time() {
+        let mut settings = ChartSettings::default();
+        assert_eq!(settings.line_time(3.), 3.);
+        settings.frozen_line_time = Some(1.);
+        assert_eq!(settings.line_time(3.), 1.);
+    }
+
+    #[test]
+    fn attached_note_scrolls() {
+        // a note 2s ahead on a line scrolling at unit speed
+        let mut chart = chart("cv 0 0 5.85\nn1 0 4 0 1 0\n");
+        chart.lines[0].note_offset = 0.1;
+        let mut last = f32::INFINITY;
+        for time in [0., 0.5, 1., 1.5] {
+            chart.lines[0].height.set_time(time);
+            let distance = note_distance(&chart.lines[0], &chart.lines[0].notes[0], 1.);
+            assert!(distance < last);
+            last = distance;
+        }
+        // and rests on the line (apart from the offset) once it's hit
+        chart.lines[0].height.set_time(2.);
+        assert!((note_distance(&chart.lines[0], &chart.lines[0].notes[0], 1.) - 0.1).abs() < 1e-5);
+    }
+
+    #[test]
+    fn prime_samples_animations() {
+        // a line turning by 90° and scrolling at unit speed over 2s
+        let mut chart = chart("cv 0 0 5.85\ncd 0 0 0\ncr 0 0 4 90 2\nn1 0 8 0 1 0\n");
+        chart.lines[0].notes[0].judge = JudgeStatus::Judged;
+        chart.prime(1.);
+        let line = &chart.lines[0];
+        let rotation = &line.object.rotation;
+        assert!(rotation.now() < 0.);
+        assert!((rotation.now() - rotation.value_at(1.)).abs() < 1e-5);
+        assert!((line.height.now() - 1.).abs() < 1e-5);
+        // ready to be played from there
+        assert!(matches!(line.notes[0].judge, JudgeStatus::NotJudged));
+    }
+}
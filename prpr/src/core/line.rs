@@ -1,16 +1,18 @@
-use super::{chart::ChartSettings, object::CtrlObject, Anim, AnimFloat, BpmList, Matrix, Note, Object, Point, RenderConfig, Resource, Vector};
+use super::{
+    chart::ChartSettings, object::CtrlObject, Anim, AnimFloat, BpmList, Matrix, Note, NoteKind, Object, Point, RenderConfig, Resource, Vector,
+};
 use crate::{
-    ext::{draw_text_aligned, get_viewport, NotNanExt, SafeTexture},
+    ext::{draw_text_aligned, get_viewport, make_blend_pipeline, NotNanExt, SafeTexture},
     judge::JudgeStatus,
     ui::Ui,
 };
 use macroquad::prelude::*;
-use miniquad::{RenderPass, Texture, TextureParams, TextureWrap};
+use miniquad::{BlendFactor, BlendState, BlendValue, Equation, RenderPass, Texture, TextureParams, TextureWrap};
 use nalgebra::Rotation2;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 
-#[derive(Clone, Copy, Deserialize)]
+#[derive(Clone, Copy, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 #[repr(usize)]
 pub enum UIElement {
@@ -23,30 +25,187 @@ pub enum UIElement {
     Level,
 }
 
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Additive,
+    Multiply,
+}
+
+/// The screen edge notes of a line fly in from, see [`JudgeLine::spawn_from`].
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpawnDirection {
+    Left,
+    Right,
+    Top,
+    Bottom,
+}
+
+impl BlendMode {
+    /// Factors of the line's colors and of what's beneath them, which are added up. `None` for the default blending.
+    fn blend_factors(self) -> Option<(BlendFactor, BlendFactor)> {
+        match self {
+            Self::Normal => None,
+            Self::Additive => Some((BlendFactor::Value(BlendValue::SourceAlpha), BlendFactor::One)),
+            Self::Multiply => Some((BlendFactor::Value(BlendValue::DestinationColor), BlendFactor::OneMinusValue(BlendValue::SourceAlpha))),
+        }
+    }
+
+    fn pipeline(self) -> Option<GlPipeline> {
+        thread_local! {
+            static PIPELINES: RefCell<[Option<GlPipeline>; 2]> = RefCell::default();
+        }
+        let (source, destination) = self.blend_factors()?;
+        let state = BlendState::new(Equation::Add, source, destination);
+        let index = self as usize - 1;
+        PIPELINES.with(|it| Some(*it.borrow_mut()[index].get_or_insert_with(|| make_blend_pipeline(state))))
+    }
+}
+
 #[derive(Default)]
 pub enum JudgeLineKind {
     #[default]
     Normal,
     Texture(SafeTexture),
-    Text(Anim<String>),
+    /// Text and its rotation (in degrees) relative to the line
+    Text(Anim<String>, AnimFloat),
     Paint(Anim<f32>, RefCell<(Option<RenderPass>, bool)>),
 }
 
+impl Clone for JudgeLineKind {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Normal => Self::Normal,
+            Self::Texture(texture) => Self::Texture(texture.clone()),
+            Self::Text(anim, rotation) => Self::Text(anim.clone(), rotation.clone()),
+            // the canvas is not shared, the clone paints on its own
+            Self::Paint(anim, _) => Self::Paint(anim.clone(), RefCell::default()),
+        }
+    }
+}
+
+/// Notes whose speeds differ by less than this share a culling group.
+const SPEED_GROUP_TOLERANCE: f32 = 1e-4;
+
+#[inline]
+fn speed_group(speed: f32) -> i64 {
+    (speed / SPEED_GROUP_TOLERANCE).round() as i64
+}
+
+/// Sorts a group of notes into drawing order for [`crate::config::Config::note_time_order`]: earlier notes are drawn
+/// first, beneath later ones.
+fn sort_by_time(notes: &mut [&Note]) {
+    notes.sort_by_key(|it| it.time.not_nan());
+}
+
+/// Source rect for a `texture_size` texture tiled along a line `len` long on each side, `tile_width` per tile. It's larger
+/// than the texture, so that UVs go beyond 1 and wrap around (the texture is set to repeat on load).
+fn tiled_source(texture_size: Vec2, tile_width: f32, len: f32) -> Rect {
+    let repeats = 2. * len / tile_width;
+    Rect::new(0., 0., texture_size.x * repeats, texture_size.y)
+}
+
+/// Position and rotation (in radians) in UI coordinates of a line transformed by `tr`, see [`JudgeLine::screen_transform`].
+fn screen_transform(tr: &Matrix) -> (Point, f32) {
+    // the chart is rendered flipped vertically
+    (Point::new(tr[(0, 2)], -tr[(1, 2)]), -tr[(1, 0)].atan2(tr[(0, 0)]))
+}
+
+/// Whether `note`, scrolling at `speed` towards a line at `line_height`, is further than `limit` from it on screen (`offset`
+/// included). Compared in screen space, since notes are sorted by `height * speed` and negative speeds flip the order.
+fn past_limit(note: &Note, line_height: f32, speed: f32, offset: f32, limit: f32) -> bool {
+    (note.height - line_height + note.object.translation.1.now()) * speed + offset > limit
+}
+
+/// Builds the height remapping used by [`crate::config::Config::density_scroll`]: gaps between consecutive note heights
+/// shrink where notes are denser than on average and grow where they're sparser.
+fn density_map(notes: &[Note]) -> Vec<(f32, f32)> {
+    let mut heights: Vec<f32> = notes.iter().map(|it| it.height).collect();
+    heights.sort_by_key(|it| it.not_nan());
+    heights.dedup();
+    if heights.len() < 2 {
+        return Vec::new();
+    }
+    let avg = (heights[heights.len() - 1] - heights[0]) / (heights.len() - 1) as f32;
+    let mut mapped = heights[0];
+    let mut map = vec![(heights[0], mapped)];
+    for pair in heights.windows(2) {
+        let gap = pair[1] - pair[0];
+        mapped += gap * (gap / avg).clamp(0.5, 2.);
+        map.push((pair[1], mapped));
+    }
+    map
+}
+
+/// Applies a map built by [`density_map`], keeping the original spacing beyond its ends.
+pub(crate) fn map_height(map: &[(f32, f32)], height: f32) -> f32 {
+    if map.is_empty() {
+        return height;
+    }
+    let index = map.partition_point(|it| it.0 <= height);
+    if index == 0 {
+        map[0].1 + height - map[0].0
+    } else if index == map.len() {
+        map[index - 1].1 + height - map[index - 1].0
+    } else {
+        let ((h1, m1), (h2, m2)) = (map[index - 1], map[index]);
+        m1 + (height - h1) / (h2 - h1) * (m2 - m1)
+    }
+}
+
+/// Sine of an incline of `degrees`, clamped to the one of `max` degrees, see [`crate::config::Config::max_incline`].
+fn incline_sin(degrees: f32, max: f32) -> f32 {
+    let limit = max.to_radians().sin().abs();
+    degrees.to_radians().sin().clamp(-limit, limit)
+}
+
+/// Alpha a line is drawn with, given its own `alpha` and the global one. Alphas above 1 (used by PhiEdit for effects) are
+/// capped, negative ones (see [`pe_alpha_code`]) don't show the line.
+fn line_alpha(alpha: f32, global: f32) -> f32 {
+    (alpha * global).clamp(0., 1.)
+}
+
+/// The PE extension code of a negative line alpha. Codes start at -1, anything in between (e.g. float error around 0) is
+/// just a transparent line, i.e. `0`.
+fn pe_alpha_code(alpha: f32) -> u32 {
+    if alpha <= -1.0 {
+        (-alpha).floor() as u32
+    } else {
+        0
+    }
+}
+
 pub struct JudgeLineCache {
     update_order: Vec<u32>,
     not_plain_count: usize,
     above_indices: Vec<usize>,
     below_indices: Vec<usize>,
+    density_map: Vec<(f32, f32)>,
 }
 
 impl JudgeLineCache {
     pub fn new(notes: &mut Vec<Note>) -> Self {
-        notes.sort_by_key(|it| (it.plain(), !it.above, it.speed.not_nan(), ((it.height + it.object.translation.1.now()) * it.speed).not_nan()));
+        // time and x break ties so that the order doesn't depend on how the chart lists its notes; the sort being stable, notes
+        // equal in all of these keep their original order
+        notes.sort_by_key(|it| {
+            (
+                it.plain(),
+                !it.above,
+                speed_group(it.speed),
+                ((it.height + it.object.translation.1.now()) * it.speed).not_nan(),
+                it.time.not_nan(),
+                it.object.translation.0.now().not_nan(),
+            )
+        });
         let mut res = Self {
             update_order: Vec::new(),
             not_plain_count: 0,
             above_indices: Vec::new(),
             below_indices: Vec::new(),
+            density_map: Vec::new(),
         };
         res.reset(notes);
         res
@@ -54,26 +213,27 @@ impl JudgeLineCache {
 
     pub(crate) fn reset(&mut self, notes: &mut Vec<Note>) {
         self.update_order = (0..notes.len() as u32).collect();
+        self.density_map = density_map(notes);
         self.above_indices.clear();
         self.below_indices.clear();
         let mut index = notes.iter().position(|it| it.plain()).unwrap_or(notes.len());
         self.not_plain_count = index;
         while notes.get(index).map_or(false, |it| it.above) {
             self.above_indices.push(index);
-            let speed = notes[index].speed;
+            let speed = speed_group(notes[index].speed);
             loop {
                 index += 1;
-                if !notes.get(index).map_or(false, |it| it.above && it.speed == speed) {
+                if !notes.get(index).map_or(false, |it| it.above && speed_group(it.speed) == speed) {
                     break;
                 }
             }
         }
         while index != notes.len() {
             self.below_indices.push(index);
-            let speed = notes[index].speed;
+            let speed = speed_group(notes[index].speed);
             loop {
                 index += 1;
-                if !notes.get(index).map_or(false, |it| it.speed == speed) {
+                if !notes.get(index).map_or(false, |it| speed_group(it.speed) == speed) {
                     break;
                 }
             }
@@ -91,8 +251,46 @@ pub struct JudgeLine {
     pub color: Anim<Color>,
     pub parent: Option<usize>,
     pub z_index: i32,
+    /// If not empty, overrides `z_index` (rounded to the nearest integer) over time, see [`Self::now_z_index`].
+    pub z_index_anim: AnimFloat,
     pub show_below: bool,
     pub attach_ui: Option<UIElement>,
+    pub blend_mode: BlendMode,
+    /// If set, notes fly in from this screen edge shortly before they're hit. Only affects rendering.
+    pub spawn_from: Option<SpawnDirection>,
+    /// Delay (in seconds) of this line: its animations and notes happen this much later than they're written.
+    pub time_offset: f32,
+    /// If set, this line's notes are neither rendered nor judged, while the line itself is still drawn.
+    /// Can be toggled during play.
+    pub notes_disabled: bool,
+    /// If set, the line itself is only drawn while some of its notes are on screen.
+    pub hide_when_empty: bool,
+    /// If set, the texture of a [`JudgeLineKind::Texture`] line is repeated along the whole line length instead of drawn once.
+    /// The texture's wrap mode is set up when the chart is loaded, so this shouldn't be changed afterwards.
+    pub tile_texture: bool,
+    /// If set, the line and its notes are mirrored horizontally, both when rendering and judging. The underlying
+    /// animations and notes are left untouched, so this can be toggled during play.
+    pub mirrored: bool,
+    /// If set, this line's notes are scissored to the playfield, so that they can't be seen outside of it.
+    pub clip_to_playfield: bool,
+    /// If set, notes further than this (in world units) from the line aren't drawn.
+    pub appear_distance: Option<f32>,
+    /// If set, the on-screen order of this line's notes is reversed: notes enter at the line, move away from it and are
+    /// hit at the edge of the screen. Holds aren't affected.
+    pub reverse_notes: bool,
+    /// `(line, note)`: if set along with `attach_ui`, the UI element follows this note instead of the line's origin.
+    /// See [`super::Chart::note_position`].
+    pub attach_note: Option<(usize, usize)>,
+    /// If set, this line's notes aren't drawn while the line is rotated by more than 90° (either way), facing away.
+    pub cull_back_face: bool,
+    /// Multiplies the scroll speed of all of this line's notes, on top of their own speeds. Only affects rendering.
+    pub note_speed_scale: f32,
+    /// Notes keep this much distance (in world units) from the line, even when they're hit. Only affects rendering.
+    pub note_offset: f32,
+    /// Whether the line (and its notes) is drawn, switched by keyframes. Drawn if empty. Notes are still judged.
+    pub visible: Anim<bool>,
+    /// Tints (multiplied with the notes' own colors) of notes above and below the line, for telling the sides apart.
+    pub note_tints: (Option<Color>, Option<Color>),
 
     pub cache: JudgeLineCache,
 }
@@ -101,18 +299,29 @@ impl JudgeLine {
     pub fn update(&mut self, res: &mut Resource, tr: Matrix) {
         // self.object.set_time(res.time); // this is done by chart, chart has to calculate transform for us
         let rot = self.object.rotation.now();
-        self.height.set_time(res.time);
+        self.height.set_time_precise(res.precise_time());
         let line_height = self.height.now();
-        let mut ctrl_obj = self.ctrl_obj.borrow_mut();
-        self.cache.update_order.retain(|id| {
-            let note = &mut self.notes[*id as usize];
-            note.update(res, rot, &tr, &mut ctrl_obj, line_height);
-            !note.dead()
-        });
-        drop(ctrl_obj);
+        if !self.notes_disabled {
+            let mut ctrl_obj = self.ctrl_obj.borrow_mut();
+            // in the units of heights, like the culling in `render`
+            let approach = res.config.approach_sound.map(|volume| {
+                let threshold = res.config.approach_distance.unwrap_or(2. / res.aspect_ratio) * res.aspect_ratio;
+                (volume, threshold, self.note_offset * res.aspect_ratio)
+            });
+            self.cache.update_order.retain(|id| {
+                let note = &mut self.notes[*id as usize];
+                note.update(res, rot, &tr, &mut ctrl_obj, line_height);
+                if let Some((volume, threshold, offset)) = approach {
+                    let distance = (note.height - line_height + note.object.translation.1.now()) * note.speed * self.note_speed_scale + offset;
+                    note.update_approach(res, volume, distance <= threshold);
+                }
+                !note.dead(res)
+            });
+        }
         match &mut self.kind {
-            JudgeLineKind::Text(anim) => {
+            JudgeLineKind::Text(anim, rotation) => {
                 anim.set_time(res.time);
+                rotation.set_time(res.time);
             }
             JudgeLineKind::Paint(anim, ..) => {
                 anim.set_time(res.time);
@@ -120,12 +329,13 @@ impl JudgeLine {
             _ => {}
         }
         self.color.set_time(res.time);
+        self.visible.set_time(res.time);
         self.cache.above_indices.retain_mut(|index| {
-            while matches!(self.notes[*index].judge, JudgeStatus::Judged) {
+            while self.notes[*index].vanished(res) {
                 if self
                     .notes
                     .get(*index + 1)
-                    .map_or(false, |it| it.above && it.speed == self.notes[*index].speed)
+                    .map_or(false, |it| it.above && speed_group(it.speed) == speed_group(self.notes[*index].speed))
                 {
                     *index += 1;
                 } else {
@@ -135,8 +345,12 @@ impl JudgeLine {
             true
         });
         self.cache.below_indices.retain_mut(|index| {
-            while matches!(self.notes[*index].judge, JudgeStatus::Judged) {
-                if self.notes.get(*index + 1).map_or(false, |it| it.speed == self.notes[*index].speed) {
+            while self.notes[*index].vanished(res) {
+                if self
+                    .notes
+                    .get(*index + 1)
+                    .map_or(false, |it| speed_group(it.speed) == speed_group(self.notes[*index].speed))
+                {
                     *index += 1;
                 } else {
                     return false;
@@ -146,19 +360,114 @@ impl JudgeLine {
         });
     }
 
+    /// The z-index the line is currently drawn with.
+    pub fn now_z_index(&self) -> i32 {
+        self.z_index_anim.now_opt().map_or(self.z_index, |it| it.round() as i32)
+    }
+
+    /// Whether the line (and its notes) is currently drawn, see [`Self::visible`].
+    pub fn is_visible(&self) -> bool {
+        self.visible.now_opt().unwrap_or(true)
+    }
+
+    /// Tint of this line's notes on the given side, see [`Self::note_tints`].
+    pub fn note_tint(&self, above: bool) -> Option<Color> {
+        if above {
+            self.note_tints.0
+        } else {
+            self.note_tints.1
+        }
+    }
+
+    /// Whether the line is rotated to face away, so that its notes are culled. See [`Self::cull_back_face`].
+    pub fn faces_away(&self) -> bool {
+        self.cull_back_face && self.object.rotation.now().to_radians().cos() < 0.
+    }
+
+    /// Whether notes of this line are drawn at all, see [`Self::notes_disabled`] and [`Self::faces_away`].
+    pub fn draws_notes(&self) -> bool {
+        !self.notes_disabled && !self.faces_away()
+    }
+
     pub fn now_transform(&self, res: &Resource, lines: &[JudgeLine]) -> Matrix {
-        if let Some(parent) = self.parent {
+        let tr = if let Some(parent) = self.parent {
             let po = &lines[parent].object;
             let mut tr = Rotation2::new(po.rotation.now().to_radians()) * self.object.now_translation(res);
             tr += po.now_translation(res);
             self.object.now_rotation().append_translation(&tr)
         } else {
             self.object.now(res)
+        };
+        self.mirror(tr)
+    }
+
+    /// Scissor rect for this line's notes, given the playfield `viewport`. See [`Self::clip_to_playfield`].
+    fn note_scissor(&self, viewport: (i32, i32, i32, i32)) -> Option<(i32, i32, i32, i32)> {
+        self.clip_to_playfield.then_some(viewport)
+    }
+
+    /// Reflects the line's transform `tr` horizontally if it's [`Self::mirrored`].
+    fn mirror(&self, tr: Matrix) -> Matrix {
+        if self.mirrored {
+            // notes are symmetric, so the reflection is only undone for the line itself (see `render`)
+            tr.append_nonuniform_scaling(&Vector::new(-1., 1.))
+        } else {
+            tr
+        }
+    }
+
+    /// Current position and rotation (in radians) of the line in UI coordinates, for anchoring UI elements to it.
+    pub fn screen_transform(&self, res: &Resource, lines: &[JudgeLine]) -> (Point, f32) {
+        screen_transform(&self.now_transform(res, lines))
+    }
+
+    /// How far above and below the line (in note heights) the screen extends, given that the line's transform is applied.
+    fn visible_heights(res: &Resource) -> (f32, f32) {
+        let (vw, vh) = (1.1, 1.);
+        let p = [
+            res.screen_to_world(Point::new(-vw, -vh)),
+            res.screen_to_world(Point::new(-vw, vh)),
+            res.screen_to_world(Point::new(vw, -vh)),
+            res.screen_to_world(Point::new(vw, vh)),
+        ];
+        let height_above = p[0].y.max(p[1].y.max(p[2].y.max(p[3].y))) * res.aspect_ratio;
+        let height_below = -p[0].y.min(p[1].y.min(p[2].y.min(p[3].y))) * res.aspect_ratio;
+        (height_above, height_below)
+    }
+
+    /// Whether any note of this line that's yet to be hit is on screen. Like [`Self::visible_heights`], this expects the
+    /// line's transform to be applied.
+    fn has_visible_notes(&self, res: &Resource) -> bool {
+        let (height_above, height_below) = Self::visible_heights(res);
+        self.has_notes_within(res.time, height_above, height_below)
+    }
+
+    /// Whether any note of this line that's yet to be hit at `time` is within `height_above` above and `height_below` below
+    /// the line.
+    fn has_notes_within(&self, time: f32, height_above: f32, height_below: f32) -> bool {
+        if self.notes_disabled {
+            return false;
         }
+        let line_height = self.height.now();
+        self.notes.iter().any(|note| {
+            let limit = if note.above { height_above } else { height_below };
+            let speed = note.speed * self.note_speed_scale;
+            let bottom = (note.height - line_height + note.object.translation.1.now()) * speed;
+            match note.kind {
+                NoteKind::Hold { end_time, end_height, .. } => time < end_time && bottom <= limit && (end_height - line_height) * speed >= 0.,
+                _ => !matches!(note.judge, JudgeStatus::Judged) && (0. ..=limit).contains(&bottom),
+            }
+        })
     }
 
     pub fn render(&self, ui: &mut Ui, res: &mut Resource, lines: &[JudgeLine], bpm_list: &mut BpmList, settings: &ChartSettings, id: usize) {
-        let alpha = self.object.alpha.now_opt().unwrap_or(1.0) * res.alpha;
+        if !self.is_visible() {
+            return;
+        }
+        // negative values are magic (see `pe_alpha_extension`), they must not be scaled by the global alpha
+        let raw_alpha = self.object.alpha.now_opt().unwrap_or(1.0);
+        let alpha = raw_alpha * res.alpha;
+        let draw_alpha = line_alpha(raw_alpha, res.alpha);
         let color = self.color.now_opt();
         res.with_model(self.now_transform(res, lines), |res| {
             if res.config.debug {
@@ -166,78 +475,110 @@ impl JudgeLine {
                     ui.text(id.to_string()).pos(0., -0.01).anchor(0.5, 1.).size(0.8).draw();
                 });
             }
-            res.with_model(self.object.now_scale(), |res| {
-                res.apply_model(|res| match &self.kind {
-                    JudgeLineKind::Normal => {
-                        let mut color = color.unwrap_or(res.judge_line_color);
-                        color.a = alpha.max(0.0);
-                        let len = res.info.line_length;
-                        draw_line(-len, 0., len, 0., 0.01, color);
-                    }
-                    JudgeLineKind::Texture(texture) => {
-                        let mut color = color.unwrap_or(WHITE);
-                        color.a = alpha.max(0.0);
-                        let hf = vec2(texture.width() / res.aspect_ratio, texture.height() / res.aspect_ratio);
-                        draw_texture_ex(
-                            **texture,
-                            -hf.x / 2.,
-                            -hf.y / 2.,
-                            color,
-                            DrawTextureParams {
-                                dest_size: Some(hf),
-                                flip_y: true,
-                                ..Default::default()
-                            },
-                        );
-                    }
-                    JudgeLineKind::Text(anim) => {
-                        let mut color = color.unwrap_or(WHITE);
-                        color.a = alpha.max(0.0);
-                        let now = anim.now();
-                        res.apply_model_of(&Matrix::identity().append_nonuniform_scaling(&Vector::new(1., -1.)), |_| {
-                            draw_text_aligned(ui, &now, 0., 0., (0.5, 0.5), 1., color);
-                        });
-                    }
-                    JudgeLineKind::Paint(anim, state) => {
-                        let mut color = color.unwrap_or(WHITE);
-                        color.a = alpha.max(0.0) * 2.55;
-                        let mut gl = unsafe { get_internal_gl() };
-                        let mut guard = state.borrow_mut();
-                        let vp = get_viewport();
-                        let pass = *guard.0.get_or_insert_with(|| {
-                            let ctx = &mut gl.quad_context;
-                            let tex = Texture::new_render_texture(
-                                ctx,
-                                TextureParams {
-                                    width: vp.2 as _,
-                                    height: vp.3 as _,
-                                    format: miniquad::TextureFormat::RGBA8,
-                                    filter: FilterMode::Linear,
-                                    wrap: TextureWrap::Clamp,
-                                },
-                            );
-                            RenderPass::new(ctx, tex, None)
-                        });
-                        gl.flush();
-                        let old_pass = gl.quad_gl.get_active_render_pass();
-                        gl.quad_gl.render_pass(Some(pass));
-                        gl.quad_gl.viewport(None);
-                        let size = anim.now();
-                        if size <= 0. {
-                            if guard.1 {
-                                clear_background(Color::default());
-                                guard.1 = false;
+            let pipeline = self.blend_mode.pipeline();
+            if pipeline.is_some() {
+                unsafe { get_internal_gl() }.quad_gl.pipeline(pipeline);
+            }
+            if !self.hide_when_empty || self.has_visible_notes(res) {
+                let mut scale = self.object.now_scale();
+                if self.mirrored {
+                    scale = scale.prepend_nonuniform_scaling(&Vector::new(-1., 1.));
+                }
+                res.with_model(scale, |res| {
+                    res.apply_model(|res| match &self.kind {
+                        JudgeLineKind::Normal => {
+                            let mut color = color.or(settings.line_color).unwrap_or(res.judge_line_color);
+                            color.a = draw_alpha;
+                            let len = res.info.line_length;
+                            draw_line(-len, 0., len, 0., 0.01, color);
+                        }
+                        JudgeLineKind::Texture(texture) => {
+                            let mut color = color.unwrap_or(WHITE);
+                            color.a = draw_alpha;
+                            let hf = vec2(texture.width() / res.aspect_ratio, texture.height() / res.aspect_ratio);
+                            if self.tile_texture {
+                                let len = res.info.line_length;
+                                draw_texture_ex(
+                                    **texture,
+                                    -len,
+                                    -hf.y / 2.,
+                                    color,
+                                    DrawTextureParams {
+                                        dest_size: Some(vec2(2. * len, hf.y)),
+                                        source: Some(tiled_source(vec2(texture.width(), texture.height()), hf.x, len)),
+                                        flip_y: true,
+                                        ..Default::default()
+                                    },
+                                );
+                            } else {
+                                draw_texture_ex(
+                                    **texture,
+                                    -hf.x / 2.,
+                                    -hf.y / 2.,
+                                    color,
+                                    DrawTextureParams {
+                                        dest_size: Some(hf),
+                                        flip_y: true,
+                                        ..Default::default()
+                                    },
+                                );
                             }
-                        } else {
-                            ui.fill_circle(0., 0., size / vp.2 as f32 * 2., color);
-                            guard.1 = true;
                         }
-                        gl.flush();
-                        gl.quad_gl.render_pass(old_pass);
-                        gl.quad_gl.viewport(Some(vp));
-                    }
-                })
-            });
+                        JudgeLineKind::Text(anim, rotation) => {
+                            let mut color = color.unwrap_or(WHITE);
+                            color.a = draw_alpha;
+                            let now = anim.now();
+                            let model = Rotation2::new(rotation.now_opt().unwrap_or_default().to_radians())
+                                .to_homogeneous()
+                                .prepend_nonuniform_scaling(&Vector::new(1., -1.));
+                            res.apply_model_of(&model, |_| {
+                                draw_text_aligned(ui, &now, 0., 0., (0.5, 0.5), 1., color);
+                            });
+                        }
+                        JudgeLineKind::Paint(anim, state) => {
+                            let mut color = color.unwrap_or(WHITE);
+                            color.a = alpha.max(0.0) * 2.55;
+                            let mut gl = unsafe { get_internal_gl() };
+                            let mut guard = state.borrow_mut();
+                            let vp = get_viewport();
+                            let pass = *guard.0.get_or_insert_with(|| {
+                                let ctx = &mut gl.quad_context;
+                                let tex = Texture::new_render_texture(
+                                    ctx,
+                                    TextureParams {
+                                        width: vp.2 as _,
+                                        height: vp.3 as _,
+                                        format: miniquad::TextureFormat::RGBA8,
+                                        filter: FilterMode::Linear,
+                                        wrap: TextureWrap::Clamp,
+                                    },
+                                );
+                                RenderPass::new(ctx, tex, None)
+                            });
+                            gl.flush();
+                            let old_pass = gl.quad_gl.get_active_render_pass();
+                            gl.quad_gl.render_pass(Some(pass));
+                            gl.quad_gl.viewport(None);
+                            let size = anim.now();
+                            if size <= 0. {
+                                if guard.1 {
+                                    clear_background(Color::default());
+                                    guard.1 = false;
+                                }
+                            } else {
+                                ui.fill_circle(0., 0., size / vp.2 as f32 * 2., color);
+                                guard.1 = true;
+                            }
+                            gl.flush();
+                            gl.quad_gl.render_pass(old_pass);
+                            gl.quad_gl.viewport(Some(vp));
+                        }
+                    })
+                });
+            }
+            if pipeline.is_some() {
+                unsafe { get_internal_gl() }.quad_gl.pipeline(None);
+            }
             if let JudgeLineKind::Paint(_, state) = &self.kind {
                 let guard = state.borrow_mut();
                 if guard.1 {
@@ -256,20 +597,37 @@ impl JudgeLine {
                     );
                 }
             }
+            if !self.draws_notes() {
+                return;
+            }
             let mut config = RenderConfig {
                 settings,
                 ctrl_obj: &mut self.ctrl_obj.borrow_mut(),
                 line_height: self.height.now(),
                 appear_before: f32::INFINITY,
                 draw_below: self.show_below,
-                incline_sin: self.incline.now_opt().map(|it| it.to_radians().sin()).unwrap_or_default(),
+                incline_sin: self
+                    .incline
+                    .now_opt()
+                    .map(|it| incline_sin(it, res.config.max_incline))
+                    .unwrap_or_default(),
+                spawn_from: self.spawn_from,
+                appear_distance: self.appear_distance,
+                speed_scale: self.note_speed_scale,
+                note_offset: self.note_offset,
+                reverse_span: None,
+                tint: None,
+                height_map: if res.config.density_scroll {
+                    Some(&self.cache.density_map)
+                } else {
+                    None
+                },
             };
-            if alpha < 0.0 {
+            if raw_alpha < 0.0 {
                 if !settings.pe_alpha_extension {
                     return;
                 }
-                let w = (-alpha).floor() as u32;
-                match w {
+                match pe_alpha_code(raw_alpha) {
                     1 => {
                         return;
                     }
@@ -285,50 +643,344 @@ impl JudgeLine {
                     _ => {}
                 }
             }
-            let (vw, vh) = (1.1, 1.);
-            let p = [
-                res.screen_to_world(Point::new(-vw, -vh)),
-                res.screen_to_world(Point::new(-vw, vh)),
-                res.screen_to_world(Point::new(vw, -vh)),
-                res.screen_to_world(Point::new(vw, vh)),
-            ];
-            let height_above = p[0].y.max(p[1].y.max(p[2].y.max(p[3].y))) * res.aspect_ratio;
-            let height_below = -p[0].y.min(p[1].y.min(p[2].y.min(p[3].y))) * res.aspect_ratio;
-            let agg = res.config.aggressive;
+            if let Some(beats) = res.config.approach_guide_beats {
+                if let Some(y) = self.approach_guide_y(bpm_list, res.time, beats, res.aspect_ratio) {
+                    let len = res.info.line_length;
+                    let mut color = res.judge_line_color;
+                    color.a *= 0.3 * draw_alpha;
+                    res.apply_model(|_| draw_line(-len, y, len, y, 0.005, color));
+                }
+            }
+            let scissor = self.note_scissor(get_viewport());
+            if scissor.is_some() {
+                // notes are batched, the ones of other lines must not be clipped
+                res.note_buffer.borrow_mut().draw_all();
+                unsafe { get_internal_gl() }.quad_gl.scissor(scissor);
+            }
+            let (height_above, height_below) = Self::visible_heights(res);
+            let offset = self.note_offset * res.aspect_ratio;
+            // culling works on the original heights, which remapped ones can be far from, and expects scaled speeds to keep
+            // the order of notes
+            let agg = res.config.aggressive && !res.config.density_scroll && self.note_speed_scale > 0.;
+            let time_order = res.config.note_time_order;
+            let mut group = Vec::new();
+            // heights are reversed within what's visible, so culling is unaffected
+            config.reverse_span = self.reverse_notes.then_some(height_above / res.aspect_ratio);
+            config.tint = self.note_tint(true);
             for note in self.notes.iter().take(self.cache.not_plain_count).filter(|it| it.above) {
                 note.render(res, &mut config, bpm_list);
             }
             for index in &self.cache.above_indices {
                 let speed = self.notes[*index].speed;
-                let limit = height_above / speed;
+                let scaled = speed * self.note_speed_scale;
                 for note in self.notes[*index..].iter() {
-                    if !note.above || speed != note.speed {
+                    if !note.above || speed_group(speed) != speed_group(note.speed) {
                         break;
                     }
-                    if agg && note.height - config.line_height + note.object.translation.1.now() > limit {
+                    if agg && past_limit(note, config.line_height, scaled, offset, height_above) {
                         break;
                     }
-                    note.render(res, &mut config, bpm_list);
+                    if time_order {
+                        group.push(note);
+                    } else {
+                        note.render(res, &mut config, bpm_list);
+                    }
                 }
+                Self::render_in_time_order(&mut group, res, &mut config, bpm_list);
             }
+            config.reverse_span = self.reverse_notes.then_some(height_below / res.aspect_ratio);
+            config.tint = self.note_tint(false);
             res.with_model(Matrix::identity().append_nonuniform_scaling(&Vector::new(1.0, -1.0)), |res| {
                 for note in self.notes.iter().take(self.cache.not_plain_count).filter(|it| !it.above) {
                     note.render(res, &mut config, bpm_list);
                 }
                 for index in &self.cache.below_indices {
                     let speed = self.notes[*index].speed;
-                    let limit = height_below / speed;
+                    let scaled = speed * self.note_speed_scale;
                     for note in self.notes[*index..].iter() {
-                        if speed != note.speed {
+                        if speed_group(speed) != speed_group(note.speed) {
                             break;
                         }
-                        if agg && note.height - config.line_height + note.object.translation.1.now() > limit {
+                        if agg && past_limit(note, config.line_height, scaled, offset, height_below) {
                             break;
                         }
-                        note.render(res, &mut config, bpm_list);
+                        if time_order {
+                            group.push(note);
+                        } else {
+                            note.render(res, &mut config, bpm_list);
+                        }
                     }
+                    Self::render_in_time_order(&mut group, res, &mut config, bpm_list);
                 }
             });
+            if scissor.is_some() {
+                res.note_buffer.borrow_mut().draw_all();
+                unsafe { get_internal_gl() }.quad_gl.scissor(None);
+            }
         });
     }
+
+    /// How far above the line (in world units) notes `beats` ahead of `time` are, where the approach guide is drawn. See
+    /// [`crate::config::Config::approach_guide_beats`]. Charts without BPMs (e.g. Phigros ones) can't have beat-based guides.
+    fn approach_guide_y(&self, bpm_list: &mut BpmList, time: f32, beats: f32, aspect_ratio: f32) -> Option<f32> {
+        if bpm_list.is_empty() {
+            return None;
+        }
+        let ahead = bpm_list.time_beats(bpm_list.beat(time) + beats);
+        Some((self.height.value_at(ahead) - self.height.now()) / aspect_ratio)
+    }
+
+    /// Renders (and takes out) notes collected from a speed group, so that earlier ones are drawn beneath later ones.
+    fn render_in_time_order(notes: &mut Vec<&Note>, res: &mut Resource, config: &mut RenderConfig, bpm_list: &mut BpmList) {
+        sort_by_time(notes);
+        for note in notes.drain(..) {
+            note.render(res, config, bpm_list);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incline_is_clamped() {
+        assert!((incline_sin(90., 30.) - 0.5).abs() < 1e-4);
+        assert!((incline_sin(-90., 30.) + 0.5).abs() < 1e-4);
+        assert_eq!(incline_sin(10., 30.), 10f32.to_radians().sin());
+    }
+
+    #[test]
+    fn close_speeds_group_together() {
+        assert_eq!(speed_group(1.), speed_group(1. + 1e-7));
+        assert_eq!(speed_group(2.5), speed_group(2.5 - 1e-7));
+        assert_ne!(speed_group(1.), speed_group(1.001));
+    }
+
+    #[test]
+    fn alpha_codes_start_at_minus_one() {
+        assert_eq!(pe_alpha_code(-1e-6), 0);
+        assert_eq!(pe_alpha_code(-0.99), 0);
+        assert_eq!(pe_alpha_code(-1.), 1);
+        assert_eq!(pe_alpha_code(-2.5), 2);
+        assert_eq!(pe_alpha_code(-150.), 150);
+    }
+
+    #[test]
+    fn dense_notes_come_closer() {
+        let chart = crate::parse::parse_pec("0\nbp 0 120\nn1 0 1 0 1 0\nn1 0 2 0 1 0\n", Default::default()).unwrap();
+        let template = &chart.lines[0].notes[0];
+        let notes = [0., 1., 1.1, 1.2, 3.]
+            .into_iter()
+            .map(|height| Note { height, ..template.clone() })
+            .collect::<Vec<_>>();
+        let map = density_map(&notes);
+        let gap = |a: f32, b: f32| map_height(&map, b) - map_height(&map, a);
+        // the average gap is 0.75
+        assert!((gap(1., 1.1) - 0.05).abs() < 1e-4);
+        assert!((gap(1.2, 3.) - 3.6).abs() < 1e-4);
+        assert!((gap(0., 1.) - 4. / 3.).abs() < 1e-4);
+        // spacing is kept beyond the ends
+        assert!((gap(-1., 0.) - 1.).abs() < 1e-4);
+        assert!((gap(3., 5.) - 2.).abs() < 1e-4);
+        assert!(density_map(&notes[..1]).is_empty());
+    }
+
+    #[test]
+    fn note_order_ignores_listing_order() {
+        let parse = |notes: &str| crate::parse::parse_pec(&format!("0\nbp 0 120\n{notes}"), Default::default()).unwrap();
+        let key = |note: &Note| (note.time, note.object.translation.0.keyframes[0].value);
+        let a = parse("n1 0 1 100 1 0\nn1 0 1 0 1 0\nn1 0 1 0 1 0\nn1 0 2 0 1 0\n");
+        let b = parse("n1 0 2 0 1 0\nn1 0 1 0 1 0\nn1 0 1 0 1 0\nn1 0 1 100 1 0\n");
+        let mut notes = b.lines[0].notes.clone();
+        notes.reverse();
+        JudgeLineCache::new(&mut notes);
+        for other in [&b.lines[0].notes, &notes] {
+            assert_eq!(a.lines[0].notes.iter().map(key).collect::<Vec<_>>(), other.iter().map(key).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn back_face_is_culled() {
+        let mut chart = crate::parse::parse_pec("0\nbp 0 120\nn1 0 1 0 1 0\n", Default::default()).unwrap();
+        let line = &mut chart.lines[0];
+        line.object.rotation = AnimFloat::fixed(120.);
+        assert!(!line.faces_away());
+        line.cull_back_face = true;
+        assert!(line.faces_away());
+        for rotation in [0., 80., -80., 360.] {
+            line.object.rotation = AnimFloat::fixed(rotation);
+            assert!(!line.faces_away(), "at {rotation}°");
+        }
+        line.object.rotation = AnimFloat::fixed(-100.);
+        assert!(line.faces_away());
+    }
+
+    #[test]
+    fn visibility_switches_by_keyframes() {
+        use crate::core::Keyframe;
+        let mut chart = crate::parse::parse_pec("0\nbp 0 120\nn1 0 1 0 1 0\n", Default::default()).unwrap();
+        let line = &mut chart.lines[0];
+        // lines without switches are always visible
+        assert!(line.is_visible());
+        line.visible = Anim::new(vec![Keyframe::new(0., true, 0), Keyframe::new(1., false, 0), Keyframe::new(2., true, 0)]);
+        for (time, expected) in [(0.5, true), (1., false), (1.99, false), (2., true), (10., true)] {
+            line.visible.set_time(time);
+            assert_eq!(line.is_visible(), expected, "at {time}");
+        }
+    }
+
+    #[test]
+    fn approach_guide_distance() {
+        // unit speed, doubled from 1s on
+        let mut chart = crate::parse::parse_pec("0\nbp 0 120\ncv 0 0 5.85\ncv 0 2 11.7\nn1 0 1 0 1 0\n", Default::default()).unwrap();
+        let mut bpm_list = chart.bpm_list.borrow_mut();
+        let line = &mut chart.lines[0];
+        line.height.set_time(0.2);
+        // 1 beat is 0.5s
+        assert!((line.approach_guide_y(&mut bpm_list, 0.2, 1., 2.).unwrap() - 0.25).abs() < 1e-5);
+        line.height.set_time(0.5);
+        assert!((line.approach_guide_y(&mut bpm_list, 0.5, 2., 2.).unwrap() - 0.75).abs() < 1e-5);
+        assert_eq!(line.approach_guide_y(&mut BpmList::default(), 0.5, 2., 2.), None);
+    }
+
+    #[test]
+    fn alpha_above_one_is_capped() {
+        assert_eq!(line_alpha(2., 1.), 1.);
+        assert_eq!(line_alpha(2., 0.25), 0.5);
+        assert_eq!(line_alpha(0.5, 1.), 0.5);
+        assert_eq!(line_alpha(-2., 1.), 0.);
+        // and isn't taken for a PE extension code
+        assert_eq!(pe_alpha_code(2.), 0);
+    }
+
+    #[test]
+    fn blend_modes_mix_colors() {
+        // red channel of a line of `src` drawn over `dst`
+        fn blend(mode: BlendMode, src: Color, dst: Color) -> f32 {
+            let factor = |factor| match factor {
+                BlendFactor::One => 1.,
+                BlendFactor::Value(BlendValue::SourceAlpha) => src.a,
+                BlendFactor::Value(BlendValue::DestinationColor) => dst.r,
+                BlendFactor::OneMinusValue(BlendValue::SourceAlpha) => 1. - src.a,
+                _ => unreachable!(),
+            };
+            let (source, destination) = mode.blend_factors().unwrap();
+            src.r * factor(source) + dst.r * factor(destination)
+        }
+        assert!(BlendMode::Normal.blend_factors().is_none());
+        let (src, dst) = (Color::new(0.5, 0.5, 0.5, 1.), Color::new(0.4, 0.4, 0.4, 1.));
+        // brighter than both
+        assert!((blend(BlendMode::Additive, src, dst) - 0.9).abs() < 1e-6);
+        // darker than both
+        assert!((blend(BlendMode::Multiply, src, dst) - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn culling_follows_animated_heights() {
+        use crate::core::Keyframe;
+        let chart = crate::parse::parse_pec("0\nbp 0 120\nn1 0 1 0 1 0\n", Default::default()).unwrap();
+        let template = &chart.lines[0].notes[0];
+        let note = |height| Note { height, ..template.clone() };
+        // the line speeds up, and then scrolls back
+        let mut height = AnimFloat::new(vec![Keyframe::new(0., 0., 2), Keyframe::new(1., 2., 2), Keyframe::new(2., 1., 0)]);
+        height.set_time(0.25);
+        assert!(!past_limit(&note(1.2), height.now(), 1., 0., 1.));
+        assert!(past_limit(&note(2.), height.now(), 1., 0., 1.));
+        height.set_time(1.5);
+        assert!(!past_limit(&note(2.), height.now(), 1., 0., 1.));
+        // negative speeds flip the order of notes
+        assert!(!past_limit(&note(0.), 0.5, -1., 0., 1.));
+        assert!(past_limit(&note(-1.), 0.5, -1., 0., 1.));
+        // and the offset from the line counts
+        assert!(past_limit(&note(1.2), 0.5, 1., 0.4, 1.));
+    }
+
+    #[test]
+    fn screen_transform_of_line() {
+        // a line at (0.2, 0.3), rotated by 30° counterclockwise in chart coordinates
+        let tr = Rotation2::new(30f32.to_radians())
+            .to_homogeneous()
+            .append_translation(&Vector::new(0.2, 0.3));
+        let (pos, angle) = screen_transform(&tr);
+        assert!((pos - Point::new(0.2, -0.3)).norm() < 1e-6);
+        assert!((angle + 30f32.to_radians()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn disabled_notes_are_not_drawn() {
+        let mut chart = crate::parse::parse_pec("0\nbp 0 120\nn1 0 1 0 1 0\n", Default::default()).unwrap();
+        let line = &mut chart.lines[0];
+        assert!(line.draws_notes());
+        line.notes_disabled = true;
+        assert!(!line.draws_notes());
+        // toggled back live
+        line.notes_disabled = false;
+        assert!(line.draws_notes());
+    }
+
+    #[test]
+    fn empty_line_shows_as_notes_approach() {
+        let mut chart = crate::parse::parse_pec("0\nbp 0 120\ncv 0 0 5.85\nn1 0 4 0 1 0\n", Default::default()).unwrap();
+        let line = &mut chart.lines[0];
+        // the note is two heights away
+        line.height.set_time(0.);
+        assert!(!line.has_notes_within(0., 1., 1.));
+        line.height.set_time(1.5);
+        assert!(line.has_notes_within(1.5, 1., 1.));
+        // and gone once hit
+        line.notes[0].judge = JudgeStatus::Judged;
+        assert!(!line.has_notes_within(1.5, 1., 1.));
+    }
+
+    #[test]
+    fn tiled_texture_repeats() {
+        // tiles half as wide as the 4 units long line
+        let source = tiled_source(vec2(100., 20.), 0.5, 2.);
+        assert_eq!(source, Rect::new(0., 0., 800., 20.));
+        // so the texture keeps its aspect ratio
+        assert_eq!(4. / (source.w / 100.), 0.5);
+    }
+
+    #[test]
+    fn mirroring_only_flips_its_line() {
+        let mut chart = crate::parse::parse_pec("0\nbp 0 120\nn1 0 1 0 1 0\nn1 1 1 0 1 0\n", Default::default()).unwrap();
+        chart.lines[1].mirrored = true;
+        let tr = Matrix::new_translation(&Vector::new(0.25, 0.125));
+        let p = Point::new(0.5, 0.25);
+        assert_eq!(chart.lines[0].mirror(tr).transform_point(&p), Point::new(0.75, 0.375));
+        // the line's position is mirrored as well
+        assert_eq!(chart.lines[1].mirror(tr).transform_point(&p), Point::new(-0.75, 0.375));
+        // notes are left untouched
+        assert_eq!(chart.lines[1].notes[0].object.translation.0.now(), 0.);
+    }
+
+    #[test]
+    fn earlier_notes_drawn_beneath() {
+        let chart = crate::parse::parse_pec("0\nbp 0 120\nn1 0 1 0 1 0\n", Default::default()).unwrap();
+        let template = &chart.lines[0].notes[0];
+        // at the same height, as if the line stopped between them, with the later one first
+        let note = |time| Note { time, ..template.clone() };
+        let (late, early) = (note(2.), note(1.));
+        let mut group = vec![&late, &early];
+        sort_by_time(&mut group);
+        assert_eq!(group[0].time, 1.);
+        assert_eq!(group[1].time, 2.);
+    }
+
+    #[test]
+    fn notes_clipped_to_playfield() {
+        let mut chart = crate::parse::parse_pec("0\nbp 0 120\nn1 0 1 0 1 0\nn1 1 1 0 1 0\n", Default::default()).unwrap();
+        chart.lines[0].clip_to_playfield = true;
+        // a 4:3 playfield letterboxed in a wider window
+        let viewport = (160, 0, 960, 720);
+        let inside = |(x, y, w, h): (i32, i32, i32, i32), px: i32, py: i32| (x..x + w).contains(&px) && (y..y + h).contains(&py);
+        let scissor = chart.lines[0].note_scissor(viewport).unwrap();
+        assert!(inside(scissor, 640, 360));
+        // a note drawn in the margin is cut off
+        assert!(!inside(scissor, 100, 360));
+        assert!(!inside(scissor, 1200, 360));
+        // other lines aren't clipped
+        assert_eq!(chart.lines[1].note_scissor(viewport), None);
+    }
 }
@@ -1,15 +1,34 @@
-use super::{chart::ChartSettings, BpmList, CtrlObject, JudgeLine, Matrix, Object, Point, Resource, JUDGE_LINE_GOOD_COLOR, JUDGE_LINE_PERFECT_COLOR};
-use crate::{judge::JudgeStatus, parse::RPE_HEIGHT};
+use super::{
+    chart::ChartSettings, line::map_height, BpmList, CtrlObject, JudgeLine, Matrix, NoteAtlas, Object, Point, Resource, SpawnDirection, Vector,
+};
+use crate::{config::InclineMode, judge::JudgeStatus, parse::RPE_HEIGHT};
 use macroquad::prelude::*;
+use sasa::PlaySfxParams;
+use serde::{Deserialize, Serialize};
 
 const HOLD_PARTICLE_INTERVAL: f32 = 0.15;
 const FADEOUT_TIME: f32 = 0.16;
 const BAD_TIME: f32 = 0.5;
+const SPAWN_LEAD_TIME: f32 = 1.;
+const SPAWN_TIME: f32 = 0.3;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HoldKind {
+    #[default]
+    Normal,
+    /// Has to be pressed again at the end, in addition to being held from the start
+    Pinch,
+    /// Released at the start and pressed at the end, must not be held in between
+    Reverse,
+    /// Judged like a drag along its whole length: touching its path is enough, and gaps only downgrade it to a good
+    Drag,
+}
 
 #[derive(Clone, Debug)]
 pub enum NoteKind {
     Click,
-    Hold { end_time: f32, end_height: f32 },
+    Hold { end_time: f32, end_height: f32, kind: HoldKind },
     Flick,
     Drag,
 }
@@ -25,12 +44,46 @@ impl NoteKind {
     }
 }
 
+#[derive(Clone)]
 pub struct Note {
     pub object: Object,
     pub kind: NoteKind,
     pub time: f32,
     pub height: f32,
     pub speed: f32,
+    /// The last real time (i.e. unaffected by playback speed) at which this note is being held.
+    /// Only meaningful for holds; stays `0.` for other kinds of notes.
+    pub last_real_time: f32,
+    /// The time at which this note was first seen judged, used for its disappearing animation.
+    pub judged_time: Option<f32>,
+    /// Whether this note was within the approach distance when last updated, `None` if not updated since the last seek.
+    /// See [`crate::config::Config::approach_sound`].
+    pub approach_inside: Option<bool>,
+    /// The beat subdivision this note lies on (e.g. 4 for sixteenth notes), `0` if unknown.
+    /// See [`super::Chart::assign_subdivisions`].
+    pub subdivision: u32,
+    /// `(line, k)`: this note takes the timing of the `k`-th note (in time order) of judge line `line`.
+    /// Resolved when the chart is parsed.
+    pub anchor: Option<(usize, usize)>,
+    /// Id of the link group this note belongs to. A group's bonus is only awarded if all of its notes are hit.
+    pub link: Option<u32>,
+    /// How much this note counts towards accuracy (and thus score) compared to a normal one, `1.` by default.
+    pub weight: f32,
+    /// Direction (in degrees, counterclockwise from the line's direction) a flick has to be swiped in, any if unset.
+    /// See [`crate::config::Config::flick_tolerance`].
+    pub flick_dir: Option<f32>,
+    /// Notes with a higher z are drawn above the ones with a lower z, whatever their kinds and heights.
+    pub z: i8,
+    /// Autoplay leaves this note to the player, for teaching charts.
+    pub no_autoplay: bool,
+    /// If set, the note isn't drawn before this time, however early its speed and height would show it. For reveal effects.
+    pub spawn_time: Option<f32>,
+    /// The opposite of `fake`: the note is judged as usual, but never drawn.
+    pub invisible: bool,
+    /// For holds, how long (in seconds) before the end releasing still counts as holding through.
+    /// [`crate::judge::LIMIT_BAD`] if unset.
+    pub release_grace: Option<f32>,
+    pub sound: HitSoundParams,
 
     pub above: bool,
     pub multiple_hint: bool,
@@ -38,6 +91,21 @@ pub struct Note {
     pub judge: JudgeStatus,
 }
 
+/// Audio parameters of a note's hit sound.
+#[derive(Clone, Copy, Debug)]
+pub struct HitSoundParams {
+    /// Multiplies the global sound effect volume
+    pub volume: f32,
+    /// From `-1.` (left) to `1.` (right)
+    pub pan: f32,
+}
+
+impl Default for HitSoundParams {
+    fn default() -> Self {
+        Self { volume: 1., pan: 0. }
+    }
+}
+
 pub struct RenderConfig<'a> {
     pub settings: &'a ChartSettings,
     pub ctrl_obj: &'a mut CtrlObject,
@@ -45,9 +113,99 @@ pub struct RenderConfig<'a> {
     pub appear_before: f32,
     pub draw_below: bool,
     pub incline_sin: f32,
+    pub spawn_from: Option<SpawnDirection>,
+    pub appear_distance: Option<f32>,
+    /// Scroll speed multiplier of the line, see [`JudgeLine::note_speed_scale`]
+    pub speed_scale: f32,
+    /// Extra distance of notes from the line, see [`JudgeLine::note_offset`]
+    pub note_offset: f32,
+    /// Visible height on the side of the line being drawn if notes are reversed, see [`JudgeLine::reverse_notes`]
+    pub reverse_span: Option<f32>,
+    /// Tint of notes on the side of the line being drawn, see [`JudgeLine::note_tints`]
+    pub tint: Option<Color>,
+    /// Remapping of note heights, see [`crate::config::Config::density_scroll`]
+    pub height_map: Option<&'a [(f32, f32)]>,
+}
+
+impl RenderConfig<'_> {
+    fn map_height(&self, height: f32) -> f32 {
+        self.height_map.map_or(height, |map| map_height(map, height))
+    }
+
+    /// Height (in world units) a note of `speed` is drawn at for `height`, given the line's speed scale.
+    fn world_height(&self, height: f32, aspect_ratio: f32, speed: f32) -> f32 {
+        self.map_height(height) / aspect_ratio * speed * self.speed_scale
+    }
+
+    /// `color` with the tint of the side being drawn applied.
+    fn tinted(&self, color: Color) -> Color {
+        match self.tint {
+            Some(tint) => Color::new(color.r * tint.r, color.g * tint.g, color.b * tint.b, color.a * tint.a),
+            None => color,
+        }
+    }
+
+    /// Where a note `distance` ahead of the line is drawn, given the line's settings. `None` if it's beyond the line's
+    /// appear distance. Holds aren't reversed.
+    fn place(&self, distance: f32, hold: bool) -> Option<f32> {
+        let base = distance + self.note_offset;
+        if self.appear_distance.map_or(false, |it| base > it) {
+            return None;
+        }
+        Some(match self.reverse_span {
+            Some(span) if !hold => span - base,
+            _ => base,
+        })
+    }
 }
 
-fn draw_tex(res: &Resource, texture: Texture2D, order: i8, x: f32, y: f32, color: Color, mut params: DrawTextureParams, clip: bool) {
+/// Scale (and alpha) of a note `base` away from the line, see [`crate::config::Config::approach_distance`].
+fn approach_factor(base: f32, distance: f32) -> f32 {
+    (1. - base / distance).clamp(0., 1.)
+}
+
+/// Alpha of a note `base` away from the line, fading out within `distance` of it, see
+/// [`crate::config::Config::fade_near_line`].
+fn near_line_alpha(base: f32, distance: f32) -> f32 {
+    (base.abs() / distance).min(1.)
+}
+
+/// How far (from `0.` to `1.`) a note at `note_time` has flown in from the spawning edge at `time`, see
+/// [`JudgeLine::spawn_from`].
+fn spawn_progress(note_time: f32, time: f32) -> f32 {
+    ((time - (note_time - SPAWN_LEAD_TIME - SPAWN_TIME)) / SPAWN_TIME).clamp(0., 1.)
+}
+
+/// Screen position of a note resting at `screen` while flying in from `from` with `progress`, the screen extending to
+/// `top` vertically. It starts just past the edge.
+fn spawn_point(screen: Point, from: SpawnDirection, progress: f32, top: f32) -> Point {
+    let top = top + 0.1;
+    let edge = match from {
+        SpawnDirection::Left => Point::new(-1.1, screen.y),
+        SpawnDirection::Right => Point::new(1.1, screen.y),
+        SpawnDirection::Top => Point::new(screen.x, top),
+        SpawnDirection::Bottom => Point::new(screen.x, -top),
+    };
+    let t = 1. - (1. - progress).powi(3);
+    edge + (screen - edge) * t
+}
+
+/// Applies the incline factor of a note (`1.` on the line) to its translation and scale, as `mode` says: either the note is
+/// moved horizontally, or it's scaled as if it were further away.
+fn apply_incline(mode: InclineMode, factor: f32, tr: &mut Vector, scale: &mut Vector) {
+    match mode {
+        InclineMode::Shear => tr.x *= factor,
+        InclineMode::Depth => *scale *= factor.max(0.),
+    }
+}
+
+/// Alpha of a note at screen height `y` (in `-top..top`) fading out within `band` of the edges, see
+/// [`crate::config::Config::edge_fade`].
+fn edge_fade_alpha(y: f32, top: f32, band: f32) -> f32 {
+    ((top - y.abs()) / band).clamp(0., 1.)
+}
+
+fn draw_tex(res: &Resource, texture: Texture2D, order: (i8, i8), x: f32, y: f32, color: Color, mut params: DrawTextureParams, clip: bool) {
     let Vec2 { x: w, y: h } = params.dest_size.unwrap();
     if h < 0. {
         return;
@@ -69,7 +227,7 @@ fn draw_tex(res: &Resource, texture: Texture2D, order: i8, x: f32, y: f32, color
     params.flip_y = true;
     draw_tex_pts(res, texture, order, p, color, params);
 }
-fn draw_tex_pts(res: &Resource, texture: Texture2D, order: i8, p: [Point; 4], color: Color, params: DrawTextureParams) {
+fn draw_tex_pts(res: &Resource, texture: Texture2D, order: (i8, i8), p: [Point; 4], color: Color, params: DrawTextureParams) {
     let mut p = p.map(|it| res.world_to_screen(it));
     if p[0].x.min(p[1].x.min(p[2].x.min(p[3].x))) > 1.
         || p[0].x.max(p[1].x.max(p[2].x.max(p[3].x))) < -1.
@@ -101,8 +259,9 @@ fn draw_tex_pts(res: &Resource, texture: Texture2D, order: i8, p: [Point; 4], co
         .push((order, texture.raw_miniquad_texture_handle().gl_internal_id()), vertices);
 }
 
-fn draw_center(res: &Resource, tex: Texture2D, order: i8, scale: f32, color: Color) {
-    let hf = vec2(scale, tex.height() * scale / tex.width());
+fn draw_center(res: &Resource, tex: Texture2D, source: Option<Rect>, order: (i8, i8), scale: f32, color: Color) {
+    let r = source.unwrap_or(Rect::new(0., 0., 1., 1.));
+    let hf = vec2(scale, tex.height() * r.h * scale / (tex.width() * r.w));
     draw_tex(
         res,
         tex,
@@ -111,6 +270,7 @@ fn draw_center(res: &Resource, tex: Texture2D, order: i8, scale: f32, color: Col
         -hf.y,
         color,
         DrawTextureParams {
+            source,
             dest_size: Some(hf * 2.),
             ..Default::default()
         },
@@ -123,17 +283,25 @@ impl Note {
         line.object.rotation.now() + if self.above { 0. } else { 180. }
     }
 
+    /// Notes are drawn in ascending order of this: by z, and then by kind.
+    pub fn draw_order(&self) -> (i8, i8) {
+        (self.z, self.kind.order())
+    }
+
     pub fn plain(&self) -> bool {
         !self.fake && !matches!(self.kind, NoteKind::Hold { .. }) && self.object.translation.1.keyframes.len() <= 1
         // && self.ctrl_obj.is_default()
     }
 
     pub fn update(&mut self, res: &mut Resource, parent_rot: f32, parent_tr: &Matrix, ctrl_obj: &mut CtrlObject, line_height: f32) {
-        self.object.set_time(res.time);
+        self.object.set_time_precise(res.precise_time());
+        if self.judged_time.is_none() && matches!(self.judge, JudgeStatus::Judged) {
+            self.judged_time = Some(res.time);
+        }
         if let Some(color) = if let JudgeStatus::Hold(perfect, at, ..) = &mut self.judge {
             if res.time > *at {
                 *at += HOLD_PARTICLE_INTERVAL / res.config.speed;
-                Some(if *perfect { JUDGE_LINE_PERFECT_COLOR } else { JUDGE_LINE_GOOD_COLOR })
+                Some(res.hit_colors.of(*perfect))
             } else {
                 None
             }
@@ -147,11 +315,63 @@ impl Note {
         }
     }
 
-    pub fn dead(&self) -> bool {
-        (!matches!(self.kind, NoteKind::Hold { .. }) || matches!(self.judge, JudgeStatus::Judged)) && self.object.dead()
+    /// Plays the approach tick if the note just came within the approach distance, see
+    /// [`crate::config::Config::approach_sound`].
+    pub(crate) fn update_approach(&mut self, res: &mut Resource, volume: f32, inside: bool) {
+        if self.approach_ticks(inside, res.time) {
+            let amplifier = res.config.volume_sfx * volume;
+            if amplifier > 1e-2 {
+                let _ = res.sfx_tick.play(PlaySfxParams { amplifier });
+            }
+        }
+    }
+
+    /// Whether the note ticks at `time`, being `inside` the approach distance: only when it just came inside. Notes found
+    /// already inside (e.g. right after a seek) don't tick.
+    fn approach_ticks(&mut self, inside: bool, time: f32) -> bool {
+        let was_inside = self.approach_inside.replace(inside);
+        inside && was_inside == Some(false) && !self.fake && self.time > time
+    }
+
+    pub fn dead(&self, res: &Resource) -> bool {
+        (if matches!(self.kind, NoteKind::Hold { .. }) {
+            matches!(self.judge, JudgeStatus::Judged)
+        } else {
+            // judged notes have to be kept alive until their disappearing animation is over
+            res.config.judged_fade <= 0. || self.vanished(res)
+        }) && self.object.dead()
         // && self.ctrl_obj.dead()
     }
 
+    /// Whether this note is judged and done disappearing, see [`crate::config::Config::judged_fade`].
+    pub fn vanished(&self, res: &Resource) -> bool {
+        self.vanished_at(res.time, res.config.judged_fade)
+    }
+
+    fn vanished_at(&self, time: f32, fade: f32) -> bool {
+        matches!(self.judge, JudgeStatus::Judged) && (fade <= 0. || self.judged_time.map_or(false, |it| time >= it + fade))
+    }
+
+    /// Progress (from `0.` to `1.`) of the disappearing animation of a judged note, lasting `fade` seconds.
+    fn vanish_progress(&self, time: f32, fade: f32) -> f32 {
+        self.judged_time.map_or(0., |it| (time - it) / fade)
+    }
+
+    /// Keeps a hold being held at `real_time`: it's no longer released and [`Self::last_real_time`] advances. Does nothing
+    /// to notes that aren't being held.
+    pub(crate) fn hold_on(&mut self, real_time: f32) {
+        if let JudgeStatus::Hold(.., up_time) = &mut self.judge {
+            *up_time = f32::INFINITY;
+            self.last_real_time = real_time;
+        }
+    }
+
+    /// Whether the note may be drawn at `time` at all, regardless of where it is. See [`Self::spawn_time`] and
+    /// [`Self::invisible`].
+    pub fn drawn_at(&self, time: f32) -> bool {
+        !self.invisible && self.spawn_time.map_or(true, |it| time >= it)
+    }
+
     fn init_ctrl_obj(&self, ctrl_obj: &mut CtrlObject, line_height: f32) {
         ctrl_obj.set_height((self.height - line_height + self.object.translation.1.now() / self.speed) * RPE_HEIGHT / 2.);
     }
@@ -159,17 +379,38 @@ impl Note {
     pub fn now_transform(&self, res: &Resource, ctrl_obj: &CtrlObject, base: f32, incline_sin: f32) -> Matrix {
         let incline_val = 1. - incline_sin * (base * res.aspect_ratio + self.object.translation.1.now()) * RPE_HEIGHT / 2. / 360.;
         let mut tr = self.object.now_translation(res);
-        tr.x *= incline_val * ctrl_obj.pos.now_opt().unwrap_or(1.);
-        tr.y += base;
+        tr.x *= ctrl_obj.pos.now_opt().unwrap_or(1.);
         let mut scale = self.object.scale.now_with_def(1., 1.);
         scale.x *= ctrl_obj.size.now_opt().unwrap_or(1.);
+        apply_incline(res.config.incline_mode, incline_val, &mut tr, &mut scale);
+        tr.y += base;
         self.object.now_rotation().append_nonuniform_scaling(&scale).append_translation(&tr)
     }
 
+    /// Moves `model` towards the spawning edge while the note is still flying in.
+    fn apply_spawn(&self, res: &Resource, model: Matrix, from: SpawnDirection) -> Matrix {
+        let progress = spawn_progress(self.time, res.time);
+        if progress >= 1. {
+            return model;
+        }
+        let pos = model.transform_point(&Point::origin());
+        let target = res.screen_to_world(spawn_point(res.world_to_screen(pos), from, progress, 1. / res.aspect_ratio));
+        model.append_translation(&(target - pos))
+    }
+
     pub fn render(&self, res: &mut Resource, config: &mut RenderConfig, bpm_list: &mut BpmList) {
-        if matches!(self.judge, JudgeStatus::Judged) && !matches!(self.kind, NoteKind::Hold { .. }) {
+        if !self.drawn_at(res.time) {
             return;
         }
+        // progress of the disappearing animation of judged notes
+        let vanish = if matches!(self.judge, JudgeStatus::Judged) && !matches!(self.kind, NoteKind::Hold { .. }) {
+            if self.vanished(res) {
+                return;
+            }
+            Some(self.vanish_progress(res.time, res.config.judged_fade))
+        } else {
+            None
+        };
         if config.appear_before.is_finite() {
             // TODO optimize
             let beat = bpm_list.beat(self.time);
@@ -178,47 +419,88 @@ impl Note {
                 return;
             }
         }
-        let scale = (if self.multiple_hint {
+        let mut scale = (if self.multiple_hint {
             res.res_pack.note_style_mh.click.width() / res.res_pack.note_style.click.width()
         } else {
             1.0
         }) * res.note_width;
-        let ctrl_obj = &mut config.ctrl_obj;
-        self.init_ctrl_obj(ctrl_obj, config.line_height);
-        let mut color = self.object.now_color();
+        self.init_ctrl_obj(config.ctrl_obj, config.line_height);
+        // only read from here on, while `config` is
+        let ctrl_obj = &*config.ctrl_obj;
+        let mut color = config.tinted(self.object.now_color());
         color.a *= res.alpha * ctrl_obj.alpha.now_opt().unwrap_or(1.);
         let spd = self.speed * ctrl_obj.y.now_opt().unwrap_or(1.);
 
-        let line_height = config.line_height / res.aspect_ratio * spd;
-        let height = self.height / res.aspect_ratio * spd;
+        let line_height = config.world_height(config.line_height, res.aspect_ratio, spd);
+        let height = config.world_height(self.height, res.aspect_ratio, spd);
 
-        let base = height - line_height;
+        let Some(base) = config.place(height - line_height, matches!(self.kind, NoteKind::Hold { .. })) else {
+            return;
+        };
+        if let Some(distance) = res.config.approach_distance {
+            if !matches!(self.kind, NoteKind::Hold { .. }) {
+                let factor = approach_factor(base, distance);
+                scale *= factor;
+                color.a *= factor;
+            }
+        }
+        if let Some(progress) = vanish {
+            scale *= 1. - progress;
+            color.a *= 1. - progress;
+        }
+        if let Some(distance) = res.config.fade_near_line {
+            if !matches!(self.kind, NoteKind::Hold { .. }) {
+                color.a *= near_line_alpha(base, distance);
+            }
+        }
         if !config.draw_below
+            && vanish.is_none()
             && ((res.time - FADEOUT_TIME >= self.time) || (self.fake && res.time >= self.time) || (self.time > res.time && base <= -1e-5))
             && !matches!(self.kind, NoteKind::Hold { .. })
         {
             return;
         }
-        let order = self.kind.order();
-        let style = if res.config.multiple_hint && self.multiple_hint {
-            &res.res_pack.note_style_mh
+        let order = self.draw_order();
+        let mh = res.config.multiple_hint && self.multiple_hint;
+        let style = if mh { &res.res_pack.note_style_mh } else { &res.res_pack.note_style };
+        let sub_style = if mh {
+            None
+        } else {
+            res.res_pack.subdivision_styles.get(&self.subdivision)
+        };
+        // the note atlas only replaces the default textures
+        let atlas = if mh || sub_style.is_some() {
+            None
         } else {
-            &res.res_pack.note_style
+            res.res_pack.note_atlas.as_ref()
         };
-        let draw = |res: &mut Resource, tex: Texture2D| {
+        let atlas = atlas.map(|it| (*it.texture, it.click, it.flick, it.drag));
+        let draw = |res: &mut Resource, tex: Texture2D, source: Option<Rect>| {
             let mut color = color;
-            if !config.draw_below {
+            if !config.draw_below && vanish.is_none() {
                 color.a *= (self.time - res.time).min(0.) / FADEOUT_TIME + 1.;
             }
-            res.with_model(self.now_transform(res, ctrl_obj, base, config.incline_sin), |res| {
-                draw_center(res, tex, order, scale, color);
+            let mut model = self.now_transform(res, ctrl_obj, base, config.incline_sin);
+            if let Some(from) = config.spawn_from {
+                model = self.apply_spawn(res, model, from);
+            }
+            res.with_model(model, |res| {
+                if let Some(band) = res.config.edge_fade {
+                    let y = res.world_to_screen(Point::origin()).y;
+                    color.a *= edge_fade_alpha(y, 1. / res.aspect_ratio, band);
+                }
+                draw_center(res, tex, source, order, scale, color);
             });
         };
         match self.kind {
             NoteKind::Click => {
-                draw(res, *style.click);
+                if let Some((tex, click, ..)) = atlas {
+                    draw(res, tex, Some(click));
+                } else {
+                    draw(res, *sub_style.map_or(&style.click, |it| &it.click), None);
+                }
             }
-            NoteKind::Hold { end_time, end_height } => {
+            NoteKind::Hold { end_time, end_height, kind } => {
                 res.with_model(self.now_transform(res, ctrl_obj, 0., 0.), |res| {
                     let style = if res.config.multiple_hint && self.multiple_hint {
                         &res.res_pack.note_style_mh
@@ -232,31 +514,44 @@ impl Note {
                     if res.time >= end_time {
                         return;
                     }
-                    let end_height = end_height / res.aspect_ratio * spd;
+                    let end_height = config.world_height(end_height, res.aspect_ratio, spd);
 
                     let clip = !config.draw_below && config.settings.hold_partial_cover;
 
                     let h = if self.time <= res.time { line_height } else { height };
-                    let bottom = h - line_height;
-                    let top = end_height - line_height;
+                    let bottom = h - line_height + config.note_offset;
+                    let top = end_height - line_height + config.note_offset;
                     if res.time < self.time && bottom < -1e-6 && !config.settings.hold_partial_cover {
                         return;
                     }
-                    let tex = &style.hold;
+                    // with a note atlas, the hold's sub-rect of it is laid out just like hold.png
+                    let atlas = if mh {
+                        None
+                    } else {
+                        res.res_pack.note_atlas.as_ref().map(|it| (*it.texture, it.hold))
+                    };
+                    let tex = atlas.map_or(*style.hold, |it| it.0);
+                    let uv = |r: Rect| atlas.map_or(r, |it| NoteAtlas::sub_rect(it.1, r));
                     let ratio = style.hold_ratio();
+                    // drag holds are drawn as a fainter ribbon
+                    let body_color = if kind == HoldKind::Drag {
+                        Color { a: color.a * 0.6, ..color }
+                    } else {
+                        color
+                    };
                     // body
                     // TODO (end_height - height) is not always total height
                     draw_tex(
                         res,
-                        **(if res.res_pack.info.hold_repeat {
-                            style.hold_body.as_ref().unwrap()
+                        if res.res_pack.info.hold_repeat {
+                            **style.hold_body.as_ref().unwrap()
                         } else {
                             tex
-                        }),
+                        },
                         order,
                         -scale,
                         bottom,
-                        color,
+                        body_color,
                         DrawTextureParams {
                             source: Some({
                                 if res.res_pack.info.hold_repeat {
@@ -265,7 +560,7 @@ impl Note {
                                     let height = hold_body.height();
                                     Rect::new(0., 0., 1., (top - bottom) / scale / 2. * width / height)
                                 } else {
-                                    style.hold_body_rect()
+                                    uv(style.hold_body_rect())
                                 }
                             }),
                             dest_size: Some(vec2(scale * 2., top - bottom)),
@@ -274,36 +569,77 @@ impl Note {
                         clip,
                     );
                     // head
-                    if res.time < self.time || res.res_pack.info.hold_keep_head {
-                        let r = style.hold_head_rect();
+                    if kind == HoldKind::Drag {
+                        // drag holds start with a drag note
+                        let drag = *style.drag;
+                        let hf = vec2(scale, drag.height() / drag.width() * scale);
+                        draw_tex(
+                            res,
+                            drag,
+                            order,
+                            -scale,
+                            bottom - hf.y,
+                            color,
+                            DrawTextureParams {
+                                dest_size: Some(hf * 2.),
+                                ..Default::default()
+                            },
+                            clip,
+                        );
+                    } else if res.time < self.time || res.res_pack.info.hold_keep_head {
+                        // reverse holds have their caps inverted
+                        let reverse = kind == HoldKind::Reverse;
+                        let r = if reverse { style.hold_tail_rect() } else { style.hold_head_rect() };
                         let hf = vec2(scale, r.h / r.w * scale * ratio);
                         draw_tex(
                             res,
-                            **tex,
+                            tex,
                             order,
                             -scale,
                             bottom - if res.res_pack.info.hold_compact { hf.y } else { hf.y * 2. },
                             color,
                             DrawTextureParams {
-                                source: Some(r),
+                                source: Some(uv(r)),
                                 dest_size: Some(hf * 2.),
+                                flip_y: reverse,
                                 ..Default::default()
                             },
                             clip,
                         );
                     }
                     // tail
+                    if matches!(kind, HoldKind::Pinch | HoldKind::Reverse) {
+                        // pinch and reverse holds are capped with a (flipped) head at the end
+                        let r = style.hold_head_rect();
+                        let hf = vec2(scale, r.h / r.w * scale * ratio);
+                        draw_tex(
+                            res,
+                            tex,
+                            order,
+                            -scale,
+                            top - if res.res_pack.info.hold_compact { hf.y } else { 0. },
+                            color,
+                            DrawTextureParams {
+                                source: Some(uv(r)),
+                                dest_size: Some(hf * 2.),
+                                flip_y: true,
+                                ..Default::default()
+                            },
+                            clip,
+                        );
+                        return;
+                    }
                     let r = style.hold_tail_rect();
                     let hf = vec2(scale, r.h / r.w * scale * ratio);
                     draw_tex(
                         res,
-                        **tex,
+                        tex,
                         order,
                         -scale,
                         top - if res.res_pack.info.hold_compact { hf.y } else { 0. },
                         color,
                         DrawTextureParams {
-                            source: Some(r),
+                            source: Some(uv(r)),
                             dest_size: Some(hf * 2.),
                             ..Default::default()
                         },
@@ -312,10 +648,18 @@ impl Note {
                 });
             }
             NoteKind::Flick => {
-                draw(res, *style.flick);
+                if let Some((tex, _, flick, _)) = atlas {
+                    draw(res, tex, Some(flick));
+                } else {
+                    draw(res, *sub_style.map_or(&style.flick, |it| &it.flick), None);
+                }
             }
             NoteKind::Drag => {
-                draw(res, *style.drag);
+                if let Some((tex, .., drag)) = atlas {
+                    draw(res, tex, Some(drag));
+                } else {
+                    draw(res, *sub_style.map_or(&style.drag, |it| &it.drag), None);
+                }
             }
         }
     }
@@ -342,7 +686,8 @@ impl BadNote {
                     NoteKind::Flick => *style.flick,
                     _ => unreachable!(),
                 },
-                self.kind.order(),
+                None,
+                (0, self.kind.order()),
                 res.note_width,
                 Color::new(0.423529, 0.262745, 0.262745, (self.time - res.time).max(-1.) / BAD_TIME + 1.),
             );
@@ -350,3 +695,230 @@ impl BadNote {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_config<'a>(settings: &'a ChartSettings, ctrl_obj: &'a mut CtrlObject) -> RenderConfig<'a> {
+        RenderConfig {
+            settings,
+            ctrl_obj,
+            line_height: 0.,
+            appear_before: f32::INFINITY,
+            draw_below: false,
+            incline_sin: 0.,
+            spawn_from: None,
+            appear_distance: None,
+            speed_scale: 1.,
+            note_offset: 0.,
+            reverse_span: None,
+            tint: None,
+            height_map: None,
+        }
+    }
+
+    #[test]
+    fn approach_grows_notes() {
+        assert_eq!(approach_factor(2., 1.), 0.);
+        assert_eq!(approach_factor(1., 1.), 0.);
+        assert!(approach_factor(0.75, 1.) < approach_factor(0.25, 1.));
+        assert_eq!(approach_factor(0., 1.), 1.);
+    }
+
+    #[test]
+    fn notes_fade_at_edges() {
+        assert_eq!(edge_fade_alpha(0., 0.5, 0.1), 1.);
+        assert_eq!(edge_fade_alpha(0.3, 0.5, 0.1), 1.);
+        assert!((edge_fade_alpha(-0.45, 0.5, 0.1) - 0.5).abs() < 1e-5);
+        assert_eq!(edge_fade_alpha(0.6, 0.5, 0.1), 0.);
+    }
+
+    #[test]
+    fn incline_modes() {
+        let apply = |mode| {
+            let (mut tr, mut scale) = (Vector::new(0.5, 0.2), Vector::new(1., 1.));
+            apply_incline(mode, 0.5, &mut tr, &mut scale);
+            (tr, scale)
+        };
+        // far notes are moved towards the center...
+        assert_eq!(apply(InclineMode::Shear), (Vector::new(0.25, 0.2), Vector::new(1., 1.)));
+        // ...or drawn smaller in place
+        assert_eq!(apply(InclineMode::Depth), (Vector::new(0.5, 0.2), Vector::new(0.5, 0.5)));
+    }
+
+    #[test]
+    fn approach_ticks_once_per_note() {
+        use crate::{core::ChartExtra, parse::parse_pec};
+        fn ticks(note: &mut Note, frames: &[(bool, f32)]) -> usize {
+            frames.iter().filter(|(inside, time)| note.approach_ticks(*inside, *time)).count()
+        }
+        // a note at 2s, coming inside at 1s
+        let frames = [(false, 0.), (false, 0.5), (true, 1.), (true, 1.5), (true, 1.9)];
+        let mut chart = parse_pec("0\nbp 0 120\nn1 0 4 0 1 0\n", ChartExtra::default()).unwrap();
+        assert_eq!(ticks(&mut chart.lines[0].notes[0], &frames), 1);
+        // seeking back, it ticks once again
+        chart.reset_play_state();
+        assert_eq!(ticks(&mut chart.lines[0].notes[0], &frames), 1);
+        // but not when it's already inside right after the seek
+        chart.reset_play_state();
+        assert_eq!(ticks(&mut chart.lines[0].notes[0], &frames[2..]), 0);
+    }
+
+    #[test]
+    fn notes_fly_in_from_edge() {
+        // a note at 2s flies in within the 0.3s before 1s
+        assert_eq!(spawn_progress(2., 0.5), 0.);
+        assert_eq!(spawn_progress(2., 1.5), 1.);
+        let progress = spawn_progress(2., 0.85);
+        assert!(0. < progress && progress < 1.);
+
+        let rest = Point::new(0.2, 0.3);
+        assert_eq!(spawn_point(rest, SpawnDirection::Left, 0., 0.5), Point::new(-1.1, 0.3));
+        let flying = spawn_point(rest, SpawnDirection::Left, progress, 0.5);
+        assert!(-1.1 < flying.x && flying.x < rest.x);
+        assert_eq!(flying.y, rest.y);
+        let flying = spawn_point(rest, SpawnDirection::Top, progress, 0.5);
+        assert!(rest.y < flying.y && flying.y < 0.6);
+        assert!((spawn_point(rest, SpawnDirection::Bottom, 1., 0.5) - rest).norm() < 1e-6);
+    }
+
+    #[test]
+    fn note_z_draws_above() {
+        use crate::{core::ChartExtra, parse::parse_pec};
+        // a click and a flick at the same place, flicks being drawn above clicks by default
+        let mut chart = parse_pec("0\nbp 0 120\nn1 0 1 0 1 0\nn3 0 1 0 1 0\n", ChartExtra::default()).unwrap();
+        let notes = &mut chart.lines[0].notes;
+        let (click, flick) = if matches!(notes[0].kind, NoteKind::Click) { (0, 1) } else { (1, 0) };
+        assert!(notes[click].draw_order() < notes[flick].draw_order());
+        notes[click].z = 1;
+        assert!(notes[click].draw_order() > notes[flick].draw_order());
+    }
+
+    #[test]
+    fn appear_distance_culls_far_notes() {
+        let settings = ChartSettings::default();
+        let mut ctrl_obj = CtrlObject::default();
+        let mut config = render_config(&settings, &mut ctrl_obj);
+        assert_eq!(config.place(3., false), Some(3.));
+        config.appear_distance = Some(1.5);
+        assert_eq!(config.place(1., false), Some(1.));
+        // culled whether it's on screen or not
+        assert_eq!(config.place(1.6, false), None);
+        assert_eq!(config.place(1.6, true), None);
+    }
+
+    #[test]
+    fn reversed_notes_swap_order() {
+        let settings = ChartSettings::default();
+        let mut ctrl_obj = CtrlObject::default();
+        let mut config = render_config(&settings, &mut ctrl_obj);
+        // an earlier note at 0.5 and a later one at 1.2
+        assert!(config.place(0.5, false) < config.place(1.2, false));
+        config.reverse_span = Some(2.);
+        assert_eq!(config.place(0.5, false), Some(1.5));
+        assert!(config.place(0.5, false) > config.place(1.2, false));
+        // holds keep their layout
+        assert_eq!(config.place(0.5, true), Some(0.5));
+    }
+
+    #[test]
+    fn late_spawn_hides_note() {
+        use crate::{core::ChartExtra, parse::parse_pec};
+        // a note at 2s, revealed at 1.5s
+        let mut chart = parse_pec("0\nbp 0 120\nn1 0 4 0 1 0\n", ChartExtra::default()).unwrap();
+        let note = &mut chart.lines[0].notes[0];
+        assert!(note.drawn_at(0.));
+        note.spawn_time = Some(1.5);
+        assert!(!note.drawn_at(0.));
+        assert!(!note.drawn_at(1.4));
+        assert!(note.drawn_at(1.5));
+        assert!(note.drawn_at(1.9));
+    }
+
+    #[test]
+    fn speed_scale_multiplies_distance() {
+        let settings = ChartSettings::default();
+        let mut ctrl_obj = CtrlObject::default();
+        let mut config = render_config(&settings, &mut ctrl_obj);
+        let distance = |config: &RenderConfig| config.world_height(3., 2., 1.5) - config.world_height(1., 2., 1.5);
+        assert!((distance(&config) - 1.5).abs() < 1e-5);
+        config.speed_scale = 2.;
+        assert!((distance(&config) - 3.).abs() < 1e-5);
+        config.speed_scale = 0.;
+        assert_eq!(distance(&config), 0.);
+    }
+
+    #[test]
+    fn note_offset_keeps_distance() {
+        let settings = ChartSettings::default();
+        let mut ctrl_obj = CtrlObject::default();
+        let mut config = render_config(&settings, &mut ctrl_obj);
+        config.note_offset = 0.2;
+        assert_eq!(config.place(1., false), Some(1.2));
+        // hit notes still keep away from the line
+        assert_eq!(config.place(0., false), Some(0.2));
+        assert_eq!(config.place(0., true), Some(0.2));
+        // and the offset counts towards the appear distance
+        config.appear_distance = Some(1.1);
+        assert_eq!(config.place(1., false), None);
+    }
+
+    #[test]
+    fn notes_tinted_by_side() {
+        use crate::parse::parse_pec;
+        let mut chart = parse_pec("0\nbp 0 120\nn1 0 1 0 1 0\n", Default::default()).unwrap();
+        let red = Color::new(1., 0., 0., 0.5);
+        chart.lines[0].note_tints = (None, Some(red));
+        let settings = ChartSettings::default();
+        let mut ctrl_obj = CtrlObject::default();
+        let mut config = render_config(&settings, &mut ctrl_obj);
+        let color = Color::new(0.5, 0.5, 0.5, 1.);
+        config.tint = chart.lines[0].note_tint(true);
+        assert_eq!(config.tinted(color), color);
+        config.tint = chart.lines[0].note_tint(false);
+        assert_eq!(config.tinted(color), Color::new(0.5, 0., 0., 0.5));
+    }
+
+    #[test]
+    fn holding_advances_last_real_time() {
+        use crate::{core::ChartExtra, parse::parse_pec};
+        let mut chart = parse_pec("0\nbp 0 120\nn2 0 1 3 0 1 0\nn1 0 2 0 1 0\n", ChartExtra::default()).unwrap();
+        let notes = &mut chart.lines[0].notes;
+        let hold = notes.iter().position(|it| matches!(it.kind, NoteKind::Hold { .. })).unwrap();
+        // briefly released at 0.6s
+        notes[hold].judge = JudgeStatus::Hold(true, 0.5, 0., false, 0.6);
+        for time in [0.62, 0.8, 1.2] {
+            notes[hold].hold_on(time);
+            assert_eq!(notes[hold].last_real_time, time);
+        }
+        assert!(matches!(notes[hold].judge, JudgeStatus::Hold(.., up_time) if up_time.is_infinite()));
+        // notes that aren't being held are left alone
+        notes[1 - hold].hold_on(1.);
+        assert_eq!(notes[1 - hold].last_real_time, 0.);
+    }
+
+    #[test]
+    fn notes_fade_near_line() {
+        assert_eq!(near_line_alpha(0., 0.2), 0.);
+        assert!(near_line_alpha(0.05, 0.2) < near_line_alpha(0.1, 0.2));
+        assert_eq!(near_line_alpha(0.3, 0.2), 1.);
+        // on either side
+        assert_eq!(near_line_alpha(-0.1, 0.2), near_line_alpha(0.1, 0.2));
+    }
+
+    #[test]
+    fn judged_notes_fade_then_vanish() {
+        use crate::{core::ChartExtra, parse::parse_pec};
+        let mut chart = parse_pec("0\nbp 0 120\nn1 0 2 0 1 0\n", ChartExtra::default()).unwrap();
+        let note = &mut chart.lines[0].notes[0];
+        assert!(!note.vanished_at(1.25, 0.5));
+        note.judge = JudgeStatus::Judged;
+        note.judged_time = Some(1.);
+        assert_eq!(note.vanish_progress(1.25, 0.5), 0.5);
+        assert!(!note.vanished_at(1.25, 0.5));
+        assert!(note.vanished_at(1.5, 0.5));
+        // without fading, judged notes are gone at once
+        assert!(note.vanished_at(1., 0.));
+    }
+}
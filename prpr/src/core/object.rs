@@ -1,8 +1,8 @@
-use super::{AnimFloat, AnimVector, Color, Matrix, Resource, Vector};
+use super::{AnimFloat, AnimVector, BpmList, Color, Matrix, Resource, Vector};
 use macroquad::prelude::*;
 use nalgebra::Rotation2;
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct Object {
     pub alpha: AnimFloat,
     pub scale: AnimVector,
@@ -21,12 +21,21 @@ impl Object {
     }
 
     pub fn set_time(&mut self, time: f32) {
-        self.alpha.set_time(time);
-        self.scale.0.set_time(time);
-        self.scale.1.set_time(time);
-        self.rotation.set_time(time);
-        self.translation.0.set_time(time);
-        self.translation.1.set_time(time);
+        self.set_time_precise(time as f64);
+    }
+
+    pub fn set_time_precise(&mut self, time: f64) {
+        self.alpha.set_time_precise(time);
+        self.scale.set_time_precise(time);
+        self.rotation.set_time_precise(time);
+        self.translation.set_time_precise(time);
+    }
+
+    pub fn resolve_beats(&mut self, bpm_list: &mut BpmList) {
+        self.alpha.resolve_beats(bpm_list);
+        self.scale.resolve_beats(bpm_list);
+        self.rotation.resolve_beats(bpm_list);
+        self.translation.resolve_beats(bpm_list);
     }
 
     pub fn dead(&self) -> bool {
@@ -70,7 +79,7 @@ impl Object {
     }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct CtrlObject {
     pub alpha: AnimFloat,
     pub size: AnimFloat,
@@ -1,6 +1,6 @@
-use super::{MSRenderTarget, Matrix, Point, JUDGE_LINE_PERFECT_COLOR, NOTE_WIDTH_RATIO_BASE};
+use super::{ChartSettings, HitColors, LineColors, MSRenderTarget, Matrix, Point, JUDGE_LINE_PERFECT_COLOR, NOTE_WIDTH_RATIO_BASE};
 use crate::{
-    config::Config,
+    config::{AspectMode, Config},
     ext::{create_audio_manger, nalgebra_to_glm, SafeTexture},
     fs::FileSystem,
     info::ChartInfo,
@@ -11,7 +11,13 @@ use macroquad::prelude::*;
 use miniquad::{gl::GLuint, Texture, TextureWrap};
 use sasa::{AudioClip, AudioManager, Sfx};
 use serde::Deserialize;
-use std::{cell::RefCell, collections::BTreeMap, ops::DerefMut, path::Path, sync::atomic::AtomicU32};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    ops::DerefMut,
+    path::Path,
+    sync::atomic::AtomicU32,
+};
 
 pub const MAX_SIZE: usize = 64; // needs tweaking
 pub static DPI_VALUE: AtomicU32 = AtomicU32::new(250);
@@ -53,6 +59,23 @@ pub struct ResPackInfo {
     pub hold_repeat: bool,
     #[serde(default)]
     pub hold_compact: bool,
+
+    /// Beat subdivisions that have their own note textures (`click_{n}.png`, `drag_{n}.png` and `flick_{n}.png`)
+    #[serde(default)]
+    pub subdivisions: Vec<u32>,
+
+    /// Regions of `atlas.png` holding the texture of each kind of note
+    #[serde(default)]
+    pub atlas: Option<NoteAtlasInfo>,
+}
+
+/// `(x, y, width, height)` in pixels for each kind of note.
+#[derive(Deserialize)]
+pub struct NoteAtlasInfo {
+    pub click: (u32, u32, u32, u32),
+    pub hold: (u32, u32, u32, u32),
+    pub flick: (u32, u32, u32, u32),
+    pub drag: (u32, u32, u32, u32),
 }
 
 pub struct NoteStyle {
@@ -98,13 +121,84 @@ impl NoteStyle {
     }
 }
 
+/// A single texture holding all kinds of notes, so that they can be drawn without switching textures.
+/// The rects are in UV coordinates.
+pub struct NoteAtlas {
+    pub texture: SafeTexture,
+    pub click: Rect,
+    pub hold: Rect,
+    pub flick: Rect,
+    pub drag: Rect,
+}
+
+impl NoteAtlasInfo {
+    /// UV rects of the regions of click, hold, flick and drag notes, in a `width` by `height` atlas. Fails if a region
+    /// doesn't lie within the atlas.
+    fn uv_rects(&self, width: u32, height: u32) -> Result<[Rect; 4]> {
+        let uv = |(x, y, rw, rh): (u32, u32, u32, u32)| {
+            if x + rw > width || y + rh > height {
+                bail!("Note atlas region ({x}, {y}, {rw}, {rh}) is out of the {width}x{height} atlas");
+            }
+            let (w, h) = (width as f32, height as f32);
+            Ok(Rect::new(x as f32 / w, y as f32 / h, rw as f32 / w, rh as f32 / h))
+        };
+        Ok([uv(self.click)?, uv(self.hold)?, uv(self.flick)?, uv(self.drag)?])
+    }
+}
+
+impl NoteAtlas {
+    fn new(texture: SafeTexture, info: &NoteAtlasInfo) -> Result<Self> {
+        let [click, hold, flick, drag] = info.uv_rects(texture.width() as u32, texture.height() as u32)?;
+        Ok(Self {
+            texture,
+            click,
+            hold,
+            flick,
+            drag,
+        })
+    }
+
+    /// Maps `rect`, relative to `outer`, into the atlas.
+    pub fn sub_rect(outer: Rect, rect: Rect) -> Rect {
+        Rect::new(outer.x + rect.x * outer.w, outer.y + rect.y * outer.h, rect.w * outer.w, rect.h * outer.h)
+    }
+}
+
+pub struct SubdivisionStyle {
+    pub click: SafeTexture,
+    pub drag: SafeTexture,
+    pub flick: SafeTexture,
+}
+
+/// Visuals that can be switched during play, see [`Resource::set_skin`].
+pub struct Skin {
+    pub note_style: NoteStyle,
+    pub note_style_mh: NoteStyle,
+    pub line_colors: LineColors,
+    pub hit_colors: HitColors,
+}
+
+impl Skin {
+    /// Swaps this skin with the visuals in use, given by parts.
+    fn swap(&mut self, note_style: &mut NoteStyle, note_style_mh: &mut NoteStyle, line_colors: &mut LineColors, hit_colors: &mut HitColors) {
+        std::mem::swap(note_style, &mut self.note_style);
+        std::mem::swap(note_style_mh, &mut self.note_style_mh);
+        std::mem::swap(line_colors, &mut self.line_colors);
+        std::mem::swap(hit_colors, &mut self.hit_colors);
+    }
+}
+
 pub struct ResourcePack {
     pub info: ResPackInfo,
     pub note_style: NoteStyle,
     pub note_style_mh: NoteStyle,
+    pub subdivision_styles: HashMap<u32, SubdivisionStyle>,
+    pub note_atlas: Option<NoteAtlas>,
     pub sfx_click: AudioClip,
     pub sfx_drag: AudioClip,
     pub sfx_flick: AudioClip,
+    /// Tick played as notes approach, see [`Config::approach_sound`].
+    pub sfx_tick: AudioClip,
     pub ending: AudioClip,
     pub hit_fx: SafeTexture,
 }
@@ -166,6 +260,28 @@ impl ResourcePack {
             get_body(&mut note_style_mh);
         }
         let hit_fx = image::load_from_memory(&fs.load_file("hit_fx.png").await.context("Missing hit_fx.png")?)?.into();
+        let mut subdivision_styles = HashMap::new();
+        for sub in &info.subdivisions {
+            macro_rules! load_sub_tex {
+                ($name:literal) => {{
+                    let path = format!(concat!($name, "_{}.png"), sub);
+                    image::load_from_memory(&fs.load_file(&path).await.with_context(|| format!("Missing {path}"))?)?.into()
+                }};
+            }
+            subdivision_styles.insert(
+                *sub,
+                SubdivisionStyle {
+                    click: load_sub_tex!("click"),
+                    drag: load_sub_tex!("drag"),
+                    flick: load_sub_tex!("flick"),
+                },
+            );
+        }
+        let note_atlas = if let Some(atlas) = &info.atlas {
+            Some(NoteAtlas::new(load_tex!("atlas.png"), atlas)?)
+        } else {
+            None
+        };
 
         macro_rules! load_clip {
             ($path:literal) => {
@@ -175,14 +291,24 @@ impl ResourcePack {
                     AudioClip::new(load_file($path).await?)?
                 }
             };
+            ($path:literal, $fallback:literal) => {
+                if let Some(sfx) = fs.load_file($path).await.ok().map(|it| AudioClip::new(it)).transpose()? {
+                    sfx
+                } else {
+                    AudioClip::new(load_file($fallback).await?)?
+                }
+            };
         }
         Ok(Self {
             info,
             note_style,
             note_style_mh,
+            subdivision_styles,
+            note_atlas,
             sfx_click: load_clip!("click.ogg"),
             sfx_drag: load_clip!("drag.ogg"),
             sfx_flick: load_clip!("flick.ogg"),
+            sfx_tick: load_clip!("tick.ogg", "cali_hit.ogg"),
             ending: load_clip!("ending.mp3"),
             hit_fx,
         })
@@ -196,6 +322,17 @@ pub struct ParticleEmitter {
     hide_particles: bool,
 }
 
+/// Lifetime (in chart time) of hit effects: the resource pack's `pack` one, unless the config overrides it.
+fn hit_fx_lifetime(config: &Config, pack: f32) -> f32 {
+    config.hit_fx_duration.unwrap_or(pack)
+}
+
+/// Chart time hit effects advance by in a frame of `dt` seconds. Effects last in chart time, so that they keep up with
+/// playback speed.
+pub fn hit_fx_step(config: &Config, dt: f32) -> f32 {
+    dt * config.speed
+}
+
 impl ParticleEmitter {
     pub fn new(res_pack: &ResourcePack, scale: f32, hide_particles: bool) -> Result<Self> {
         let colors_curve = {
@@ -259,13 +396,20 @@ impl ParticleEmitter {
         self.emitter.config.size = self.scale * scale / 5.;
         self.emitter_square.config.size = self.scale * scale / 44.;
     }
+
+    /// Sets how long hit effects emitted from now on last.
+    pub fn set_duration(&mut self, duration: f32) {
+        self.emitter.config.lifetime = duration;
+        self.emitter_square.config.lifetime = duration;
+    }
 }
 
 #[derive(Default)]
-pub struct NoteBuffer(BTreeMap<(i8, GLuint), Vec<(Vec<Vertex>, Vec<u16>)>>);
+pub struct NoteBuffer(BTreeMap<((i8, i8), GLuint), Vec<(Vec<Vertex>, Vec<u16>)>>);
 
 impl NoteBuffer {
-    pub fn push(&mut self, key: (i8, GLuint), vertices: [Vertex; 4]) {
+    /// `key` is the draw order (a note's z and then its kind's order) and the texture.
+    pub fn push(&mut self, key: ((i8, i8), GLuint), vertices: [Vertex; 4]) {
         let meshes = self.0.entry(key).or_default();
         if meshes.last().map_or(true, |it| it.0.len() + 4 > MAX_SIZE * 4) {
             meshes.push(Default::default());
@@ -299,9 +443,15 @@ pub struct Resource {
     pub note_width: f32,
 
     pub time: f32,
+    /// `time` in full precision, see [`Self::precise_time`]
+    pub(crate) time_precise: f64,
 
     pub alpha: f32,
     pub judge_line_color: Color,
+    /// What `judge_line_color` is set to during play.
+    pub line_colors: LineColors,
+    /// Colors of the hit effects of perfect and good hits. The alpha doubles as the intensity of the effect.
+    pub hit_colors: HitColors,
 
     pub camera: Camera2D,
     pub camera_matrix: Mat4,
@@ -325,6 +475,7 @@ pub struct Resource {
     pub sfx_click: Sfx,
     pub sfx_drag: Sfx,
     pub sfx_flick: Sfx,
+    pub sfx_tick: Sfx,
 
     pub chart_target: Option<MSRenderTarget>,
     pub no_effect: bool,
@@ -405,12 +556,14 @@ impl Resource {
         let sfx_click = audio.create_sfx(res_pack.sfx_click.clone(), buffer_size)?;
         let sfx_drag = audio.create_sfx(res_pack.sfx_drag.clone(), buffer_size)?;
         let sfx_flick = audio.create_sfx(res_pack.sfx_flick.clone(), buffer_size)?;
+        let sfx_tick = audio.create_sfx(res_pack.sfx_tick.clone(), buffer_size)?;
 
         let aspect_ratio = config.aspect_ratio.unwrap_or(info.aspect_ratio);
         let note_width = config.note_scale * NOTE_WIDTH_RATIO_BASE;
         let note_scale = config.note_scale;
 
-        let emitter = ParticleEmitter::new(&res_pack, note_scale, res_pack.info.hide_particles)?;
+        let mut emitter = ParticleEmitter::new(&res_pack, note_scale, res_pack.info.hide_particles)?;
+        emitter.set_duration(hit_fx_lifetime(&config, res_pack.info.hit_fx_duration));
 
         let no_effect = config.disable_effect || has_no_effect;
 
@@ -424,9 +577,12 @@ impl Resource {
             note_width,
 
             time: 0.,
+            time_precise: 0.,
 
             alpha: 1.,
             judge_line_color: JUDGE_LINE_PERFECT_COLOR,
+            line_colors: LineColors::default(),
+            hit_colors: HitColors::default(),
 
             camera,
             camera_matrix: camera.matrix(),
@@ -450,6 +606,7 @@ impl Resource {
             sfx_click,
             sfx_drag,
             sfx_flick,
+            sfx_tick,
 
             chart_target: None,
             no_effect,
@@ -460,6 +617,14 @@ impl Resource {
         })
     }
 
+    /// Scales notes, along with their hit effects, by the chart's [`ChartSettings::note_scale`] on top of the player's
+    /// [`Config::note_scale`], see [`note_scale`].
+    pub fn apply_chart_settings(&mut self, settings: &ChartSettings) {
+        let scale = note_scale(&self.config, settings);
+        self.note_width = scale * NOTE_WIDTH_RATIO_BASE;
+        self.emitter.set_scale(scale);
+    }
+
     pub fn emit_at_origin(&mut self, rotation: f32, color: Color) {
         if !self.config.particle {
             return;
@@ -478,37 +643,56 @@ impl Resource {
         if !self.no_effect || self.config.sample_count != 1 {
             self.chart_target = Some(MSRenderTarget::new(dim, self.config.sample_count));
         }
-        fn viewport(aspect_ratio: f32, (w, h): (u32, u32)) -> (i32, i32, i32, i32) {
-            let w = w as f32;
-            let h = h as f32;
-            let (rw, rh) = {
-                let ew = h * aspect_ratio;
-                if ew > w {
-                    let eh = w / aspect_ratio;
-                    (w, eh)
-                } else {
-                    (ew, h)
-                }
-            };
-            (((w - rw) / 2.).round() as i32, ((h - rh) / 2.).round() as i32, rw as i32, rh as i32)
-        }
         let aspect_ratio = self.config.aspect_ratio.unwrap_or(self.info.aspect_ratio);
-        if self.config.fix_aspect_ratio {
-            self.aspect_ratio = aspect_ratio;
-            self.camera.viewport = Some(viewport(aspect_ratio, dim));
-        } else {
-            self.aspect_ratio = aspect_ratio.min(dim.0 as f32 / dim.1 as f32);
-            self.camera.zoom = vec2(1., -self.aspect_ratio);
-            self.camera_matrix = self.camera.matrix();
-            self.camera.viewport = Some(viewport(self.aspect_ratio, dim));
-        };
+        match self.config.aspect_mode {
+            AspectMode::Auto => {
+                self.aspect_ratio = aspect_ratio.min(dim.0 as f32 / dim.1 as f32);
+                self.camera.zoom = vec2(1., -self.aspect_ratio);
+                self.camera_matrix = self.camera.matrix();
+                self.camera.viewport = Some(viewport(self.aspect_ratio, dim));
+            }
+            AspectMode::Fit => {
+                self.aspect_ratio = aspect_ratio;
+                self.camera.viewport = Some(viewport(aspect_ratio, dim));
+            }
+            AspectMode::Stretch => {
+                // the playfield keeps the chart's aspect ratio, it's the viewport that stretches it to the whole screen
+                self.aspect_ratio = aspect_ratio;
+                self.camera.viewport = Some((0, 0, dim.0 as i32, dim.1 as i32));
+            }
+        }
         true
     }
 
+    /// Switches to `skin`, returning the current one so that it can be switched back to. Note textures of a note atlas or of
+    /// subdivision styles in the resource pack still take precedence over the ones of the skin.
+    pub fn set_skin(&mut self, mut skin: Skin) -> Skin {
+        skin.swap(&mut self.res_pack.note_style, &mut self.res_pack.note_style_mh, &mut self.line_colors, &mut self.hit_colors);
+        skin
+    }
+
     pub fn world_to_screen(&self, pt: Point) -> Point {
         self.model_stack.last().unwrap().transform_point(&pt)
     }
 
+    /// Advances `time` towards the given one, by at most `config.max_frame_delta` at once so that a lag spike doesn't skip notes.
+    /// What's left is caught up during the following frames.
+    pub fn advance_time(&mut self, time: f64) {
+        let time = clamp_frame_time(self.time as f64, time, self.config.max_frame_delta);
+        self.time = time as f32;
+        self.time_precise = if self.config.precise_time { time } else { self.time as f64 };
+    }
+
+    /// The current time in full precision if [`crate::config::Config::precise_time`] is set, otherwise just `time`.
+    /// Falls back to `time` as well if it has been changed directly since the last [`Self::advance_time`].
+    pub fn precise_time(&self) -> f64 {
+        if self.time_precise as f32 == self.time {
+            self.time_precise
+        } else {
+            self.time as f64
+        }
+    }
+
     pub fn screen_to_world(&self, pt: Point) -> Point {
         self.model_stack.last().unwrap().try_inverse().unwrap().transform_point(&pt)
     }
@@ -533,3 +717,178 @@ impl Resource {
         unsafe { get_internal_gl() }.quad_gl.pop_model_matrix();
     }
 }
+
+/// Scale notes are rendered at: the player's [`Config::note_scale`] times the chart's [`ChartSettings::note_scale`]. This is
+/// the only place the two are combined. Judging is affected by neither.
+fn note_scale(config: &Config, settings: &ChartSettings) -> f32 {
+    config.note_scale * settings.note_scale
+}
+
+/// The largest viewport of the given aspect ratio centered in a `w`×`h` screen, letterboxed or pillarboxed.
+fn viewport(aspect_ratio: f32, (w, h): (u32, u32)) -> (i32, i32, i32, i32) {
+    let w = w as f32;
+    let h = h as f32;
+    let (rw, rh) = {
+        let ew = h * aspect_ratio;
+        if ew > w {
+            let eh = w / aspect_ratio;
+            (w, eh)
+        } else {
+            (ew, h)
+        }
+    };
+    (((w - rw) / 2.).round() as i32, ((h - rh) / 2.).round() as i32, rw as i32, rh as i32)
+}
+
+/// How far time advances from `current` towards `target` in a frame, see [`Resource::advance_time`].
+fn clamp_frame_time(current: f64, target: f64, max_delta: Option<f32>) -> f64 {
+    match max_delta {
+        Some(max) if target > current => target.min(current + max as f64),
+        _ => target,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stall_is_caught_up_gradually() {
+        // a 1 second stall right after 10s
+        let mut time = 10.;
+        let mut frames = 0;
+        while time < 11. {
+            let next = clamp_frame_time(time, 11., Some(0.25));
+            assert!(next - time <= 0.25);
+            time = next;
+            frames += 1;
+        }
+        assert_eq!(frames, 4);
+        // seeking backwards and unclamped time aren't affected
+        assert_eq!(clamp_frame_time(10., 5., Some(0.1)), 5.);
+        assert_eq!(clamp_frame_time(10., 11., None), 11.);
+    }
+
+    #[test]
+    fn fit_letterboxes() {
+        // a 2:1 chart on a 4:3 screen keeps its aspect ratio
+        assert_eq!(viewport(2., (800, 600)), (0, 100, 800, 400));
+        // and a 3:2 one on a 16:9 screen is pillarboxed
+        assert_eq!(viewport(1.5, (1600, 900)), (125, 0, 1350, 900));
+    }
+
+    #[test]
+    fn hit_colors_by_judgement() {
+        use crate::core::JUDGE_LINE_GOOD_COLOR;
+        let colors = HitColors::default();
+        assert_eq!(colors.of(true), JUDGE_LINE_PERFECT_COLOR);
+        assert_eq!(colors.of(false), JUDGE_LINE_GOOD_COLOR);
+        let colors = HitColors { good: BLUE, ..colors };
+        assert_eq!(colors.of(false), BLUE);
+        assert_eq!(colors.of(true), JUDGE_LINE_PERFECT_COLOR);
+    }
+
+    #[test]
+    fn line_colors_by_play() {
+        let colors = LineColors {
+            normal: GRAY,
+            ..Default::default()
+        };
+        assert_eq!(colors.of(true, true), JUDGE_LINE_PERFECT_COLOR);
+        assert_eq!(colors.of(false, true), colors.full_combo);
+        assert_eq!(colors.of(false, false), GRAY);
+    }
+
+    #[test]
+    fn chart_note_scale_multiplies_config() {
+        let config = Config {
+            note_scale: 1.2,
+            ..Default::default()
+        };
+        let settings = ChartSettings {
+            note_scale: 1.5,
+            ..Default::default()
+        };
+        assert!((note_scale(&config, &settings) - 1.8).abs() < 1e-6);
+        assert_eq!(note_scale(&config, &ChartSettings::default()), 1.2);
+    }
+
+    #[test]
+    fn longer_hit_fx_last_more_frames() {
+        // frames an effect emitted on one is still visible on, at 64 fps so that the sums are exact
+        let frames = |config: &Config| {
+            let lifetime = hit_fx_lifetime(config, 0.5);
+            let mut lived = 0.;
+            let mut frames = 0;
+            while lived < lifetime {
+                lived += hit_fx_step(config, 1. / 64.);
+                frames += 1;
+            }
+            frames
+        };
+        let mut config = Config::default();
+        assert_eq!(frames(&config), 32);
+        config.hit_fx_duration = Some(1.);
+        assert_eq!(frames(&config), 64);
+        // twice as fast, the same effect is over in half the frames
+        config.speed = 2.;
+        assert_eq!(frames(&config), 32);
+    }
+
+    #[test]
+    fn skins_switch_line_colors_and_textures() {
+        let style = || {
+            let texture = || SafeTexture::from(Texture2D::empty());
+            NoteStyle {
+                click: texture(),
+                hold: texture(),
+                flick: texture(),
+                drag: texture(),
+                hold_body: None,
+                hold_atlas: (0, 0),
+            }
+        };
+        let (mut note_style, mut note_style_mh) = (style(), style());
+        let (mut line_colors, mut hit_colors) = (LineColors::default(), HitColors::default());
+        let mut skin = Skin {
+            note_style: style(),
+            note_style_mh: style(),
+            line_colors: LineColors {
+                normal: GRAY,
+                ..Default::default()
+            },
+            hit_colors: HitColors::default(),
+        };
+        let (old_click, new_click) = (note_style.click.clone(), skin.note_style.click.clone());
+        skin.swap(&mut note_style, &mut note_style_mh, &mut line_colors, &mut hit_colors);
+        assert!(note_style.click == new_click);
+        assert_eq!(line_colors.of(false, false), GRAY);
+        // switching back
+        skin.swap(&mut note_style, &mut note_style_mh, &mut line_colors, &mut hit_colors);
+        assert!(note_style.click == old_click);
+        assert_eq!(line_colors.of(false, false), LineColors::default().normal);
+        // the textures were never uploaded, and there's no context to delete them in
+        std::mem::forget((note_style, note_style_mh, skin, old_click, new_click));
+    }
+
+    #[test]
+    fn note_atlas_rects() {
+        // a 2x2 grid of 128px cells
+        let mut info = NoteAtlasInfo {
+            click: (0, 0, 128, 128),
+            hold: (128, 0, 128, 128),
+            flick: (0, 128, 128, 128),
+            drag: (128, 128, 128, 128),
+        };
+        let [click, hold, flick, drag] = info.uv_rects(256, 256).unwrap();
+        assert_eq!(click, Rect::new(0., 0., 0.5, 0.5));
+        assert_eq!(hold, Rect::new(0.5, 0., 0.5, 0.5));
+        assert_eq!(flick, Rect::new(0., 0.5, 0.5, 0.5));
+        assert_eq!(drag, Rect::new(0.5, 0.5, 0.5, 0.5));
+        // the head of a hold, in the top quarter of its own texture
+        assert_eq!(NoteAtlas::sub_rect(hold, Rect::new(0., 0., 1., 0.25)), Rect::new(0.5, 0., 0.5, 0.125));
+        // a third column that doesn't exist
+        info.drag = (256, 128, 128, 128);
+        assert!(info.uv_rects(256, 256).is_err());
+    }
+}
@@ -96,6 +96,100 @@ fn bounce(x: f32) -> f32 {
     })
 }
 
+// derivatives of the above, `dN!` matching `fN!`
+
+macro_rules! d1 {
+    ($fn:ident) => {
+        $fn
+    };
+}
+
+macro_rules! d2 {
+    ($fn:ident) => {
+        |x| $fn(1. - x)
+    };
+}
+
+macro_rules! d3 {
+    ($fn:ident) => {
+        |x| {
+            let x = x * 2.;
+            if x < 1. {
+                $fn(x)
+            } else {
+                $fn(2. - x)
+            }
+        }
+    };
+}
+
+#[inline]
+fn sine_d(x: f32) -> f32 {
+    PI / 2. * ((x * PI) / 2.).sin()
+}
+
+#[inline]
+fn quad_d(x: f32) -> f32 {
+    2. * x
+}
+
+#[inline]
+fn cubic_d(x: f32) -> f32 {
+    3. * x * x
+}
+
+#[inline]
+fn quart_d(x: f32) -> f32 {
+    4. * x * x * x
+}
+
+#[inline]
+fn quint_d(x: f32) -> f32 {
+    5. * x * x * x * x
+}
+
+#[inline]
+fn expo_d(x: f32) -> f32 {
+    10. * std::f32::consts::LN_2 * (2.0_f32).powf(10. * (x - 1.))
+}
+
+#[inline]
+fn circ_d(x: f32) -> f32 {
+    x / (1. - x * x).sqrt()
+}
+
+#[inline]
+fn back_d(x: f32) -> f32 {
+    const C1: f32 = 1.70158;
+    const C3: f32 = C1 + 1.;
+    (C3 * 3. * x - C1 * 2.) * x
+}
+
+#[inline]
+fn elastic_d(x: f32) -> f32 {
+    const C4: f32 = (2. * PI) / 3.;
+    let u = (x * 10. - 10.75) * C4;
+    -((2.0_f32).powf(10. * x - 10.) * (10. * std::f32::consts::LN_2 * u.sin() + 10. * C4 * u.cos()))
+}
+
+#[inline]
+fn bounce_d(x: f32) -> f32 {
+    const N1: f32 = 7.5625;
+    const D1: f32 = 2.75;
+
+    let x = 1. - x;
+    2. * N1
+        * if x < 1. / D1 {
+            x
+        } else if x < 2. / D1 {
+            x - 1.5 / D1
+        } else if x < 2.5 / D1 {
+            x - 2.25 / D1
+        } else {
+            x - 2.625 / D1
+        }
+}
+
 #[rustfmt::skip]
 pub static TWEEN_FUNCTIONS: [fn(f32) -> f32; 33] = [
 	|_| 0., |_| 1., |x| x,
@@ -111,6 +205,22 @@ pub static TWEEN_FUNCTIONS: [fn(f32) -> f32; 33] = [
 	f1!(bounce), f2!(bounce), f3!(bounce),
 ];
 
+/// Derivatives of [`TWEEN_FUNCTIONS`].
+#[rustfmt::skip]
+pub static TWEEN_DERIVATIVES: [fn(f32) -> f32; 33] = [
+	|_| 0., |_| 0., |_| 1.,
+	d1!(sine_d), d2!(sine_d), d3!(sine_d),
+	d1!(quad_d), d2!(quad_d), d3!(quad_d),
+	d1!(cubic_d), d2!(cubic_d), d3!(cubic_d),
+	d1!(quart_d), d2!(quart_d), d3!(quart_d),
+	d1!(quint_d), d2!(quint_d), d3!(quint_d),
+	d1!(expo_d), d2!(expo_d), d3!(expo_d),
+	d1!(circ_d), d2!(circ_d), d3!(circ_d),
+	d1!(back_d), d2!(back_d), d3!(back_d),
+	d1!(elastic_d), d2!(elastic_d), d3!(elastic_d),
+	d1!(bounce_d), d2!(bounce_d), d3!(bounce_d),
+];
+
 thread_local! {
     static TWEEN_FUNCTION_RCS: Lazy<Vec<Rc<dyn TweenFunction>>> = Lazy::new(|| {
         (0..33)
@@ -121,6 +231,13 @@ thread_local! {
 
 pub trait TweenFunction {
     fn y(&self, x: f32) -> f32;
+
+    /// The slope of [`TweenFunction::y`] at `x`, estimated with a small difference unless known exactly.
+    fn dy(&self, x: f32) -> f32 {
+        const DELTA: f32 = 1e-3;
+        let (lo, hi) = ((x - DELTA).max(0.), (x + DELTA).min(1.));
+        (self.y(hi) - self.y(lo)) / (hi - lo)
+    }
 }
 
 pub struct StaticTween(pub TweenId);
@@ -128,6 +245,10 @@ impl TweenFunction for StaticTween {
     fn y(&self, x: f32) -> f32 {
         TWEEN_FUNCTIONS[self.0 as usize](x)
     }
+
+    fn dy(&self, x: f32) -> f32 {
+        TWEEN_DERIVATIVES[self.0 as usize](x)
+    }
 }
 
 impl StaticTween {
@@ -142,6 +263,10 @@ impl TweenFunction for ClampedTween {
     fn y(&self, x: f32) -> f32 {
         (TWEEN_FUNCTIONS[self.0 as usize](f32::tween(&self.1.start, &self.1.end, x)) - self.2.start) / (self.2.end - self.2.start)
     }
+
+    fn dy(&self, x: f32) -> f32 {
+        TWEEN_DERIVATIVES[self.0 as usize](f32::tween(&self.1.start, &self.1.end, x)) * (self.1.end - self.1.start) / (self.2.end - self.2.start)
+    }
 }
 
 impl ClampedTween {
@@ -172,6 +297,11 @@ impl TweenFunction for BezierTween {
     fn y(&self, x: f32) -> f32 {
         Self::sample(self.p1.1, self.p2.1, self.t_for_x(x))
     }
+
+    fn dy(&self, x: f32) -> f32 {
+        let t = self.t_for_x(x);
+        Self::slope(self.p1.1, self.p2.1, t) / Self::slope(self.p1.0, self.p2.0, t).max(SLOPE_EPS)
+    }
 }
 
 impl BezierTween {
@@ -303,6 +433,17 @@ impl Tweenable for Color {
     }
 }
 
+impl Tweenable for bool {
+    fn tween(x: &Self, y: &Self, t: f32) -> Self {
+        // discrete, so a value holds until the next keyframe
+        if t >= 1. {
+            *y
+        } else {
+            *x
+        }
+    }
+}
+
 impl Tweenable for String {
     fn tween(x: &Self, y: &Self, t: f32) -> Self {
         if x.is_empty() && y.is_empty() {
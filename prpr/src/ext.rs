@@ -377,6 +377,26 @@ pub fn make_pipeline(write_color: bool, pass_op: StencilOp, test_func: CompareFu
     .unwrap()
 }
 
+pub fn make_blend_pipeline(blend: BlendState) -> GlPipeline {
+    let InternalGlContext {
+        quad_gl: gl,
+        quad_context: context,
+    } = unsafe { get_internal_gl() };
+    gl.make_pipeline(
+        context,
+        shader::VERTEX,
+        shader::FRAGMENT,
+        PipelineParams {
+            color_blend: Some(blend),
+            primitive_type: PrimitiveType::Triangles,
+            ..Default::default()
+        },
+        Vec::new(),
+        Vec::new(),
+    )
+    .unwrap()
+}
+
 mod shader {
     pub const VERTEX: &str = r#"#version 100
 attribute vec3 position;
@@ -7,6 +7,29 @@ pub enum ChartFormat {
     Rpe = 0,
     Pec,
     Pgr,
+    Bms,
+}
+
+impl ChartFormat {
+    /// Guesses the format of a chart from its content.
+    pub fn infer(text: &str) -> Self {
+        if text.starts_with('{') {
+            // phigros charts may carry a `META` too, but RPE ones never have `formatVersion`
+            if text.contains("\"META\"") && !text.contains("\"formatVersion\"") {
+                Self::Rpe
+            } else {
+                Self::Pgr
+            }
+        } else if text.lines().any(|it| {
+            let it = it.trim_start();
+            it.starts_with("#BPM") || it.starts_with("#PLAYER") || it.starts_with("#WAV")
+        }) {
+            // PEC lines may start with `#` too, but never with these headers
+            Self::Bms
+        } else {
+            Self::Pec
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -1,8 +1,10 @@
 use crate::{
     config::Config,
-    core::{BadNote, Chart, NoteKind, Point, Resource, Vector, JUDGE_LINE_GOOD_COLOR, JUDGE_LINE_PERFECT_COLOR},
+    core::{BadNote, Chart, HitSoundParams, HoldKind, Note, NoteKind, Point, Resource, Vector},
     ext::{get_viewport, NotNanExt},
+    time::TimeManager,
 };
+use anyhow::{bail, Result};
 use macroquad::prelude::{
     utils::{register_input_subscriber, repeat_all_miniquad_input},
     *,
@@ -10,10 +12,12 @@ use macroquad::prelude::{
 use miniquad::{EventHandler, MouseButton};
 use once_cell::sync::Lazy;
 use sasa::{PlaySfxParams, Sfx};
+use serde::{Deserialize, Serialize};
 use std::{
     cell::RefCell,
     collections::{HashMap, VecDeque},
     num::FpCategory,
+    ops::Range,
 };
 
 pub const FLICK_SPEED_THRESHOLD: f32 = 1.8;
@@ -21,7 +25,10 @@ pub const LIMIT_PERFECT: f32 = 0.08;
 pub const LIMIT_GOOD: f32 = 0.16;
 pub const LIMIT_BAD: f32 = 0.22;
 pub const UP_TOLERANCE: f32 = 0.01;
+#[deprecated(note = "distance no longer weighs against timing when picking the note a touch hits, see `SIMULTANEOUS_TOLERANCE`")]
 pub const DIST_FACTOR: f32 = 0.2;
+/// Notes whose judge times differ by no more than this are considered simultaneous when picking which one a touch hits.
+pub const SIMULTANEOUS_TOLERANCE: f32 = 0.01;
 
 pub fn play_sfx(sfx: &mut Sfx, config: &Config) {
     if config.volume_sfx <= 1e-2 {
@@ -32,6 +39,154 @@ pub fn play_sfx(sfx: &mut Sfx, config: &Config) {
     });
 }
 
+/// Whether `note` (of a line delayed by `time_offset`) is judged automatically rather than by the player, either because of
+/// autoplay or because it lies in one of the chart's [`crate::core::ChartExtra::auto_sections`].
+fn is_auto(autoplay: bool, sections: &[Range<f32>], note: &Note, time_offset: f32) -> bool {
+    (autoplay && !note.no_autoplay) || sections.iter().any(|it| it.contains(&(note.time + time_offset)))
+}
+
+/// The earliest note of enabled lines that a key press would hit, as `(line_id, note_id)`. Only clicks and holds that
+/// aren't judged yet count.
+fn next_key_note(chart: &Chart, notes: &[(Vec<u32>, usize)], autoplay: bool, sections: &[Range<f32>]) -> Option<(usize, u32)> {
+    chart
+        .lines
+        .iter()
+        .zip(notes.iter())
+        .enumerate()
+        .filter(|(_, (line, _))| !line.notes_disabled)
+        .filter_map(|(line_id, (line, (idx, st)))| {
+            idx[*st..]
+                .iter()
+                .cloned()
+                .find(|id| {
+                    let note = &line.notes[*id as usize];
+                    !is_auto(autoplay, sections, note, line.time_offset)
+                        && matches!(note.judge, JudgeStatus::NotJudged)
+                        && matches!(note.kind, NoteKind::Click | NoteKind::Hold { .. })
+                        && !matches!(
+                            note.kind,
+                            NoteKind::Hold {
+                                kind: HoldKind::Reverse | HoldKind::Drag,
+                                ..
+                            }
+                        )
+                })
+                .map(|id| (line_id, id))
+        })
+        .min_by_key(|(line_id, id)| {
+            let line = &chart.lines[*line_id];
+            (line.notes[*id as usize].time + line.time_offset).not_nan()
+        })
+}
+
+/// How late (in chart time) a hit at `time` is for `note` of a line delayed by `time_offset`. Negative if it's early.
+fn note_dt(note: &Note, time_offset: f32, time: f32) -> f32 {
+    time - time_offset - note.time
+}
+
+/// Credit (out of a full hit) for a hold spanning `span` that's released at `up_time`, proportional to the part held, see
+/// [`Config::hold_partial_credit`].
+fn partial_credit(span: Range<f32>, up_time: f32, perfect: bool) -> f32 {
+    let held = ((up_time - span.start) / (span.end - span.start)).clamp(0., 1.);
+    held * if perfect { 1. } else { 0.65 }
+}
+
+/// A touch at `p` relative to a line, or `None` if it can't hit the line's notes, being further than `tolerance` from it
+/// (see [`Config::line_tolerance`]) or the line being degenerate.
+fn on_line(p: Point, tolerance: Option<f32>) -> Option<Point> {
+    fn ok(f: f32) -> bool {
+        matches!(f.classify(), FpCategory::Zero | FpCategory::Subnormal | FpCategory::Normal)
+    }
+    if ok(p.x) && ok(p.y) && tolerance.map_or(true, |it| p.y.abs() <= it) {
+        Some(p)
+    } else {
+        None
+    }
+}
+
+/// Whether a hit `late` (negative if early) is inside the window of `limit`, which is extended by `late_grace` for late
+/// hits, see [`Config::late_grace`].
+fn in_window(late: f32, limit: f32, late_grace: f32) -> bool {
+    -limit <= late && late <= limit + late_grace
+}
+
+/// Whether a note `(dt, dist)` away from a touch is hit rather than another one judgeable by it: the earliest one wins,
+/// and the nearest one if they're simultaneous.
+fn takes_priority((dt, dist): (f32, f32), (other_dt, other_dist): (f32, f32)) -> bool {
+    dt < other_dt - SIMULTANEOUS_TOLERANCE || (dt <= other_dt + SIMULTANEOUS_TOLERANCE && dist < other_dist)
+}
+
+/// Signed angle (in degrees, within `-180..180`) from `direction` (y-up) to a swipe of velocity `swipe` (y-down, like
+/// touches are), see [`crate::core::Note::flick_dir`].
+fn swipe_angle(swipe: Vector, direction: f32) -> f32 {
+    ((-swipe.y).atan2(swipe.x).to_degrees() - direction + 180.).rem_euclid(360.) - 180.
+}
+
+/// What happens to a started hold in the current frame, see [`hold_step`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HoldStep {
+    /// Nothing can go wrong any more, the hold is judged once it ends
+    Done,
+    Miss,
+    /// Played as it should be
+    Ok,
+    /// Released (or touched, for reverse holds); missed if it lasts longer than [`UP_TOLERANCE`]
+    Lapse,
+    /// Released, which only costs the perfect
+    Slip,
+    /// Nothing to check in this frame
+    Wait,
+}
+
+/// Judges a frame of a started hold of `kind`, `left` (in real time) from its end. `pressed` is whether the hold is
+/// pressed in this very frame, `touched` whether it's touched at all, and `grace` the time before the end from which
+/// releasing is fine.
+fn hold_step(kind: HoldKind, left: f32, grace: f32, pressed: bool, touched: bool) -> HoldStep {
+    match kind {
+        HoldKind::Normal if left <= grace => HoldStep::Done,
+        HoldKind::Drag if left <= grace => HoldStep::Done,
+        // the end has to be pressed (again)
+        HoldKind::Pinch | HoldKind::Reverse if left.abs() <= LIMIT_GOOD && pressed => HoldStep::Done,
+        HoldKind::Pinch | HoldKind::Reverse if -left > LIMIT_GOOD => HoldStep::Miss,
+        // releasing is allowed here so that the end can be pressed
+        HoldKind::Pinch | HoldKind::Reverse if left <= LIMIT_GOOD => HoldStep::Wait,
+        HoldKind::Reverse if touched => HoldStep::Lapse,
+        HoldKind::Reverse => HoldStep::Ok,
+        HoldKind::Drag if !touched => HoldStep::Slip,
+        _ if touched => HoldStep::Ok,
+        _ => HoldStep::Lapse,
+    }
+}
+
+/// Whether a hold of `kind`, `dt` (in real time) after its start, starts in this frame given whether it's just been
+/// `released` and whether it's `touched`. Reverse holds start by releasing and drag holds by merely being touched, the
+/// other ones start with a click instead.
+fn hold_started(kind: HoldKind, dt: f32, released: bool, touched: bool) -> bool {
+    dt.abs() <= LIMIT_GOOD
+        && match kind {
+            HoldKind::Reverse => released,
+            HoldKind::Drag => touched,
+            _ => false,
+        }
+}
+
+pub type HitSoundHook = Option<Box<dyn FnMut(&NoteKind, HitSoundParams)>>;
+
+fn play_hit_sound(hook: &mut HitSoundHook, sfx: &mut Sfx, config: &Config, kind: &NoteKind, params: HitSoundParams) {
+    if let Some(params) = hit_sound(hook, config, kind, params) {
+        let _ = sfx.play(params);
+    }
+}
+
+/// Passes the hit sound of a note to `hook`, and tells how to play it, `None` if it's too quiet to be heard.
+fn hit_sound(hook: &mut HitSoundHook, config: &Config, kind: &NoteKind, params: HitSoundParams) -> Option<PlaySfxParams> {
+    if let Some(hook) = hook {
+        hook(kind, params);
+    }
+    let amplifier = config.volume_sfx * params.volume;
+    (amplifier > 1e-2).then_some(PlaySfxParams { amplifier })
+}
+
 pub struct VelocityTracker {
     movements: VecDeque<(f32, Point)>,
     last_dir: Vector,
@@ -128,7 +283,7 @@ impl VelocityTracker {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum JudgeStatus {
     NotJudged,
     PreJudge,
@@ -136,8 +291,62 @@ pub enum JudgeStatus {
     Hold(bool, f32, f32, bool, f32), // perfect, at, diff, pre-judge, up-time
 }
 
-#[repr(u8)]
+/// Signed timing error of a non-perfect hit. Negative `diff` means the note was hit early.
+#[derive(Debug, Copy, Clone)]
+pub struct TimingError {
+    pub diff: f32,
+    pub time: f32,
+    pub line_id: usize,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum ReplayEvent {
+    HoldStart {
+        perfect: bool,
+        diff: f32,
+    },
+    Judged(Judgement, Option<f32>),
+    /// Credit for the held part of an early released hold, see [`Config::hold_partial_credit`]
+    PartialCredit(f32),
+}
+
 #[derive(Debug, Copy, Clone)]
+pub struct ReplayRecord {
+    pub time: f32,
+    pub line_id: usize,
+    pub note_id: u32,
+    pub event: ReplayEvent,
+}
+
+/// Judge events of a play in chronological order. Applying them is deterministic, so any
+/// position of a replay can be reached by replaying the events before it.
+#[derive(Clone)]
+pub struct Replay {
+    /// [`Chart::content_hash`] of the chart the replay was recorded on. A replay can't be applied to another chart.
+    pub chart_hash: u64,
+    pub records: Vec<ReplayRecord>,
+}
+
+impl Replay {
+    /// An empty replay of `chart`.
+    pub fn new(chart: &Chart) -> Self {
+        Self {
+            chart_hash: chart.content_hash(),
+            records: Vec::new(),
+        }
+    }
+
+    /// Fails if the replay wasn't recorded on `chart`.
+    pub fn check(&self, chart: &Chart) -> Result<()> {
+        if self.chart_hash != chart.content_hash() {
+            bail!("replay was recorded on another chart");
+        }
+        Ok(())
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
 pub enum Judgement {
     Perfect,
     Good,
@@ -145,10 +354,32 @@ pub enum Judgement {
     Miss,
 }
 
+/// How a note was judged, kept so that the score it made can be carried over to a reloaded chart.
+#[derive(Clone, Deserialize, Serialize)]
+struct NoteOutcome {
+    time: f32,
+    judgement: Judgement,
+    diff: Option<f32>,
+    partial: f32,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct LinkState {
+    size: u32,
+    remaining: u32,
+    // whether every note judged so far has been hit
+    intact: bool,
+}
+
 #[cfg(not(feature = "closed"))]
-#[derive(Default)]
+#[derive(Clone, Default, Deserialize, Serialize)]
 pub(crate) struct JudgeInner {
     diffs: Vec<f32>,
+    // in notes, from holds released early
+    partial: f64,
+    // in notes, credit beyond the usual one of notes weighing more (or less) than 1
+    weight_bonus: f64,
+    total_weight: f64,
 
     combo: u32,
     max_combo: u32,
@@ -158,9 +389,12 @@ pub(crate) struct JudgeInner {
 
 #[cfg(not(feature = "closed"))]
 impl JudgeInner {
-    pub fn new(num_of_notes: u32) -> Self {
+    pub fn new(num_of_notes: u32, total_weight: f64) -> Self {
         Self {
             diffs: Vec::new(),
+            partial: 0.,
+            weight_bonus: 0.,
+            total_weight,
 
             combo: 0,
             max_combo: 0,
@@ -188,15 +422,31 @@ impl JudgeInner {
         }
     }
 
+    pub fn commit_partial(&mut self, credit: f32) {
+        self.partial += credit as f64;
+    }
+
+    /// Accounts for the weight of a judged note, see [`crate::core::Note::weight`].
+    pub fn commit_weight(&mut self, what: Judgement, weight: f32) {
+        let credit = match what {
+            Judgement::Perfect => 1.,
+            Judgement::Good => 0.65,
+            _ => return,
+        };
+        self.weight_bonus += (weight as f64 - 1.) * credit;
+    }
+
     pub fn reset(&mut self) {
         self.combo = 0;
         self.max_combo = 0;
         self.counts = [0; 4];
         self.diffs.clear();
+        self.partial = 0.;
+        self.weight_bonus = 0.;
     }
 
     pub fn accuracy(&self) -> f64 {
-        (self.counts[0] as f64 + self.counts[1] as f64 * 0.65) / self.num_of_notes as f64
+        (self.counts[0] as f64 + self.counts[1] as f64 * 0.65 + self.partial + self.weight_bonus) / self.total_weight
     }
 
     pub fn score(&self) -> u32 {
@@ -219,6 +469,7 @@ impl JudgeInner {
             counts: self.counts,
             early,
             late: self.diffs.len() as u32 - early,
+            link_bonus: 0,
         }
     }
 
@@ -236,6 +487,34 @@ mod inner;
 #[cfg(feature = "closed")]
 use inner::*;
 
+/// The complete state of a play at some point, so that a suspended game can be resumed exactly where it was left.
+/// Only valid for the very chart it was taken from. See [`Judge::snapshot`].
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PlayState {
+    pub time: f32,
+    last_time: f32,
+    // judge status, last real time and judged time of every note, by line
+    notes: Vec<Vec<(JudgeStatus, f32, Option<f32>)>>,
+    next_notes: Vec<usize>,
+    hit_errors: Vec<f32>,
+    links: HashMap<u32, LinkState>,
+    link_bonus: u32,
+    outcomes: Vec<Vec<Option<NoteOutcome>>>,
+    inner: JudgeInner,
+    /// [`Chart::content_hash`] of the chart the state was taken from.
+    chart_hash: u64,
+}
+
+impl PlayState {
+    /// Fails if the state wasn't taken from `chart`.
+    pub fn check(&self, chart: &Chart) -> Result<()> {
+        if self.chart_hash != chart.content_hash() {
+            bail!("play state was taken from another chart");
+        }
+        Ok(())
+    }
+}
+
 #[repr(C)]
 pub struct Judge {
     // notes of each line in order
@@ -243,8 +522,27 @@ pub struct Judge {
     pub notes: Vec<(Vec<u32>, usize)>,
     pub trackers: HashMap<u64, VelocityTracker>,
     pub last_time: f32,
+    pub last_error: Option<TimingError>,
+    /// Signed timing errors (in seconds, negative for early) of every hit in order, for graphing them after play.
+    pub hit_errors: Vec<f32>,
+    /// If set, judge events are recorded into it
+    pub replay: Option<Replay>,
+    /// Called with the previous combo whenever a combo is broken
+    pub on_combo_break: Option<Box<dyn FnMut(u32)>>,
+    /// Combo interval between milestones, see [`Self::take_milestone`]
+    pub combo_milestone: Option<u32>,
+    /// Called with the kind and sound parameters of every note whose hit sound is played. Panning isn't applied by the
+    /// sound effects themselves, so audio backends supporting it should hook this.
+    pub on_hit_sound: HitSoundHook,
 
     key_down_count: u32,
+    links: HashMap<u32, LinkState>,
+    link_bonus: u32,
+    // whether some notes are left to the player during autoplay
+    manual_notes: bool,
+    milestone: Option<u32>,
+    // by line and note
+    outcomes: Vec<Vec<Option<NoteOutcome>>>,
 
     pub(crate) inner: JudgeInner,
 }
@@ -265,25 +563,304 @@ impl Judge {
                 (idx, 0)
             })
             .collect();
+        let mut links = HashMap::new();
+        for note in chart.lines.iter().flat_map(|it| it.notes.iter()).filter(|it| !it.fake) {
+            if let Some(link) = note.link {
+                let state = links.entry(link).or_insert(LinkState {
+                    size: 0,
+                    remaining: 0,
+                    intact: true,
+                });
+                state.size += 1;
+                state.remaining += 1;
+            }
+        }
         Self {
             notes,
             trackers: HashMap::new(),
             last_time: 0.,
+            last_error: None,
+            hit_errors: Vec::new(),
+            replay: None,
+            on_combo_break: None,
+            combo_milestone: None,
+            on_hit_sound: None,
 
             key_down_count: 0,
+            links,
+            link_bonus: 0,
+            manual_notes: chart.lines.iter().flat_map(|it| it.notes.iter()).any(|it| !it.fake && it.no_autoplay),
+            milestone: None,
+            outcomes: chart.lines.iter().map(|it| vec![None; it.notes.len()]).collect(),
 
-            inner: JudgeInner::new(chart.lines.iter().map(|it| it.notes.iter().filter(|it| !it.fake).count() as u32).sum()),
+            inner: JudgeInner::new(
+                chart.lines.iter().map(|it| it.notes.iter().filter(|it| !it.fake).count() as u32).sum(),
+                chart
+                    .lines
+                    .iter()
+                    .flat_map(|it| it.notes.iter())
+                    .filter(|it| !it.fake)
+                    .map(|it| it.weight as f64)
+                    .sum(),
+            ),
         }
     }
 
     pub fn reset(&mut self) {
         self.notes.iter_mut().for_each(|it| it.1 = 0);
         self.trackers.clear();
+        self.last_error = None;
+        self.hit_errors.clear();
+        if let Some(replay) = &mut self.replay {
+            replay.records.clear();
+        }
+        for state in self.links.values_mut() {
+            state.remaining = state.size;
+            state.intact = true;
+        }
+        self.link_bonus = 0;
+        self.milestone = None;
+        self.outcomes.iter_mut().for_each(|it| it.fill(None));
         self.inner.reset();
     }
 
+    /// Captures the current state of the play, to be resumed later with [`Self::restore`].
+    pub fn snapshot(&self, res: &Resource, chart: &Chart) -> PlayState {
+        PlayState {
+            time: res.time,
+            last_time: self.last_time,
+            notes: chart
+                .lines
+                .iter()
+                .map(|line| {
+                    line.notes
+                        .iter()
+                        .map(|it| (it.judge.clone(), it.last_real_time, it.judged_time))
+                        .collect()
+                })
+                .collect(),
+            next_notes: self.notes.iter().map(|it| it.1).collect(),
+            hit_errors: self.hit_errors.clone(),
+            links: self.links.clone(),
+            link_bonus: self.link_bonus,
+            outcomes: self.outcomes.clone(),
+            inner: self.inner.clone(),
+            chart_hash: chart.content_hash(),
+        }
+    }
+
+    /// Brings the judge, `chart` and the time of `res` back to `state`. The music has to be seeked to
+    /// [`PlayState::time`] by the caller.
+    ///
+    /// Fails, leaving everything untouched, if `state` wasn't taken from this chart.
+    pub fn restore(&mut self, res: &mut Resource, chart: &mut Chart, state: &PlayState) -> Result<()> {
+        state.check(chart)?;
+        res.time = state.time;
+        for (line, notes) in chart.lines.iter_mut().zip(&state.notes) {
+            for (note, (judge, last_real_time, judged_time)) in line.notes.iter_mut().zip(notes) {
+                note.judge = judge.clone();
+                note.last_real_time = *last_real_time;
+                note.judged_time = *judged_time;
+            }
+            line.cache.reset(&mut line.notes);
+        }
+        for (it, next) in self.notes.iter_mut().zip(&state.next_notes) {
+            it.1 = *next;
+        }
+        self.trackers.clear();
+        self.last_time = state.last_time;
+        self.last_error = None;
+        self.hit_errors = state.hit_errors.clone();
+        self.links = state.links.clone();
+        self.link_bonus = state.link_bonus;
+        self.outcomes = state.outcomes.clone();
+        self.inner = state.inner.clone();
+        Ok(())
+    }
+
+    fn commit_link(&mut self, link: Option<u32>, what: Judgement) {
+        let Some(state) = link.and_then(|it| self.links.get_mut(&it)) else {
+            return;
+        };
+        state.remaining -= 1;
+        state.intact &= matches!(what, Judgement::Perfect | Judgement::Good);
+        if state.remaining == 0 && state.intact {
+            self.link_bonus += 1;
+        }
+    }
+
+    /// Keeps track of the signed timing error of a hit at `time`, returning it if it's a non-perfect hit.
+    fn track_error(&mut self, judgement: Judgement, diff: f32, time: f32, line_id: usize) -> Option<f32> {
+        if !matches!(judgement, Judgement::Miss) {
+            self.hit_errors.push(diff);
+        }
+        if !matches!(judgement, Judgement::Good | Judgement::Bad) {
+            return None;
+        }
+        self.last_error = Some(TimingError { diff, time, line_id });
+        Some(diff)
+    }
+
+    fn record(&mut self, time: f32, line_id: usize, note_id: u32, event: ReplayEvent) {
+        self.track_outcome(time, line_id, note_id, event);
+        if let Some(replay) = &mut self.replay {
+            replay.records.push(ReplayRecord {
+                time,
+                line_id,
+                note_id,
+                event,
+            });
+        }
+    }
+
+    fn track_outcome(&mut self, time: f32, line_id: usize, note_id: u32, event: ReplayEvent) {
+        let outcome = &mut self.outcomes[line_id][note_id as usize];
+        match event {
+            ReplayEvent::HoldStart { .. } => {}
+            ReplayEvent::Judged(judgement, diff) => {
+                *outcome = Some(NoteOutcome {
+                    time,
+                    judgement,
+                    diff,
+                    // credited right before the hold is judged
+                    partial: outcome.as_ref().map_or(0., |it| it.partial),
+                });
+            }
+            ReplayEvent::PartialCredit(credit) => {
+                outcome
+                    .get_or_insert(NoteOutcome {
+                        time,
+                        judgement: Judgement::Miss,
+                        diff: None,
+                        partial: 0.,
+                    })
+                    .partial += credit;
+            }
+        }
+    }
+
+    /// Reconstructs the judge state (combo, score and the status of every note) at the current time of `tm`, which should
+    /// already be seeked, by applying the events of `replay` from the very beginning. `offset` is the one subtracted from the
+    /// time of `tm` to get the chart time.
+    ///
+    /// Fails, leaving everything untouched, if the replay wasn't recorded on this chart.
+    pub fn seek_replay(&mut self, res: &Resource, chart: &mut Chart, replay: &Replay, tm: &TimeManager, offset: f32) -> Result<()> {
+        replay.check(chart)?;
+        self.reset();
+        chart.reset();
+        let spd = res.config.speed;
+        // the same way the game derives the chart time every frame
+        let time = (tm.now() - offset as f64).max(0.) as f32;
+        for record in replay.records.iter().take_while(|it| it.time <= time) {
+            self.track_outcome(record.time, record.line_id, record.note_id, record.event);
+            let note = &mut chart.lines[record.line_id].notes[record.note_id as usize];
+            match record.event {
+                ReplayEvent::HoldStart { perfect, diff } => {
+                    note.judge = JudgeStatus::Hold(perfect, record.time, diff, false, f32::INFINITY);
+                    note.last_real_time = time / spd;
+                }
+                ReplayEvent::Judged(what, diff) => {
+                    note.judge = JudgeStatus::Judged;
+                    let (link, weight) = (note.link, note.weight);
+                    // not through `commit`, seeking doesn't break combos
+                    self.inner.commit(what, diff);
+                    self.inner.commit_weight(what, weight);
+                    self.commit_link(link, what);
+                }
+                ReplayEvent::PartialCredit(credit) => {
+                    self.inner.commit_partial(credit);
+                }
+            }
+        }
+        self.skip_judged(chart);
+        self.last_time = time / spd;
+        Ok(())
+    }
+
+    /// Moves past the notes at the front of each line that are already judged.
+    fn skip_judged(&mut self, chart: &Chart) {
+        for (line, (idx, st)) in chart.lines.iter().zip(self.notes.iter_mut()) {
+            while idx
+                .get(*st)
+                .map_or(false, |id| matches!(line.notes[*id as usize].judge, JudgeStatus::Judged))
+            {
+                *st += 1;
+            }
+        }
+    }
+
+    /// Swaps `chart` for `new` (e.g. the same chart parsed again after being edited) during play, keeping the play
+    /// time. Notes are matched by line and index: a note keeps its judge status if its time, kind and fakeness are
+    /// unchanged, and is re-armed otherwise. Score and combo are rebuilt from the judgements of the kept notes.
+    pub fn reload_chart(&mut self, chart: &mut Chart, mut new: Chart) {
+        let mut kept = Vec::new();
+        for (line_id, (line, old)) in new.lines.iter_mut().zip(&chart.lines).enumerate() {
+            for (id, (note, old)) in line.notes.iter_mut().zip(&old.notes).enumerate() {
+                if note.time == old.time && note.fake == old.fake && std::mem::discriminant(&note.kind) == std::mem::discriminant(&old.kind) {
+                    note.judge = old.judge.clone();
+                    note.last_real_time = old.last_real_time;
+                    note.judged_time = old.judged_time;
+                    if let Some(outcome) = &self.outcomes[line_id][id] {
+                        kept.push((line_id, id, outcome.clone()));
+                    }
+                }
+            }
+            line.cache.reset(&mut line.notes);
+        }
+        new.set_seed(chart.seed());
+        let fresh = Self::new(&new);
+        self.notes = fresh.notes;
+        self.links = fresh.links;
+        self.link_bonus = 0;
+        self.manual_notes = fresh.manual_notes;
+        self.outcomes = fresh.outcomes;
+        self.inner = fresh.inner;
+        self.trackers.clear();
+        self.last_error = None;
+        // in the order they were judged, for the combo to come out the same
+        kept.sort_by_key(|it| it.2.time.not_nan());
+        for (line_id, id, outcome) in kept {
+            let note = &new.lines[line_id].notes[id];
+            self.inner.commit_partial(outcome.partial);
+            self.inner.commit(outcome.judgement, outcome.diff);
+            self.inner.commit_weight(outcome.judgement, note.weight);
+            self.commit_link(note.link, outcome.judgement);
+            self.outcomes[line_id][id] = Some(outcome);
+        }
+        self.skip_judged(&new);
+        *chart = new;
+    }
+
     pub fn commit(&mut self, what: Judgement, diff: Option<f32>) {
+        let combo = self.inner.combo();
         self.inner.commit(what, diff);
+        if combo != 0 && self.inner.combo() == 0 {
+            if let Some(hook) = &mut self.on_combo_break {
+                hook(combo);
+            }
+        }
+        if let Some(step) = self.combo_milestone.filter(|it| *it != 0) {
+            let now = self.inner.combo();
+            if now > combo && now / step > combo / step {
+                self.milestone = Some(now / step * step);
+            }
+        }
+    }
+
+    /// The combo of the last milestone crossed since this was last called, if any.
+    pub fn take_milestone(&mut self) -> Option<u32> {
+        self.milestone.take()
+    }
+
+    /// Mean and standard deviation of [`Self::hit_errors`], `None` if nothing has been hit yet.
+    pub fn hit_error_stats(&self) -> Option<(f32, f32)> {
+        if self.hit_errors.is_empty() {
+            return None;
+        }
+        let n = self.hit_errors.len() as f32;
+        let mean = self.hit_errors.iter().sum::<f32>() / n;
+        let variance = self.hit_errors.iter().map(|it| (it - mean).powi(2)).sum::<f32>() / n;
+        Some((mean, variance.sqrt()))
     }
 
     #[inline]
@@ -330,14 +907,20 @@ impl Judge {
     }
 
     pub fn update(&mut self, res: &mut Resource, chart: &mut Chart, bad_notes: &mut Vec<BadNote>) {
-        if res.config.autoplay {
+        let autoplay = res.config.autoplay;
+        if autoplay || !chart.extra.auto_sections.is_empty() {
             self.auto_play_update(res, chart);
-            return;
+            if autoplay && !self.manual_notes {
+                return;
+            }
         }
         const X_DIFF_MAX: f32 = 0.21 / (16. / 9.) * 2.;
         let spd = res.config.speed;
 
         let t = res.time;
+        let line_time = chart.settings.line_time(t);
+        let late_grace = res.config.late_grace;
+        let line_tolerance = res.config.line_tolerance;
         // TODO optimize
         let mut touches: HashMap<u64, Touch> = {
             let mut touches = touches();
@@ -378,7 +961,8 @@ impl Judge {
             let guard = it.borrow();
             (guard.0.clone(), guard.2)
         });
-        self.key_down_count = self.key_down_count.saturating_add_signed(TOUCHES.with(|it| it.borrow().1));
+        let key_delta = TOUCHES.with(|it| it.borrow().1);
+        self.key_down_count = self.key_down_count.saturating_add_signed(key_delta);
         {
             fn to_local(Vec2 { x, y }: Vec2) -> Point {
                 Point::new(x / screen_width() * 2. - 1., y / screen_height() * 2. - 1.)
@@ -416,26 +1000,20 @@ impl Judge {
         // pos[line][touch]
         let mut pos = Vec::<Vec<Option<Point>>>::with_capacity(chart.lines.len());
         for id in 0..pos.capacity() {
-            chart.lines[id].object.set_time(t);
+            chart.lines[id].object.set_time(line_time - chart.lines[id].time_offset);
             let inv = chart.lines[id].now_transform(res, &chart.lines).try_inverse().unwrap();
             pos.push(
                 touches
                     .iter()
                     .map(|touch| {
                         let p = touch.position;
-                        let p = inv.transform_point(&Point::new(p.x, -p.y));
-                        fn ok(f: f32) -> bool {
-                            matches!(f.classify(), FpCategory::Zero | FpCategory::Subnormal | FpCategory::Normal)
-                        }
-                        if ok(p.x) && ok(p.y) {
-                            Some(p)
-                        } else {
-                            None
-                        }
+                        on_line(inv.transform_point(&Point::new(p.x, -p.y)), line_tolerance)
                     })
                     .collect(),
             );
         }
+        // notes judged automatically were taken care of above
+        let sections = &chart.extra.auto_sections;
         let mut judgements = Vec::new();
         // clicks & flicks
         for (id, touch) in touches.iter().enumerate() {
@@ -445,19 +1023,41 @@ impl Judge {
             if !(click || flick) {
                 continue;
             }
+            let swipe = self.trackers.get(&touch.id).map_or_else(Vector::default, |it| it.speed());
+            // (note, distance, dt). Among judgeable notes, the earliest one wins, and the nearest one if they're simultaneous
             let mut closest = (None, X_DIFF_MAX, LIMIT_BAD);
             for (line_id, ((line, pos), (idx, st))) in chart.lines.iter_mut().zip(pos.iter()).zip(self.notes.iter_mut()).enumerate() {
+                if line.notes_disabled {
+                    continue;
+                }
                 let Some(pos) = pos[id] else { continue; };
+                let t = t - line.time_offset;
+                let line_rot = line.object.rotation.now();
                 for id in &idx[*st..] {
                     let note = &mut line.notes[*id as usize];
-                    if !matches!(note.judge, JudgeStatus::NotJudged | JudgeStatus::PreJudge) {
+                    if is_auto(autoplay, sections, note, line.time_offset) || !matches!(note.judge, JudgeStatus::NotJudged | JudgeStatus::PreJudge) {
                         continue;
                     }
                     if !click && matches!(note.kind, NoteKind::Click | NoteKind::Hold { .. }) {
                         continue;
                     }
+                    if let (true, NoteKind::Flick, Some(dir)) = (flick, &note.kind, note.flick_dir) {
+                        let rot = line_rot + if note.above { 0. } else { 180. } + dir;
+                        if swipe_angle(swipe, rot).abs() > res.config.flick_tolerance {
+                            continue;
+                        }
+                    }
+                    if matches!(
+                        note.kind,
+                        NoteKind::Hold {
+                            kind: HoldKind::Reverse | HoldKind::Drag,
+                            ..
+                        }
+                    ) {
+                        continue;
+                    }
                     let dt = (note.time - t) / spd;
-                    if dt >= closest.2 {
+                    if dt > closest.2 + SIMULTANEOUS_TOLERANCE {
                         break;
                     }
                     let x = &mut note.object.translation.0;
@@ -466,13 +1066,12 @@ impl Judge {
                     if dist > X_DIFF_MAX {
                         continue;
                     }
-                    if dt.abs()
-                        > if matches!(note.kind, NoteKind::Click) {
-                            LIMIT_BAD - LIMIT_PERFECT * (dist - 0.9).max(0.)
-                        } else {
-                            LIMIT_GOOD
-                        }
-                    {
+                    let limit = if matches!(note.kind, NoteKind::Click) {
+                        LIMIT_BAD - LIMIT_PERFECT * (dist - 0.9).max(0.)
+                    } else {
+                        LIMIT_GOOD
+                    };
+                    if !in_window(-dt, limit, late_grace) {
                         continue;
                     }
                     let dt = if matches!(note.kind, NoteKind::Flick | NoteKind::Drag) {
@@ -480,15 +1079,14 @@ impl Judge {
                     } else {
                         dt
                     };
-                    if dt + (dist / res.note_width - 1.).max(0.) * DIST_FACTOR
-                        < closest.2 - 0.01 + (closest.1 / res.note_width - 1.).max(0.) * DIST_FACTOR
-                    {
-                        closest = (Some((line_id, *id)), dist, dt + 0.01);
+                    if closest.0.is_none() || takes_priority((dt, dist), (closest.2, closest.1)) {
+                        closest = (Some((line_id, *id)), dist, dt);
                     }
                 }
             }
             if let (Some((line_id, id)), _, dt) = closest {
                 let line = &mut chart.lines[line_id];
+                let t = t - line.time_offset;
                 if matches!(line.notes[id as usize].kind, NoteKind::Drag) {
                     continue;
                 }
@@ -498,7 +1096,7 @@ impl Judge {
                     if matches!(note.kind, NoteKind::Flick) {
                         continue; // to next loop
                     }
-                    let dt = (dt - 0.01).abs();
+                    let dt = dt.abs();
                     if dt <= LIMIT_GOOD || matches!(note.kind, NoteKind::Hold { .. }) {
                         match note.kind {
                             NoteKind::Click => {
@@ -506,8 +1104,19 @@ impl Judge {
                                 judgements.push((if dt <= LIMIT_PERFECT { Judgement::Perfect } else { Judgement::Good }, line_id, id, None));
                             }
                             NoteKind::Hold { .. } => {
-                                play_sfx(&mut res.sfx_click, &res.config);
-                                note.judge = JudgeStatus::Hold(dt <= LIMIT_PERFECT, t, (t - note.time) / spd, false, f32::INFINITY);
+                                play_hit_sound(&mut self.on_hit_sound, &mut res.sfx_click, &res.config, &note.kind, note.sound);
+                                let diff = (t - note.time) / spd;
+                                note.judge = JudgeStatus::Hold(dt <= LIMIT_PERFECT, t, diff, false, f32::INFINITY);
+                                note.last_real_time = t / spd;
+                                self.record(
+                                    res.time,
+                                    line_id,
+                                    id,
+                                    ReplayEvent::HoldStart {
+                                        perfect: dt <= LIMIT_PERFECT,
+                                        diff,
+                                    },
+                                );
                             }
                             _ => unreachable!(),
                         };
@@ -526,26 +1135,14 @@ impl Judge {
         }
         for _ in 0..keys_down {
             // find the earliest not judged click / hold note
-            if let Some((line_id, id)) = chart
-                .lines
-                .iter()
-                .zip(self.notes.iter())
-                .enumerate()
-                .filter_map(|(line_id, (line, (idx, st)))| {
-                    idx[*st..]
-                        .iter()
-                        .cloned()
-                        .find(|id| {
-                            let note = &line.notes[*id as usize];
-                            matches!(note.judge, JudgeStatus::NotJudged) && matches!(note.kind, NoteKind::Click | NoteKind::Hold { .. })
-                        })
-                        .map(|id| (line_id, id))
-                })
-                .min_by_key(|(line_id, id)| chart.lines[*line_id].notes[*id as usize].time.not_nan())
-            {
-                let note = &mut chart.lines[line_id].notes[id as usize];
-                let dt = (t - note.time).abs() / spd;
-                if dt <= if matches!(note.kind, NoteKind::Click) { LIMIT_BAD } else { LIMIT_GOOD } {
+            if let Some((line_id, id)) = next_key_note(chart, &self.notes, autoplay, sections) {
+                let line = &mut chart.lines[line_id];
+                let dt = note_dt(&line.notes[id as usize], line.time_offset, t) / spd;
+                let t = t - line.time_offset;
+                let note = &mut line.notes[id as usize];
+                let limit = if matches!(note.kind, NoteKind::Click) { LIMIT_BAD } else { LIMIT_GOOD };
+                if in_window(dt, limit, late_grace) {
+                    let dt = dt.abs();
                     match note.kind {
                         NoteKind::Click => {
                             note.judge = JudgeStatus::Judged;
@@ -563,8 +1160,19 @@ impl Judge {
                             ));
                         }
                         NoteKind::Hold { .. } => {
-                            play_sfx(&mut res.sfx_click, &res.config);
-                            note.judge = JudgeStatus::Hold(dt <= LIMIT_PERFECT, t, (t - note.time) / spd, false, f32::INFINITY);
+                            play_hit_sound(&mut self.on_hit_sound, &mut res.sfx_click, &res.config, &note.kind, note.sound);
+                            let diff = (t - note.time) / spd;
+                            note.judge = JudgeStatus::Hold(dt <= LIMIT_PERFECT, t, diff, false, f32::INFINITY);
+                            note.last_real_time = t / spd;
+                            self.record(
+                                res.time,
+                                line_id,
+                                id,
+                                ReplayEvent::HoldStart {
+                                    perfect: dt <= LIMIT_PERFECT,
+                                    diff,
+                                },
+                            );
                         }
                         _ => unreachable!(),
                     };
@@ -573,28 +1181,59 @@ impl Judge {
                 break;
             }
         }
+        // holds started while the touches are checked, recorded afterwards
+        let mut hold_starts = Vec::new();
+        let mut partial_credits = Vec::new();
         for (line_id, ((line, pos), (idx, st))) in chart.lines.iter_mut().zip(pos.iter()).zip(self.notes.iter()).enumerate() {
-            line.object.set_time(t);
+            if line.notes_disabled {
+                continue;
+            }
+            line.object.set_time(line_time - line.time_offset);
+            let t = t - line.time_offset;
             for id in &idx[*st..] {
                 let note = &mut line.notes[*id as usize];
-                if let NoteKind::Hold { end_time, .. } = &note.kind {
-                    if let JudgeStatus::Hold(.., ref mut pre_judge, ref mut up_time) = note.judge {
-                        if (*end_time - t) / spd <= LIMIT_BAD {
-                            *pre_judge = true;
+                if is_auto(autoplay, sections, note, line.time_offset) {
+                    continue;
+                }
+                let grace = note.release_grace.unwrap_or(LIMIT_BAD);
+                if let NoteKind::Hold { end_time, kind, .. } = &note.kind {
+                    if let JudgeStatus::Hold(ref mut perfect, .., ref mut pre_judge, ref mut up_time) = note.judge {
+                        if *pre_judge {
                             continue;
                         }
                         let x = &mut note.object.translation.0;
                         x.set_time(t);
                         let x = x.now();
-                        if self.key_down_count == 0 && !pos.iter().any(|it| it.map_or(false, |it| (it.x - x).abs() <= X_DIFF_MAX)) {
-                            if t > *up_time + UP_TOLERANCE {
+                        let left = (*end_time - t) / spd;
+                        let near = |it: &Option<Point>| it.map_or(false, |it| (it.x - x).abs() <= X_DIFF_MAX);
+                        let pressed = keys_down != 0
+                            || touches
+                                .iter()
+                                .zip(pos.iter())
+                                .any(|(touch, it)| touch.phase == TouchPhase::Started && near(it));
+                        let touched = self.key_down_count != 0 || pos.iter().any(near);
+                        match hold_step(*kind, left, grace, pressed, touched) {
+                            HoldStep::Done => *pre_judge = true,
+                            HoldStep::Miss => {
                                 note.judge = JudgeStatus::Judged;
                                 judgements.push((Judgement::Miss, line_id, *id, None));
-                            } else if up_time.is_infinite() {
-                                *up_time = t;
                             }
-                        } else {
-                            *up_time = f32::INFINITY;
+                            HoldStep::Ok => note.hold_on(t / spd),
+                            HoldStep::Lapse => {
+                                if t > *up_time + UP_TOLERANCE {
+                                    if res.config.hold_partial_credit && *kind != HoldKind::Reverse {
+                                        let credit = partial_credit(note.time..*end_time, *up_time, *perfect) * note.weight;
+                                        partial_credits.push((line_id, *id, credit));
+                                    }
+                                    note.judge = JudgeStatus::Judged;
+                                    judgements.push((Judgement::Miss, line_id, *id, None));
+                                } else if up_time.is_infinite() {
+                                    *up_time = t;
+                                }
+                            }
+                            // a gap doesn't miss the note, but it's no longer perfect
+                            HoldStep::Slip => *perfect = false,
+                            HoldStep::Wait => {}
                         }
                         continue;
                     }
@@ -602,9 +1241,39 @@ impl Judge {
                 if !matches!(note.judge, JudgeStatus::NotJudged) {
                     continue;
                 }
+                let dt = note_dt(note, line.time_offset, res.time) / spd;
+                if let NoteKind::Hold {
+                    kind: kind @ (HoldKind::Reverse | HoldKind::Drag),
+                    ..
+                } = note.kind
+                {
+                    let x = &mut note.object.translation.0;
+                    x.set_time(t);
+                    let x = x.now();
+                    let near = |it: &Option<Point>| it.map_or(false, |it| (it.x - x).abs() <= X_DIFF_MAX);
+                    let released = key_delta < 0
+                        || touches
+                            .iter()
+                            .zip(pos.iter())
+                            .any(|(touch, it)| matches!(touch.phase, TouchPhase::Ended | TouchPhase::Cancelled) && near(it));
+                    let touched = self.key_down_count != 0 || pos.iter().any(near);
+                    if hold_started(kind, dt, released, touched) {
+                        if kind == HoldKind::Drag {
+                            play_hit_sound(&mut self.on_hit_sound, &mut res.sfx_drag, &res.config, &note.kind, note.sound);
+                            note.judge = JudgeStatus::Hold(true, t, dt, false, f32::INFINITY);
+                            note.last_real_time = t / spd;
+                            hold_starts.push((line_id, *id, true, dt));
+                        } else {
+                            play_hit_sound(&mut self.on_hit_sound, &mut res.sfx_click, &res.config, &note.kind, note.sound);
+                            let perfect = dt.abs() <= LIMIT_PERFECT;
+                            note.judge = JudgeStatus::Hold(perfect, t, dt, false, f32::INFINITY);
+                            hold_starts.push((line_id, *id, perfect, dt));
+                        }
+                        continue;
+                    }
+                }
                 // process miss
-                let dt = (t - note.time) / spd;
-                if dt > LIMIT_BAD {
+                if dt > LIMIT_BAD + late_grace {
                     note.judge = JudgeStatus::Judged;
                     judgements.push((Judgement::Miss, line_id, *id, None));
                     continue;
@@ -631,9 +1300,20 @@ impl Judge {
                 }
             }
         }
+        for (line_id, id, perfect, diff) in hold_starts {
+            self.record(t, line_id, id, ReplayEvent::HoldStart { perfect, diff });
+        }
+        for (line_id, id, credit) in partial_credits {
+            self.inner.commit_partial(credit);
+            self.record(t, line_id, id, ReplayEvent::PartialCredit(credit));
+        }
         // process pre-judge
         for (line_id, (line, (idx, st))) in chart.lines.iter_mut().zip(self.notes.iter()).enumerate() {
-            line.object.set_time(t);
+            if line.notes_disabled {
+                continue;
+            }
+            line.object.set_time(line_time - line.time_offset);
+            let t = t - line.time_offset;
             for id in &idx[*st..] {
                 let note = &mut line.notes[*id as usize];
                 if let JudgeStatus::Hold(perfect, .., diff, true, _) = note.judge {
@@ -661,30 +1341,28 @@ impl Judge {
         }
         for (judgement, line_id, id, diff) in judgements.into_iter() {
             let line = &mut chart.lines[line_id];
+            let t = t - line.time_offset;
             let note = &mut line.notes[id as usize];
-            line.object.set_time(t);
+            line.object.set_time(line_time - line.time_offset);
             note.object.set_time(t);
             let line = &chart.lines[line_id];
             let note = &line.notes[id as usize];
             let line_tr = line.now_transform(res, &chart.lines);
-            self.commit(
-                judgement,
-                if matches!(judgement, Judgement::Good | Judgement::Bad) {
-                    Some(diff.unwrap_or((t - note.time) / spd))
-                } else {
-                    None
-                },
-            );
+            let diff = self.track_error(judgement, diff.unwrap_or((t - note.time) / spd), res.time, line_id);
+            self.commit(judgement, diff);
+            self.inner.commit_weight(judgement, note.weight);
+            self.commit_link(note.link, judgement);
+            self.record(res.time, line_id, id, ReplayEvent::Judged(judgement, diff));
             if matches!(note.kind, NoteKind::Hold { .. }) {
                 continue;
             }
             if match judgement {
                 Judgement::Perfect => {
-                    res.with_model(line_tr * note.object.now(res), |res| res.emit_at_origin(note.rotation(line), JUDGE_LINE_PERFECT_COLOR));
+                    res.with_model(line_tr * note.object.now(res), |res| res.emit_at_origin(note.rotation(line), res.hit_colors.perfect));
                     true
                 }
                 Judgement::Good => {
-                    res.with_model(line_tr * note.object.now(res), |res| res.emit_at_origin(note.rotation(line), JUDGE_LINE_GOOD_COLOR));
+                    res.with_model(line_tr * note.object.now(res), |res| res.emit_at_origin(note.rotation(line), res.hit_colors.good));
                     true
                 }
                 Judgement::Bad => {
@@ -701,7 +1379,7 @@ impl Judge {
                                 mat *= note.now_transform(
                                     res,
                                     &line.ctrl_obj.borrow_mut(),
-                                    (note.height - line.height.now()) / res.aspect_ratio * note.speed,
+                                    (note.height - line.height.now()) / res.aspect_ratio * note.speed * line.note_speed_scale,
                                     incline_sin,
                                 );
                                 mat
@@ -718,7 +1396,7 @@ impl Judge {
                     NoteKind::Flick => Some(&mut res.sfx_flick),
                     _ => None,
                 } {
-                    play_sfx(sfx, &res.config);
+                    play_hit_sound(&mut self.on_hit_sound, sfx, &res.config, &note.kind, note.sound);
                 }
             }
         }
@@ -735,11 +1413,24 @@ impl Judge {
 
     fn auto_play_update(&mut self, res: &mut Resource, chart: &mut Chart) {
         let t = res.time;
+        let autoplay = res.config.autoplay;
+        let sections = &chart.extra.auto_sections;
         let spd = res.config.speed;
         let mut judgements = Vec::new();
+        let mut hold_starts = Vec::new();
         for (line_id, (line, (idx, st))) in chart.lines.iter_mut().zip(self.notes.iter_mut()).enumerate() {
+            if line.notes_disabled {
+                continue;
+            }
+            let t = t - line.time_offset;
             for id in &idx[*st..] {
                 let note = &mut line.notes[*id as usize];
+                if !is_auto(autoplay, sections, note, line.time_offset) {
+                    if note.time > t {
+                        break;
+                    }
+                    continue;
+                }
                 if let JudgeStatus::Hold(..) = note.judge {
                     if let NoteKind::Hold { end_time, .. } = note.kind {
                         if t >= end_time {
@@ -748,6 +1439,7 @@ impl Judge {
                             continue;
                         }
                     }
+                    note.hold_on(t / spd);
                 }
                 if !matches!(note.judge, JudgeStatus::NotJudged) {
                     continue;
@@ -756,8 +1448,11 @@ impl Judge {
                     break;
                 }
                 note.judge = if matches!(note.kind, NoteKind::Hold { .. }) {
-                    play_sfx(&mut res.sfx_click, &res.config);
-                    JudgeStatus::Hold(true, t, (t - note.time) / spd, false, f32::INFINITY)
+                    play_hit_sound(&mut self.on_hit_sound, &mut res.sfx_click, &res.config, &note.kind, note.sound);
+                    note.last_real_time = t / spd;
+                    let diff = (t - note.time) / spd;
+                    hold_starts.push((line_id, *id, diff));
+                    JudgeStatus::Hold(true, t, diff, false, f32::INFINITY)
                 } else {
                     judgements.push((line_id, *id));
                     JudgeStatus::Judged
@@ -770,19 +1465,30 @@ impl Judge {
                 *st += 1;
             }
         }
+        for (line_id, id, diff) in hold_starts {
+            self.record(t, line_id, id, ReplayEvent::HoldStart { perfect: true, diff });
+        }
         for (line_id, id) in judgements.into_iter() {
             self.commit(Judgement::Perfect, None);
-            let (note_transform, note_kind) = {
+            let note = &chart.lines[line_id].notes[id as usize];
+            self.inner.commit_weight(Judgement::Perfect, note.weight);
+            self.commit_link(note.link, Judgement::Perfect);
+            self.record(t, line_id, id, ReplayEvent::Judged(Judgement::Perfect, None));
+            let (note_transform, note_kind, sound) = {
                 let line = &mut chart.lines[line_id];
                 let note = &mut line.notes[id as usize];
-                let nt = if matches!(note.kind, NoteKind::Hold { .. }) { t } else { note.time };
-                line.object.set_time(nt);
+                let nt = if matches!(note.kind, NoteKind::Hold { .. }) {
+                    t - line.time_offset
+                } else {
+                    note.time
+                };
+                line.object.set_time(chart.settings.line_time(nt));
                 note.object.set_time(nt);
-                (note.object.now(res), note.kind.clone())
+                (note.object.now(res), note.kind.clone(), note.sound)
             };
             let line = &chart.lines[line_id];
             res.with_model(line.now_transform(res, &chart.lines) * note_transform, |res| {
-                res.emit_at_origin(line.notes[id as usize].rotation(line), JUDGE_LINE_PERFECT_COLOR)
+                res.emit_at_origin(line.notes[id as usize].rotation(line), res.hit_colors.perfect)
             });
             if let Some(sfx) = match note_kind {
                 NoteKind::Click => Some(&mut res.sfx_click),
@@ -790,14 +1496,22 @@ impl Judge {
                 NoteKind::Flick => Some(&mut res.sfx_flick),
                 _ => None,
             } {
-                play_sfx(sfx, &res.config);
+                play_hit_sound(&mut self.on_hit_sound, sfx, &res.config, &note_kind, sound);
             }
         }
     }
 
-    #[inline]
     pub fn result(&self) -> PlayResult {
-        self.inner.result()
+        PlayResult {
+            link_bonus: self.link_bonus,
+            ..self.inner.result()
+        }
+    }
+
+    /// Number of link groups all of whose notes have been hit.
+    #[inline]
+    pub fn link_bonus(&self) -> u32 {
+        self.link_bonus
     }
 
     #[inline]
@@ -809,6 +1523,19 @@ impl Judge {
     pub fn counts(&self) -> [u32; 4] {
         self.inner.counts()
     }
+
+    /// Whether the play is still a full combo, i.e. no note has been judged bad or missed so far.
+    #[inline]
+    pub fn full_combo(&self) -> bool {
+        let counts = self.counts();
+        counts[2] + counts[3] == 0
+    }
+
+    /// Whether the play is still all perfect, i.e. every note judged so far has been a perfect.
+    #[inline]
+    pub fn all_perfect(&self) -> bool {
+        self.full_combo() && self.counts()[1] == 0
+    }
 }
 
 struct Handler(Vec<Touch>, i32, u32);
@@ -882,4 +1609,410 @@ pub struct PlayResult {
     pub counts: [u32; 4],
     pub early: u32,
     pub late: u32,
+    /// See [`Judge::link_bonus`]
+    pub link_bonus: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::ChartExtra, parse::parse_pec};
+
+    fn chart(notes: &str) -> Chart {
+        parse_pec(&format!("0\nbp 0 120\n{notes}"), ChartExtra::default()).unwrap()
+    }
+
+    fn hit(judge: &mut Judge, chart: &mut Chart, id: u32) {
+        let note = &mut chart.lines[0].notes[id as usize];
+        note.judge = JudgeStatus::Judged;
+        let time = note.time;
+        judge.commit(Judgement::Perfect, None);
+        judge.record(time, 0, id, ReplayEvent::Judged(Judgement::Perfect, None));
+    }
+
+    #[test]
+    fn reload_keeps_unchanged_notes() {
+        let mut chart = chart("n1 0 1 0 1 0\nn1 0 2 0 1 0\nn1 0 3 0 1 0\n");
+        let mut judge = Judge::new(&chart);
+        hit(&mut judge, &mut chart, 0);
+        hit(&mut judge, &mut chart, 1);
+        judge.last_time = 1.2;
+
+        // the second note is moved
+        judge.reload_chart(&mut chart, self::chart("n1 0 1 0 1 0\nn1 0 2.5 0 1 0\nn1 0 3 0 1 0\n"));
+        assert_eq!(judge.last_time, 1.2);
+        let notes = &chart.lines[0].notes;
+        assert!(matches!(notes[0].judge, JudgeStatus::Judged));
+        assert!(matches!(notes[1].judge, JudgeStatus::NotJudged));
+        assert!(matches!(notes[2].judge, JudgeStatus::NotJudged));
+        assert_eq!(judge.notes[0].1, 1);
+        // the kept note still counts, so a full score is still possible
+        assert_eq!(judge.combo(), 1);
+        assert_eq!(judge.counts(), [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn recorded_events_reach_replay() {
+        let mut chart = chart("n1 0 1 0 1 0\nn1 0 2 0 1 0\n");
+        let mut judge = Judge::new(&chart);
+        judge.replay = Some(Replay::new(&chart));
+        hit(&mut judge, &mut chart, 0);
+        judge.record(1.5, 0, 1, ReplayEvent::PartialCredit(0.5));
+        let replay = judge.replay.as_ref().unwrap();
+        assert_eq!(replay.chart_hash, chart.content_hash());
+        let records = replay.records.iter().map(|it| (it.time, it.note_id)).collect::<Vec<_>>();
+        assert_eq!(records, [(0.5, 0), (1.5, 1)]);
+        assert!(judge.outcomes[0].iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn play_state_only_fits_its_chart() {
+        let chart = chart("n1 0 1 0 1 0\n");
+        let judge = Judge::new(&chart);
+        let state = PlayState {
+            time: 0.,
+            last_time: 0.,
+            notes: Vec::new(),
+            next_notes: Vec::new(),
+            hit_errors: Vec::new(),
+            links: HashMap::new(),
+            link_bonus: 0,
+            outcomes: Vec::new(),
+            inner: judge.inner.clone(),
+            chart_hash: chart.content_hash(),
+        };
+        assert!(state.check(&chart).is_ok());
+        assert!(state.check(&self::chart("n1 0 1 0 1 0\nn1 0 2 0 1 0\n")).is_err());
+    }
+
+    #[test]
+    fn timing_error_sign() {
+        let chart = chart("n1 0 1 0 1 0\n");
+        let mut judge = Judge::new(&chart);
+        assert_eq!(judge.track_error(Judgement::Good, -0.03, 0.47, 0), Some(-0.03));
+        assert!(judge.last_error.unwrap().diff < 0.);
+        assert_eq!(judge.track_error(Judgement::Bad, 0.03, 0.53, 0), Some(0.03));
+        assert!(judge.last_error.unwrap().diff > 0.);
+        // perfect hits aren't shown
+        assert_eq!(judge.track_error(Judgement::Perfect, 0.01, 0.51, 0), None);
+        assert_eq!(judge.last_error.unwrap().time, 0.53);
+    }
+
+    #[test]
+    fn combo_break_fires_hook() {
+        use std::{cell::Cell, rc::Rc};
+        let chart = chart("n1 0 1 0 1 0\n");
+        let mut judge = Judge::new(&chart);
+        let broken = Rc::new(Cell::new(None));
+        let hook = Rc::clone(&broken);
+        judge.on_combo_break = Some(Box::new(move |combo| hook.set(Some(combo))));
+        for _ in 0..50 {
+            judge.commit(Judgement::Perfect, None);
+        }
+        assert_eq!(broken.get(), None);
+        judge.commit(Judgement::Miss, None);
+        assert_eq!(broken.get(), Some(50));
+        // nothing to break anymore
+        broken.set(None);
+        judge.commit(Judgement::Miss, None);
+        assert_eq!(broken.get(), None);
+    }
+
+    #[test]
+    fn link_bonus_needs_every_member() {
+        let mut chart = chart("n1 0 1 0 1 0\nn1 0 1 100 1 0\nn1 0 2 0 1 0\nn1 0 2 100 1 0\n");
+        for (note, link) in chart.lines[0].notes.iter_mut().zip([1, 1, 2, 2]) {
+            note.link = Some(link);
+        }
+        let mut judge = Judge::new(&chart);
+        judge.commit_link(Some(1), Judgement::Perfect);
+        assert_eq!(judge.link_bonus, 0);
+        judge.commit_link(Some(1), Judgement::Good);
+        assert_eq!(judge.link_bonus, 1);
+        judge.commit_link(Some(2), Judgement::Perfect);
+        judge.commit_link(Some(2), Judgement::Miss);
+        assert_eq!(judge.link_bonus, 1);
+    }
+
+    #[test]
+    fn partial_credit_counts_toward_accuracy() {
+        let chart = chart("n2 0 1 3 0 1 0\nn1 0 4 0 1 0\n");
+        let mut judge = Judge::new(&chart);
+        judge.commit(Judgement::Perfect, None);
+        // the hold was released halfway through
+        judge.inner.commit_partial(0.5);
+        assert_eq!(judge.accuracy(), 0.75);
+        assert!(judge.score() < 1000000);
+        judge.inner.reset();
+        assert_eq!(judge.accuracy(), 0.);
+    }
+
+    #[test]
+    fn weighted_notes_count_more() {
+        let mut chart = chart("n1 0 1 0 1 0\nn1 0 2 0 1 0\n");
+        chart.lines[0].notes[0].weight = 2.;
+        let mut judge = Judge::new(&chart);
+        judge.commit(Judgement::Perfect, None);
+        judge.inner.commit_weight(Judgement::Perfect, 2.);
+        assert!((judge.accuracy() - 2. / 3.).abs() < 1e-9);
+        judge.commit(Judgement::Good, None);
+        judge.inner.commit_weight(Judgement::Good, 1.);
+        assert!((judge.accuracy() - 2.65 / 3.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn swipe_angle_of_flicks() {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-3;
+        // swiping up the screen is 90°
+        assert!(close(swipe_angle(Vector::new(0., -1.), 90.), 0.));
+        assert!(close(swipe_angle(Vector::new(1., -1.), 90.), -45.));
+        assert!(close(swipe_angle(Vector::new(-1., 0.), 0.), -180.));
+        // wraps around
+        assert!(close(swipe_angle(Vector::new(1., 0.1), 350.), 10f32 - 0.1f32.atan().to_degrees()));
+    }
+
+    #[test]
+    fn hit_error_mean_and_deviation() {
+        let chart = chart("n1 0 1 0 1 0\n");
+        let mut judge = Judge::new(&chart);
+        assert_eq!(judge.hit_error_stats(), None);
+        judge.hit_errors = vec![-0.02, 0.04, 0.01, 0.05];
+        let (mean, deviation) = judge.hit_error_stats().unwrap();
+        assert!((mean - 0.02).abs() < 1e-6);
+        assert!((deviation - 0.00075f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn autoplay_leaves_manual_notes() {
+        let mut chart = chart("n1 0 1 0 1 0\nn1 0 2 0 1 0\n");
+        assert!(!Judge::new(&chart).manual_notes);
+        chart.lines[0].notes[1].no_autoplay = true;
+        assert!(Judge::new(&chart).manual_notes);
+        let notes = &chart.lines[0].notes;
+        assert!(is_auto(true, &[], &notes[0], 0.));
+        assert!(!is_auto(true, &[], &notes[1], 0.));
+        assert!(!is_auto(false, &[], &notes[0], 0.));
+    }
+
+    #[test]
+    fn full_combo_and_all_perfect() {
+        let chart = chart("n1 0 1 0 1 0\nn1 0 2 0 1 0\nn1 0 3 0 1 0\n");
+        let mut judge = Judge::new(&chart);
+        assert!(judge.all_perfect());
+        judge.commit(Judgement::Perfect, None);
+        assert!(judge.all_perfect());
+        judge.commit(Judgement::Good, None);
+        assert!(judge.full_combo() && !judge.all_perfect());
+        judge.commit(Judgement::Bad, None);
+        assert!(!judge.full_combo());
+        judge.reset();
+        judge.commit(Judgement::Miss, None);
+        assert!(!judge.full_combo() && !judge.all_perfect());
+    }
+
+    #[test]
+    fn replay_only_fits_its_chart() {
+        let chart = chart("n1 0 1 0 1 0\n");
+        let replay = Replay::new(&chart);
+        assert!(replay.check(&chart).is_ok());
+        assert!(replay.check(&self::chart("n1 0 1 0 1 0\n")).is_ok());
+        assert!(replay.check(&self::chart("n1 0 1 100 1 0\n")).is_err());
+    }
+
+    #[test]
+    fn auto_sections_judge_notes() {
+        let mut chart = chart("n1 0 1 0 1 0\nn1 0 4 0 1 0\n");
+        chart.lines[0].notes[1].no_autoplay = true;
+        let sections = [1.5..2.5];
+        let notes = &chart.lines[0].notes;
+        assert!(!is_auto(false, &sections, &notes[0], 0.));
+        // sections apply to delayed times, and even to notes left to the player by autoplay
+        assert!(is_auto(false, &sections, &notes[0], 1.));
+        assert!(is_auto(false, &sections, &notes[1], 0.));
+        assert!(!is_auto(false, &sections, &notes[1], 0.5));
+    }
+
+    #[test]
+    fn combo_milestone_fires_once() {
+        let chart = chart("n1 0 1 0 1 0\n");
+        let mut judge = Judge::new(&chart);
+        judge.combo_milestone = Some(100);
+        for _ in 0..99 {
+            judge.commit(Judgement::Perfect, None);
+        }
+        assert_eq!(judge.take_milestone(), None);
+        judge.commit(Judgement::Good, None);
+        assert_eq!(judge.take_milestone(), Some(100));
+        assert_eq!(judge.take_milestone(), None);
+        judge.commit(Judgement::Perfect, None);
+        assert_eq!(judge.take_milestone(), None);
+        // breaking the combo doesn't cross anything
+        judge.commit(Judgement::Miss, None);
+        assert_eq!(judge.take_milestone(), None);
+    }
+
+    #[test]
+    fn line_offset_delays_judging() {
+        let chart = chart("n1 0 2 0 1 0\n");
+        let note = &chart.lines[0].notes[0];
+        assert!(note_dt(note, 0., 1.).abs() < 1e-5);
+        // on a line delayed by 0.1s, hitting on the note's own time is 100ms early
+        assert!((note_dt(note, 0.1, 1.) + 0.1).abs() < 1e-5);
+        assert!(note_dt(note, 0.1, 1.1).abs() < 1e-5);
+        assert!((note_dt(note, 0.1, 1.1 + LIMIT_PERFECT) - LIMIT_PERFECT).abs() < 1e-5);
+    }
+
+    #[test]
+    fn reverse_hold_sequence() {
+        use HoldKind::Reverse;
+        // starts by releasing on time, not by touching
+        assert!(hold_started(Reverse, 0.05, true, false));
+        assert!(!hold_started(Reverse, 0.05, false, true));
+        assert!(!hold_started(Reverse, LIMIT_GOOD + 0.01, true, false));
+        // the body must not be held
+        assert_eq!(hold_step(Reverse, 1., LIMIT_BAD, false, false), HoldStep::Ok);
+        assert_eq!(hold_step(Reverse, 1., LIMIT_BAD, false, true), HoldStep::Lapse);
+        // and the end has to be pressed
+        assert_eq!(hold_step(Reverse, 0.1, LIMIT_BAD, false, false), HoldStep::Wait);
+        assert_eq!(hold_step(Reverse, 0.1, LIMIT_BAD, true, true), HoldStep::Done);
+        assert_eq!(hold_step(Reverse, -LIMIT_GOOD - 0.01, LIMIT_BAD, false, false), HoldStep::Miss);
+    }
+
+    #[test]
+    fn pinch_hold_needs_two_touches() {
+        use HoldKind::Pinch;
+        // started with a click like any other hold
+        assert!(!hold_started(Pinch, 0., true, true));
+        assert_eq!(hold_step(Pinch, 1., LIMIT_BAD, false, true), HoldStep::Ok);
+        assert_eq!(hold_step(Pinch, 1., LIMIT_BAD, false, false), HoldStep::Lapse);
+        // holding on to the end isn't enough, unlike normal holds
+        assert_eq!(hold_step(HoldKind::Normal, 0.1, LIMIT_BAD, false, true), HoldStep::Done);
+        assert_eq!(hold_step(Pinch, 0.1, LIMIT_BAD, false, true), HoldStep::Wait);
+        assert_eq!(hold_step(Pinch, -LIMIT_GOOD - 0.01, LIMIT_BAD, false, true), HoldStep::Miss);
+        // the second touch
+        assert_eq!(hold_step(Pinch, 0.1, LIMIT_BAD, true, true), HoldStep::Done);
+    }
+
+    #[test]
+    fn drag_hold_follows_touch() {
+        use HoldKind::Drag;
+        // touching is enough to start
+        assert!(hold_started(Drag, -0.1, false, true));
+        assert!(!hold_started(Drag, -0.1, false, false));
+        assert_eq!(hold_step(Drag, 1., LIMIT_BAD, false, true), HoldStep::Ok);
+        // losing the touch costs the perfect only
+        assert_eq!(hold_step(Drag, 1., LIMIT_BAD, false, false), HoldStep::Slip);
+        assert_eq!(hold_step(Drag, 0.1, LIMIT_BAD, false, false), HoldStep::Done);
+    }
+
+    #[test]
+    fn release_grace_of_holds() {
+        use HoldKind::Normal;
+        // released 0.3s before the end
+        assert_eq!(hold_step(Normal, 0.3, LIMIT_BAD, false, false), HoldStep::Lapse);
+        assert_eq!(hold_step(Normal, 0.3, 0.35, false, false), HoldStep::Done);
+        assert_eq!(hold_step(Normal, 0.1, LIMIT_BAD, false, false), HoldStep::Done);
+        assert_eq!(hold_step(Normal, 0.1, 0., false, false), HoldStep::Lapse);
+    }
+
+    #[test]
+    fn earliest_then_nearest_note_is_hit() {
+        // an earlier note wins however far it is
+        assert!(takes_priority((0., 0.2), (0.05, 0.)));
+        assert!(!takes_priority((0.05, 0.), (0., 0.2)));
+        // simultaneous ones are told apart by distance
+        assert!(takes_priority((0.005, 0.1), (0., 0.2)));
+        assert!(!takes_priority((0.005, 0.2), (0., 0.1)));
+        // just past the tolerance, distance doesn't matter any more
+        assert!(!takes_priority((0.02, 0.), (0., 0.2)));
+    }
+
+    #[test]
+    fn late_grace_extends_window() {
+        assert!(in_window(-LIMIT_BAD, LIMIT_BAD, 0.));
+        assert!(!in_window(LIMIT_BAD + 0.03, LIMIT_BAD, 0.));
+        assert!(in_window(LIMIT_BAD + 0.03, LIMIT_BAD, 0.05));
+        assert!(!in_window(LIMIT_BAD + 0.06, LIMIT_BAD, 0.05));
+        // early hits don't get any grace
+        assert!(!in_window(-LIMIT_BAD - 0.03, LIMIT_BAD, 0.05));
+    }
+
+    #[test]
+    fn line_tolerance_limits_touches() {
+        let near = Point::new(0.3, 0.05);
+        let far = Point::new(0.3, -0.5);
+        assert_eq!(on_line(near, None), Some(near));
+        assert_eq!(on_line(far, None), Some(far));
+        assert_eq!(on_line(near, Some(0.1)), Some(near));
+        assert_eq!(on_line(far, Some(0.1)), None);
+        assert_eq!(on_line(Point::new(f32::NAN, 0.), None), None);
+    }
+
+    #[test]
+    fn partial_credit_of_released_holds() {
+        assert!((partial_credit(1.0..2.0, 1.7, true) - 0.7).abs() < 1e-5);
+        assert!((partial_credit(1.0..2.0, 1.7, false) - 0.7 * 0.65).abs() < 1e-5);
+        // released right at the start, or after the end somehow
+        assert_eq!(partial_credit(1.0..2.0, 0.9, true), 0.);
+        assert_eq!(partial_credit(1.0..2.0, 2.5, true), 1.);
+    }
+
+    #[test]
+    fn hit_sound_reaches_hook_and_player() {
+        use std::{cell::RefCell, rc::Rc};
+        let heard = Rc::new(RefCell::new(Vec::new()));
+        let mut hook: HitSoundHook = Some(Box::new({
+            let heard = Rc::clone(&heard);
+            move |_: &NoteKind, params: HitSoundParams| heard.borrow_mut().push((params.volume, params.pan))
+        }));
+        let config = Config {
+            volume_sfx: 0.8,
+            ..Default::default()
+        };
+        let left = HitSoundParams { volume: 0.5, pan: -1. };
+        let played = hit_sound(&mut hook, &config, &NoteKind::Click, left).map(|it| it.amplifier);
+        assert!((played.unwrap() - 0.4).abs() < 1e-6);
+        // too quiet to be played, but still passed on
+        let quiet = HitSoundParams { volume: 0., pan: 0.5 };
+        assert!(hit_sound(&mut hook, &config, &NoteKind::Click, quiet).is_none());
+        assert_eq!(*heard.borrow(), [(0.5, -1.), (0., 0.5)]);
+    }
+
+    #[test]
+    fn invisible_notes_are_judged() {
+        let mut chart = chart("n1 0 1 0 1 0\nn1 0 2 0 1 0\n");
+        chart.lines[0].notes[0].invisible = true;
+        assert!(!chart.lines[0].notes[0].drawn_at(0.5));
+        let mut judge = Judge::new(&chart);
+        assert_eq!(judge.notes[0].0.len(), 2);
+        hit(&mut judge, &mut chart, 1);
+        // missing the unseen note still costs the full score
+        assert!(judge.score() < 1000000);
+        hit(&mut judge, &mut chart, 0);
+        assert_eq!(judge.combo(), 2);
+        assert_eq!(judge.score(), 1000000);
+    }
+
+    #[test]
+    fn offset_shifts_judge_timing() {
+        let mut chart = chart("n1 0 1 0 1 0\n");
+        // half a second into the music, right at the note
+        let dt = |chart: &Chart| note_dt(&chart.lines[0].notes[0], 0., chart.time_at(0.5, 0.) as f32);
+        assert_eq!(dt(&chart), 0.);
+        // the note comes later in the music, so the same tap is early now
+        *chart.offset_mut() = 0.1;
+        assert!((dt(&chart) + 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn disabled_lines_are_not_judged() {
+        let mut chart = chart("n1 0 2 0 1 0\nn1 1 1 0 1 0\n");
+        let judge = Judge::new(&chart);
+        assert_eq!(next_key_note(&chart, &judge.notes, false, &[]), Some((1, 0)));
+        chart.lines[1].notes_disabled = true;
+        // the earlier note is skipped for the one of the enabled line
+        assert_eq!(next_key_note(&chart, &judge.notes, false, &[]), Some((0, 0)));
+        chart.lines[0].notes_disabled = true;
+        assert_eq!(next_key_note(&chart, &judge.notes, false, &[]), None);
+    }
 }
@@ -1,3 +1,9 @@
+mod archive;
+pub use archive::{parse_archive, ArchiveChart};
+
+mod bms;
+pub use bms::{parse_bms, parse_bms_difficulties};
+
 mod extra;
 pub use extra::parse_extra;
 
@@ -5,23 +11,130 @@ mod pec;
 pub use pec::parse_pec;
 
 mod pgr;
-pub use pgr::parse_phigros;
+pub use pgr::{check_phigros_floor_positions, parse_phigros, parse_phigros_incremental, parse_phigros_with, FloorPositionMismatch};
 
 mod rpe;
-pub use rpe::{parse_rpe, RPE_HEIGHT, RPE_WIDTH};
+pub use rpe::{dump_rpe, parse_rpe, parse_rpe_with, RPE_HEIGHT, RPE_WIDTH};
 
-fn process_lines(v: &mut [crate::core::JudgeLine]) {
+use std::time::{Duration, Instant};
+
+/// Options for [`parse_rpe_with`] and [`parse_phigros_with`].
+#[derive(Clone, Debug, Default)]
+pub struct ParseOptions {
+    /// Whether to measure how long each stage of parsing takes, see [`ParseTiming`].
+    pub timing: bool,
+}
+
+/// Time spent in each stage of parsing a chart, for profiling large charts.
+#[derive(Clone, Debug, Default)]
+pub struct ParseTiming {
+    /// Deserializing the JSON source.
+    pub deserialize: Duration,
+    /// Turning events and notes into judge lines.
+    pub events: Duration,
+    /// Post-processing the lines (anchors, multiple hints).
+    pub process_lines: Duration,
+    pub total: Duration,
+}
+
+struct ParseTimer {
+    start: Option<Instant>,
+    last: Option<Instant>,
+    timing: ParseTiming,
+}
+
+impl ParseTimer {
+    fn new(options: &ParseOptions) -> Self {
+        // Instant is not available on every platform, so only touch it when asked to
+        let start = options.timing.then(Instant::now);
+        Self {
+            start,
+            last: start,
+            timing: ParseTiming::default(),
+        }
+    }
+
+    fn lap(&mut self, stage: impl FnOnce(&mut ParseTiming) -> &mut Duration) {
+        if let Some(last) = &mut self.last {
+            let now = Instant::now();
+            *stage(&mut self.timing) += now - *last;
+            *last = now;
+        }
+    }
+
+    fn finish(mut self) -> Option<ParseTiming> {
+        let start = self.start?;
+        self.timing.total = start.elapsed();
+        Some(self.timing)
+    }
+}
+
+/// Picks the difficulty named `name` (case-insensitively) out of the charts of a multi-difficulty file, like the ones given
+/// by [`parse_bms_difficulties`].
+pub fn select_difficulty(charts: Vec<(String, crate::core::Chart)>, name: &str) -> Option<crate::core::Chart> {
+    charts.into_iter().find(|it| it.0.eq_ignore_ascii_case(name)).map(|it| it.1)
+}
+
+/// Strips what some tools leave around chart files: a leading UTF-8 BOM, and trailing whitespace or null bytes.
+fn clean_source(source: &str) -> &str {
+    source
+        .strip_prefix('\u{feff}')
+        .unwrap_or(source)
+        .trim_end_matches(|c: char| c.is_whitespace() || c == '\0')
+}
+
+fn time_orders(v: &[crate::core::JudgeLine]) -> Vec<Vec<usize>> {
     use crate::ext::NotNanExt;
-    let mut times = Vec::new();
-    // TODO optimize using k-merge sort
-    let sorts = v
-        .iter()
+    v.iter()
         .map(|line| {
             let mut idx: Vec<usize> = (0..line.notes.len()).collect();
             idx.sort_by_key(|id| line.notes[*id].time.not_nan());
             idx
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
+
+fn resolve_anchors(v: &mut [crate::core::JudgeLine]) {
+    use crate::core::{JudgeLineCache, NoteKind};
+    let sorts = time_orders(v);
+    let mut changed = vec![false; v.len()];
+    for line_id in 0..v.len() {
+        for note_id in 0..v[line_id].notes.len() {
+            let Some((target_line, k)) = v[line_id].notes[note_id].anchor else {
+                continue;
+            };
+            let Some(target) = sorts.get(target_line).and_then(|it| it.get(k)) else {
+                tracing::warn!("invalid anchor ({target_line}, {k}), ignoring");
+                continue;
+            };
+            let time = v[target_line].notes[*target].time;
+            let line = &mut v[line_id];
+            let note = &mut line.notes[note_id];
+            let delta = time - note.time;
+            note.time = time;
+            line.height.set_time(time);
+            note.height = line.height.now();
+            if let NoteKind::Hold { end_time, end_height, .. } = &mut note.kind {
+                *end_time += delta;
+                line.height.set_time(*end_time);
+                *end_height = line.height.now();
+            }
+            changed[line_id] = true;
+        }
+    }
+    for (line, changed) in v.iter_mut().zip(changed) {
+        if changed {
+            line.cache = JudgeLineCache::new(&mut line.notes);
+        }
+    }
+}
+
+fn process_lines(v: &mut [crate::core::JudgeLine]) {
+    use crate::ext::NotNanExt;
+    resolve_anchors(v);
+    let mut times = Vec::new();
+    // TODO optimize using k-merge sort
+    let sorts = time_orders(v);
     for (line, idx) in v.iter_mut().zip(sorts.iter()) {
         let v = &mut line.notes;
         let mut i = 0;
@@ -83,3 +196,26 @@ pub const RPE_TWEEN_MAP: [crate::core::TweenId; 30] = {
         e(Bounce, InOut), e(Elastic, InOut),
     ]
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::ChartExtra;
+
+    #[test]
+    fn anchored_note_takes_timing() {
+        let mut chart = parse_pec("0\nbp 0 120\nn1 0 3 0 1 0\nn1 0 1 0 1 0\nn1 1 2 0 1 0\n", ChartExtra::default()).unwrap();
+        // the second note of line 0 in time order
+        chart.lines[1].notes[0].anchor = Some((0, 1));
+        resolve_anchors(&mut chart.lines);
+        assert_eq!(chart.lines[1].notes[0].time, 1.5);
+    }
+
+    #[test]
+    fn bom_and_trailing_garbage_are_stripped() {
+        assert_eq!(clean_source("\u{feff}{}\r\n\0\0"), "{}");
+        assert_eq!(clean_source("  {}"), "  {}");
+        let chart = parse_pec("\u{feff}0\r\nbp 0 120\r\nn1 0 1 0 1 0\r\n\0", ChartExtra::default()).unwrap();
+        assert_eq!(chart.lines[0].notes[0].time, 0.5);
+    }
+}
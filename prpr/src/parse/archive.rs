@@ -0,0 +1,81 @@
+use super::{parse_bms, parse_extra, parse_pec, parse_phigros, parse_rpe};
+use crate::{
+    core::{Chart, ChartExtra},
+    fs::{load_info, FileSystem, ZipFileSystem},
+    info::{ChartFormat, ChartInfo},
+};
+use anyhow::{Context, Result};
+
+/// A chart loaded from a chart package, along with the package itself so that the remaining resources (music,
+/// illustration...) can be loaded from it.
+pub struct ArchiveChart {
+    pub info: ChartInfo,
+    pub chart: Chart,
+    pub fs: ZipFileSystem,
+}
+
+/// Loads a chart from a chart package (zip archive) in memory.
+///
+/// The chart file is the one named by the info file if there is one, or otherwise the only `.json` / `.pec` file in the
+/// archive. Resources referenced by the chart, like line textures, are loaded from the archive as well.
+pub async fn parse_archive(bytes: Vec<u8>) -> Result<ArchiveChart> {
+    let mut fs = ZipFileSystem::new(bytes).context("Cannot open chart archive")?;
+    let info = load_info(&mut fs).await.context("Failed to load chart info")?;
+    let extra = if let Ok(bytes) = fs.load_file("extra.json").await {
+        // videos need ffmpeg, which isn't looked up here
+        parse_extra(&String::from_utf8(bytes)?, &mut fs, None)
+            .await
+            .context("Failed to parse extra")?
+    } else {
+        ChartExtra::default()
+    };
+    let text = String::from_utf8(fs.load_file(&info.chart).await.context("Failed to load chart")?)?;
+    let mut chart = match info.format.clone().unwrap_or_else(|| ChartFormat::infer(&text)) {
+        ChartFormat::Rpe => parse_rpe(&text, &mut fs, extra).await,
+        ChartFormat::Pgr => parse_phigros(&text, extra),
+        ChartFormat::Pec => parse_pec(&text, extra),
+        ChartFormat::Bms => parse_bms(&text, extra),
+    }?;
+    chart.settings.hold_partial_cover = info.hold_partial_cover;
+    chart.assign_subdivisions();
+    Ok(ArchiveChart { info, chart, fs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::{write::FileOptions, ZipWriter};
+
+    fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let mut w = ZipWriter::new(Cursor::new(&mut buffer));
+        for (path, content) in files {
+            w.start_file(*path, FileOptions::default()).unwrap();
+            w.write_all(content.as_bytes()).unwrap();
+        }
+        w.finish().unwrap();
+        drop(w);
+        buffer
+    }
+
+    #[test]
+    fn chart_from_archive() {
+        let bytes = zip(&[("chart.pec", "0\nbp 0 120\nn1 0 1 0 1 0\nn1 0 2 0 1 0\n"), ("readme.txt", "")]);
+        let archive = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(parse_archive(bytes))
+            .unwrap();
+        assert_eq!(archive.info.chart, "chart.pec");
+        assert_eq!(archive.chart.lines[0].notes.len(), 2);
+    }
+
+    #[test]
+    fn infer_format() {
+        assert!(matches!(ChartFormat::infer("0\nbp 0 120\n"), ChartFormat::Pec));
+        assert!(matches!(ChartFormat::infer(r#"{"formatVersion": 3, "META": {}}"#), ChartFormat::Pgr));
+        assert!(matches!(ChartFormat::infer(r#"{"META": {}, "BPMList": []}"#), ChartFormat::Rpe));
+    }
+}
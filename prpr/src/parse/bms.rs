@@ -0,0 +1,319 @@
+crate::tl_file!("parser" ptl);
+
+use super::process_lines;
+use crate::{
+    core::{
+        Anim, AnimFloat, AnimVector, BlendMode, BpmList, Chart, ChartExtra, ChartSettings, HitSoundParams, HoldKind, JudgeLine, JudgeLineCache,
+        JudgeLineKind, Keyframe, Note, NoteKind, Object,
+    },
+    ext::NotNanExt,
+    judge::JudgeStatus,
+};
+use anyhow::{Context, Result};
+use std::{cell::RefCell, collections::HashMap};
+use tracing::warn;
+
+/// Height the line scrolls by per second, the same for every BPM.
+const SCROLL_SPEED: f32 = 2.;
+const LINE_Y: f32 = -0.7;
+const LANE_SPACING: f32 = 0.2;
+const DEFAULT_BPM: f32 = 130.;
+
+// visible note channels from left to right, with the scratch of player 1 first and that of player 2 last
+const LANES: [&str; 16] = [
+    "16", "11", "12", "13", "14", "15", "18", "19", "21", "22", "23", "24", "25", "28", "29", "26",
+];
+
+struct BmsObject {
+    measure: usize,
+    // position in the measure, in [0, 1)
+    fraction: f32,
+    value: u32,
+}
+
+/// The long note channel (`5x` / `6x`) corresponding to a visible note channel (`1x` / `2x`).
+fn ln_channel(lane: &str) -> String {
+    format!("{}{}", (lane.as_bytes()[0] + 4) as char, &lane[1..])
+}
+
+/// Name of a `#DIFFICULTY` value.
+fn difficulty_name(value: &str) -> String {
+    match value {
+        "1" => "BEGINNER",
+        "2" => "NORMAL",
+        "3" => "HYPER",
+        "4" => "ANOTHER",
+        "5" => "INSANE",
+        _ => value,
+    }
+    .to_owned()
+}
+
+/// Parses a BMS file bundling several difficulties, each one starting with its `#DIFFICULTY` header. Everything before
+/// the first `#DIFFICULTY` (e.g. `#BPM`) is shared by all of them. A file without any `#DIFFICULTY` gives a single chart
+/// with an empty name.
+///
+/// Charts are named after their difficulty and come with an empty [`ChartExtra`], see [`super::select_difficulty`].
+pub fn parse_bms_difficulties(source: &str) -> Result<Vec<(String, Chart)>> {
+    let source = super::clean_source(source);
+    let mut shared = String::new();
+    let mut sections: Vec<(String, String)> = Vec::new();
+    for line in source.lines() {
+        let header = line.trim().strip_prefix('#').and_then(|it| {
+            let (key, value) = it.split_once(char::is_whitespace)?;
+            key.eq_ignore_ascii_case("DIFFICULTY").then(|| value.trim())
+        });
+        if let Some(value) = header {
+            sections.push((difficulty_name(value), shared.clone()));
+        }
+        let text = sections.last_mut().map_or(&mut shared, |it| &mut it.1);
+        text.push_str(line);
+        text.push('\n');
+    }
+    if sections.is_empty() {
+        sections.push((String::new(), shared));
+    }
+    sections
+        .into_iter()
+        .map(|(name, text)| {
+            let chart = parse_bms(&text, ChartExtra::default()).with_context(|| ptl!("bms-difficulty-location", "name" => name.clone()))?;
+            Ok((name, chart))
+        })
+        .collect()
+}
+
+/// Parses a BMS (`.bms` / `.bme`) chart. Lanes are laid out on a single judge line, `#WAVxx` keysounds are ignored and
+/// `#RANDOM` blocks aren't supported.
+pub fn parse_bms(source: &str, extra: ChartExtra) -> Result<Chart> {
+    let source = super::clean_source(source);
+    let mut bpm = DEFAULT_BPM;
+    let mut bpm_defs = HashMap::new();
+    let mut ln_obj = None;
+    let mut measure_lengths = HashMap::<usize, f32>::new();
+    let mut channels = HashMap::<String, Vec<BmsObject>>::new();
+    for (line_id, line) in source.lines().enumerate() {
+        let Some(line) = line.trim().strip_prefix('#') else {
+            continue;
+        };
+        let line_id = line_id + 1;
+        if let Some((head, data)) = line.split_once(':') {
+            if head.len() == 5 && head.is_ascii() && head.bytes().take(3).all(|it| it.is_ascii_digit()) {
+                let measure: usize = head[..3].parse()?;
+                let channel = head[3..].to_ascii_uppercase();
+                let data = data.trim();
+                if channel == "02" {
+                    measure_lengths.insert(measure, data.parse().with_context(|| ptl!("bms-invalid-measure-length", "line" => line_id))?);
+                    continue;
+                }
+                let pairs: Vec<_> = data.as_bytes().chunks(2).collect();
+                for (index, pair) in pairs.iter().enumerate() {
+                    let pair = std::str::from_utf8(pair)?;
+                    // BPM changes of channel 03 are written in hexadecimal, everything else is base 36
+                    let value = u32::from_str_radix(pair, if channel == "03" { 16 } else { 36 })
+                        .with_context(|| ptl!("bms-invalid-object", "line" => line_id, "object" => pair))?;
+                    if value != 0 {
+                        channels.entry(channel.clone()).or_default().push(BmsObject {
+                            measure,
+                            fraction: index as f32 / pairs.len() as f32,
+                            value,
+                        });
+                    }
+                }
+                continue;
+            }
+        }
+        let (key, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let key = key.to_ascii_uppercase();
+        let value = value.trim();
+        if key == "BPM" {
+            bpm = value.parse().with_context(|| ptl!("bms-invalid-bpm", "line" => line_id))?;
+        } else if let Some(id) = key.strip_prefix("BPM") {
+            let id = u32::from_str_radix(id, 36).with_context(|| ptl!("bms-invalid-bpm", "line" => line_id))?;
+            bpm_defs.insert(id, value.parse::<f32>().with_context(|| ptl!("bms-invalid-bpm", "line" => line_id))?);
+        } else if key == "LNOBJ" {
+            ln_obj = u32::from_str_radix(value, 36).ok();
+        }
+    }
+
+    let measure_length = |measure: usize| measure_lengths.get(&measure).copied().unwrap_or(1.);
+    let measure_count = channels.values().flatten().map(|it| it.measure + 1).max().unwrap_or_default();
+    let measure_beats: Vec<_> = (0..measure_count)
+        .scan(0., |beats, measure| {
+            let start = *beats;
+            *beats += 4. * measure_length(measure);
+            Some(start)
+        })
+        .collect();
+    let beat_of = |obj: &BmsObject| measure_beats[obj.measure] + 4. * measure_length(obj.measure) * obj.fraction;
+
+    let mut bpm_changes = vec![(0., bpm)];
+    bpm_changes.extend(channels.get("03").into_iter().flatten().map(|obj| (beat_of(obj), obj.value as f32)));
+    for obj in channels.get("08").into_iter().flatten() {
+        if let Some(bpm) = bpm_defs.get(&obj.value) {
+            bpm_changes.push((beat_of(obj), *bpm));
+        } else {
+            warn!("undefined BPM reference in measure {}, ignoring", obj.measure);
+        }
+    }
+    // stable, so that a change at the very beginning comes after the initial BPM and overrides it
+    bpm_changes.sort_by_key(|it| it.0.not_nan());
+    let mut r = BpmList::new(bpm_changes);
+
+    let lanes: Vec<_> = LANES
+        .iter()
+        .filter(|lane| channels.contains_key(**lane) || channels.contains_key(&ln_channel(lane)))
+        .collect();
+    let spacing = LANE_SPACING.min(1.8 / lanes.len().max(1) as f32);
+    let mut notes = Vec::new();
+    for (index, lane) in lanes.iter().enumerate() {
+        let mut objects = |channel: &str| {
+            let mut objects: Vec<_> = channels
+                .get(channel)
+                .into_iter()
+                .flatten()
+                .map(|obj| (r.time_beats(beat_of(obj)), obj.value))
+                .collect();
+            objects.sort_by_key(|it| it.0.not_nan());
+            objects
+        };
+        // (time, end time of long notes)
+        let mut lane_notes: Vec<(f32, Option<f32>)> = Vec::new();
+        for (time, value) in objects(lane) {
+            if ln_obj == Some(value) {
+                if let Some(last) = lane_notes.last_mut() {
+                    last.1 = Some(time);
+                }
+            } else {
+                lane_notes.push((time, None));
+            }
+        }
+        let long_notes = objects(&ln_channel(lane));
+        if long_notes.len() % 2 != 0 {
+            warn!("unpaired long note in channel {}, ignoring", ln_channel(lane));
+        }
+        lane_notes.extend(long_notes.chunks_exact(2).map(|it| (it[0].0, Some(it[1].0))));
+        let x = (index as f32 - (lanes.len() - 1) as f32 / 2.) * spacing;
+        notes.extend(lane_notes.into_iter().map(|(time, end_time)| Note {
+            object: Object {
+                translation: AnimVector(AnimFloat::fixed(x), AnimFloat::default()),
+                ..Default::default()
+            },
+            kind: match end_time {
+                Some(end_time) => NoteKind::Hold {
+                    end_time,
+                    end_height: end_time * SCROLL_SPEED,
+                    kind: HoldKind::Normal,
+                },
+                None => NoteKind::Click,
+            },
+            time,
+            height: time * SCROLL_SPEED,
+            speed: 1.,
+            last_real_time: 0.,
+            judged_time: None,
+            approach_inside: None,
+            subdivision: 0,
+            anchor: None,
+            link: None,
+            weight: 1.,
+            flick_dir: None,
+            z: 0,
+            no_autoplay: false,
+            spawn_time: None,
+            invisible: false,
+            release_grace: None,
+            sound: HitSoundParams::default(),
+
+            above: true,
+            multiple_hint: false,
+            fake: false,
+            judge: JudgeStatus::NotJudged,
+        }));
+    }
+
+    let max_time = *notes
+        .iter()
+        .map(|it| match it.kind {
+            NoteKind::Hold { end_time, .. } => end_time.not_nan(),
+            _ => it.time.not_nan(),
+        })
+        .max()
+        .unwrap_or_default()
+        + 1.;
+    let cache = JudgeLineCache::new(&mut notes);
+    let mut lines = vec![JudgeLine {
+        object: Object {
+            translation: AnimVector(AnimFloat::default(), AnimFloat::fixed(LINE_Y)),
+            ..Default::default()
+        },
+        ctrl_obj: RefCell::default(),
+        kind: JudgeLineKind::Normal,
+        height: AnimFloat::new(vec![Keyframe::new(0., 0., 2), Keyframe::new(max_time, max_time * SCROLL_SPEED, 0)]),
+        incline: AnimFloat::default(),
+        notes,
+        color: Anim::default(),
+        parent: None,
+        z_index: 0,
+        z_index_anim: AnimFloat::default(),
+        show_below: false,
+        attach_ui: None,
+        blend_mode: BlendMode::Normal,
+        spawn_from: None,
+        time_offset: 0.,
+        notes_disabled: false,
+        hide_when_empty: false,
+        tile_texture: false,
+        mirrored: false,
+        clip_to_playfield: false,
+        appear_distance: None,
+        reverse_notes: false,
+        attach_note: None,
+        cull_back_face: false,
+        note_speed_scale: 1.,
+        note_offset: 0.,
+        visible: Anim::default(),
+        note_tints: (None, None),
+
+        cache,
+    }];
+    process_lines(&mut lines);
+    Ok(Chart::new(0., lines, r, ChartSettings::default(), extra))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BMS: &str = "#PLAYER 1\n#BPM 120\n#00011:01010101\n#00103:F0\n#00111:0101\n";
+
+    #[test]
+    fn bpm_change() {
+        let chart = parse_bms(BMS, ChartExtra::default()).unwrap();
+        let mut times: Vec<_> = chart.lines[0].notes.iter().map(|it| it.time).collect();
+        times.sort_by_key(|it| it.not_nan());
+        // 4 beats at 120 BPM, then 240 BPM from the second measure on
+        let expected = [0., 0.5, 1., 1.5, 2., 2.5];
+        assert_eq!(times.len(), expected.len());
+        for (time, expected) in times.into_iter().zip(expected) {
+            assert!((time - expected).abs() < 1e-4, "{time} vs {expected}");
+        }
+    }
+
+    #[test]
+    fn difficulties_share_headers() {
+        let source = "#PLAYER 1\n#BPM 120\n#DIFFICULTY 2\n#00011:01\n#DIFFICULTY HARD\n#00011:0101\n";
+        let charts = parse_bms_difficulties(source).unwrap();
+        let names: Vec<_> = charts.iter().map(|it| it.0.as_str()).collect();
+        assert_eq!(names, ["NORMAL", "HARD"]);
+        assert_eq!(charts[1].1.lines[0].notes.len(), 2);
+        // the shared BPM applies to both
+        let last = charts[1].1.lines[0].notes.iter().map(|it| it.time).fold(0., f32::max);
+        assert!((last - 1.).abs() < 1e-4);
+        let normal = crate::parse::select_difficulty(charts, "normal").unwrap();
+        assert_eq!(normal.lines[0].notes.len(), 1);
+
+        let single = parse_bms_difficulties(BMS).unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].0, "");
+    }
+}
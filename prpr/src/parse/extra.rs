@@ -1,6 +1,6 @@
 use crate::{
-    core::{Anim, BpmList, ChartExtra, ClampedTween, Effect, Keyframe, StaticTween, Triple, Tweenable, Uniform, Video, EPS},
-    ext::ScaleType,
+    core::{Anim, BpmList, ChartExtra, ClampedTween, Effect, Flash, Keyframe, StaticTween, Triple, Tweenable, Uniform, Video, EPS},
+    ext::{NotNanExt, ScaleType},
     fs::FileSystem,
 };
 use anyhow::{anyhow, Context, Result};
@@ -69,6 +69,7 @@ impl<V> ExtAnim<V> {
                                 Rc::new(ClampedTween::new(tween, e.easing_left..e.easing_right))
                             }
                         },
+                        beat: None,
                     });
                     kfs.push(Keyframe::new(r.time(&e.end_time), e.end.into(), 0));
                 }
@@ -133,6 +134,27 @@ struct ExtVideo {
     dim: ExtAnim<f32>,
 }
 
+#[derive(Deserialize)]
+struct ExtFlash {
+    time: Triple,
+    duration: f32,
+    color: [u8; 4],
+    #[serde(default)]
+    behind: bool,
+}
+
+#[derive(Deserialize)]
+struct ExtMarker {
+    time: Triple,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ExtSection {
+    start: Triple,
+    end: Triple,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Extra {
@@ -141,6 +163,12 @@ struct Extra {
     effects: Vec<ExtEffect>,
     #[serde(default)]
     videos: Vec<ExtVideo>,
+    #[serde(default)]
+    flashes: Vec<ExtFlash>,
+    #[serde(default)]
+    markers: Vec<ExtMarker>,
+    #[serde(default)]
+    auto_sections: Vec<ExtSection>,
 }
 
 async fn parse_effect(r: &mut BpmList, rpe: ExtEffect, fs: &mut dyn FileSystem) -> Result<Effect> {
@@ -171,6 +199,7 @@ async fn parse_effect(r: &mut BpmList, rpe: ExtEffect, fs: &mut dyn FileSystem)
 }
 
 pub async fn parse_extra(source: &str, fs: &mut dyn FileSystem, ffmpeg: Option<&Path>) -> Result<ChartExtra> {
+    let source = super::clean_source(source);
     let ext: Extra = serde_json::from_str(source).context("Failed to parse JSON")?;
     let mut r: BpmList = ext.bpm.into();
     let mut effects = Vec::new();
@@ -197,9 +226,50 @@ pub async fn parse_extra(source: &str, fs: &mut dyn FileSystem, ffmpeg: Option<&
             );
         }
     }
+    let flashes = ext
+        .flashes
+        .into_iter()
+        .map(|it| Flash {
+            time: r.time(&it.time),
+            duration: it.duration,
+            color: Color::from_rgba(it.color[0], it.color[1], it.color[2], it.color[3]),
+            behind: it.behind,
+        })
+        .collect();
+    let mut markers: Vec<_> = ext.markers.into_iter().map(|it| (r.time(&it.time), it.name)).collect();
+    markers.sort_by_key(|it| it.0.not_nan());
+    let auto_sections = ext.auto_sections.into_iter().map(|it| r.time(&it.start)..r.time(&it.end)).collect();
     Ok(ChartExtra {
         effects,
         global_effects,
         videos,
+        flashes,
+        markers,
+        auto_sections,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fs::fs_from_assets, parse::parse_pec};
+
+    fn parse(source: &str) -> ChartExtra {
+        let mut fs = fs_from_assets("").unwrap();
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(parse_extra(source, fs.as_mut(), None))
+            .unwrap()
+    }
+
+    #[test]
+    fn markers_are_sorted() {
+        let extra = parse(r#"{ "bpm": 120, "markers": [{ "time": [4, 0, 1], "name": "chorus" }, { "time": [1, 1, 2], "name": "intro" }] }"#);
+        assert_eq!(extra.markers, [(0.75, "intro".to_owned()), (2., "chorus".to_owned())]);
+        let chart = parse_pec("0\nbp 0 120\nn1 0 1 0 1 0\n", extra).unwrap();
+        assert_eq!(chart.section_range(0), Some(0.75..2.));
+        assert_eq!(chart.section_range(1), Some(2.0..f32::INFINITY));
+        assert_eq!(chart.section_range(2), None);
+    }
+}
@@ -3,8 +3,8 @@ crate::tl_file!("parser" ptl);
 use super::{process_lines, RPE_TWEEN_MAP};
 use crate::{
     core::{
-        Anim, AnimFloat, AnimVector, BpmList, Chart, ChartExtra, ChartSettings, JudgeLine, JudgeLineCache, JudgeLineKind, Keyframe, Note, NoteKind,
-        Object, TweenId, EPS,
+        Anim, AnimFloat, AnimVector, BlendMode, BpmList, Chart, ChartExtra, ChartSettings, HitSoundParams, HoldKind, JudgeLine, JudgeLineCache,
+        JudgeLineKind, Keyframe, Note, NoteKind, Object, TweenId, EPS,
     },
     ext::NotNanExt,
     judge::{HitSound, JudgeStatus},
@@ -120,7 +120,8 @@ fn parse_events(mut events: Vec<PECEvent>, id: usize, desc: &str) -> Result<Anim
 }
 
 fn parse_speed_events(mut pec: Vec<(f32, f32)>, max_time: f32) -> AnimFloat {
-    if pec[0].0 >= EPS {
+    // lines without speed events don't scroll
+    if pec.first().map_or(true, |it| it.0 >= EPS) {
         pec.insert(0, (0., 0.));
     }
     let mut kfs = Vec::new();
@@ -143,7 +144,7 @@ fn parse_judge_line(mut pec: PECJudgeLine, id: usize, max_time: f32) -> Result<J
         for note in notes {
             height.set_time(note.time);
             note.height = height.now();
-            if let NoteKind::Hold { end_time, end_height } = &mut note.kind {
+            if let NoteKind::Hold { end_time, end_height, .. } = &mut note.kind {
                 height.set_time(*end_time);
                 *end_height = height.now();
             }
@@ -173,14 +174,32 @@ fn parse_judge_line(mut pec: PECJudgeLine, id: usize, max_time: f32) -> Result<J
         color: Anim::default(),
         parent: None,
         z_index: 0,
+        z_index_anim: AnimFloat::default(),
         show_below: false,
         attach_ui: None,
+        blend_mode: BlendMode::Normal,
+        spawn_from: None,
+        time_offset: 0.,
+        notes_disabled: false,
+        hide_when_empty: false,
+        tile_texture: false,
+        mirrored: false,
+        clip_to_playfield: false,
+        appear_distance: None,
+        reverse_notes: false,
+        attach_note: None,
+        cull_back_face: false,
+        note_speed_scale: 1.,
+        note_offset: 0.,
+        visible: Anim::default(),
+        note_tints: (None, None),
 
         cache,
     })
 }
 
 pub fn parse_pec(source: &str, extra: ChartExtra) -> Result<Chart> {
+    let source = super::clean_source(source);
     let mut offset = None;
     let mut r = None;
     let mut lines = Vec::new();
@@ -244,6 +263,7 @@ pub fn parse_pec(source: &str, extra: ChartExtra) -> Result<Chart> {
                         '2' => NoteKind::Hold {
                             end_time: it.take_time(r)?,
                             end_height: 0.0,
+                            kind: HoldKind::Normal,
                         },
                         '3' => NoteKind::Flick,
                         '4' => NoteKind::Drag,
@@ -268,6 +288,20 @@ pub fn parse_pec(source: &str, extra: ChartExtra) -> Result<Chart> {
                         time,
                         height: 0.0,
                         speed: 1.0,
+                        last_real_time: 0.0,
+                        judged_time: None,
+                        approach_inside: None,
+                        subdivision: 0,
+                        anchor: None,
+                        link: None,
+                        weight: 1.,
+                        flick_dir: None,
+                        z: 0,
+                        no_autoplay: false,
+                        spawn_time: None,
+                        invisible: false,
+                        release_grace: None,
+                        sound: HitSoundParams::default(),
 
                         above,
                         multiple_hint: false,
@@ -379,4 +413,4 @@ pub fn parse_pec(source: &str, extra: ChartExtra) -> Result<Chart> {
         extra,
         HashMap::new(),
     ))
-}
\ No newline at end of file
+}
@@ -1,16 +1,20 @@
 crate::tl_file!("parser" ptl);
 
-use super::process_lines;
+use super::{process_lines, ParseOptions, ParseTimer, ParseTiming};
 use crate::{
     core::{
-        Anim, AnimFloat, AnimVector, BpmList, Chart, ChartExtra, ChartSettings, JudgeLine, JudgeLineCache, JudgeLineKind, Keyframe, Note, NoteKind,
-        Object, HEIGHT_RATIO,
+        Anim, AnimFloat, AnimVector, BlendMode, BpmList, Chart, ChartExtra, ChartSettings, HitSoundParams, HoldKind, JudgeLine, JudgeLineCache,
+        JudgeLineKind, Keyframe, Note, NoteKind, Object, HEIGHT_RATIO,
     },
     ext::NotNanExt,
     judge::{HitSound, JudgeStatus},
 };
 use anyhow::{Context, Result};
-use serde::Deserialize;
+use macroquad::prelude::Color;
+use serde::{
+    de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
 use std::{cell::RefCell, collections::HashMap};
 use tracing::warn;
 
@@ -68,6 +72,38 @@ struct PgrJudgeLine {
 struct PgrChart {
     offset: f32,
     judge_line_list: Vec<PgrJudgeLine>,
+    #[serde(rename = "META", default)]
+    meta: PgrMeta,
+}
+
+/// Optional chart settings some exports embed. Unknown fields are ignored.
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PgrMeta {
+    hold_partial_cover: Option<bool>,
+    note_scale: Option<f32>,
+    /// RGB of lines without color events
+    line_color: Option<[u8; 3]>,
+    countdown: Option<u32>,
+}
+
+impl PgrMeta {
+    fn settings(self) -> ChartSettings {
+        let mut settings = ChartSettings::default();
+        if let Some(hold_partial_cover) = self.hold_partial_cover {
+            settings.hold_partial_cover = hold_partial_cover;
+        }
+        if let Some(note_scale) = self.note_scale {
+            settings.note_scale = note_scale;
+        }
+        if let Some([r, g, b]) = self.line_color {
+            settings.line_color = Some(Color::from_rgba(r, g, b, 255));
+        }
+        if let Some(countdown) = self.countdown {
+            settings.countdown = countdown;
+        }
+        settings
+    }
 }
 
 macro_rules! validate_events {
@@ -150,6 +186,31 @@ fn parse_move_events(r: f32, mut pgr: Vec<PgrEvent>) -> Result<AnimVector> {
     Ok(AnimVector(AnimFloat::new(kf1), AnimFloat::new(kf2)))
 }
 
+/// Tolerance (in floor position units, relative to the magnitude for large ones) of [`floor_position_mismatch`].
+const FLOOR_POSITION_TOLERANCE: f32 = 1e-2;
+
+/// The floor position integrated from the speed events at a note's time, if it's further than [`FLOOR_POSITION_TOLERANCE`]
+/// from the one stored with the note. Malformed charts have notes drawn at the wrong distance this way.
+fn floor_position_mismatch(floor_position: f32, height: &mut AnimFloat, time: f32) -> Option<f32> {
+    height.set_time(time);
+    let integrated = height.now() * HEIGHT_RATIO;
+    let diff = floor_position - integrated;
+    (diff.abs() > FLOOR_POSITION_TOLERANCE * integrated.abs().max(1.)).then_some(integrated)
+}
+
+/// A note whose stored floor position disagrees with its line's speed events, see [`check_phigros_floor_positions`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct FloorPositionMismatch {
+    pub line: usize,
+    pub above: bool,
+    /// Index of the note in `notesAbove` or `notesBelow` of the source.
+    pub note: usize,
+    /// The floor position integrated from the speed events.
+    pub expected: f32,
+    /// The floor position stored with the note.
+    pub actual: f32,
+}
+
 fn parse_notes(r: f32, mut pgr: Vec<PgrNote>, speed: &mut AnimFloat, height: &mut AnimFloat, above: bool) -> Result<Vec<Note>> {
     // is_sorted is unstable...
     if pgr.is_empty() {
@@ -166,12 +227,19 @@ fn parse_notes(r: f32, mut pgr: Vec<PgrNote>, speed: &mut AnimFloat, height: &mu
                     let end_time = (pgr.time + pgr.hold_time) * r;
                     height.set_time(end_time);
                     let end_height = height.now();
-                    NoteKind::Hold { end_time, end_height }
+                    NoteKind::Hold {
+                        end_time,
+                        end_height,
+                        kind: HoldKind::Normal,
+                    }
                 }
                 4 => NoteKind::Flick,
                 _ => ptl!(bail "unknown-note-type", "type" => pgr.kind),
             };
             let hitsound = HitSound::default_from_kind(&kind);
+            if let Some(expected) = floor_position_mismatch(pgr.floor_position, height, time) {
+                warn!("floor position of note at {time}s is {}, but {expected} from its speed events", pgr.floor_position);
+            }
             Ok(Note {
                 object: Object {
                     translation: AnimVector(AnimFloat::fixed(pgr.position_x * (2. * 9. / 160.)), AnimFloat::default()),
@@ -187,6 +255,20 @@ fn parse_notes(r: f32, mut pgr: Vec<PgrNote>, speed: &mut AnimFloat, height: &mu
                     pgr.speed
                 },
                 height: pgr.floor_position / HEIGHT_RATIO,
+                last_real_time: 0.0,
+                judged_time: None,
+                approach_inside: None,
+                subdivision: 0,
+                anchor: None,
+                link: None,
+                weight: 1.,
+                flick_dir: None,
+                z: 0,
+                no_autoplay: false,
+                spawn_time: None,
+                invisible: false,
+                release_grace: None,
+                sound: HitSoundParams::default(),
 
                 above,
                 multiple_hint: false,
@@ -220,15 +302,174 @@ fn parse_judge_line(pgr: PgrJudgeLine, max_time: f32) -> Result<JudgeLine> {
         color: Anim::default(),
         parent: None,
         z_index: 0,
+        z_index_anim: AnimFloat::default(),
         show_below: true,
         attach_ui: None,
+        blend_mode: BlendMode::Normal,
+        spawn_from: None,
+        time_offset: 0.,
+        notes_disabled: false,
+        hide_when_empty: false,
+        tile_texture: false,
+        mirrored: false,
+        clip_to_playfield: false,
+        appear_distance: None,
+        reverse_notes: false,
+        attach_note: None,
+        cull_back_face: false,
+        note_speed_scale: 1.,
+        note_offset: 0.,
+        visible: Anim::default(),
+        note_tints: (None, None),
 
         cache,
     })
 }
 
+fn line_max_time(pgr: &PgrJudgeLine) -> f32 {
+    *pgr.notes_above
+        .iter()
+        .chain(pgr.notes_below.iter())
+        .map(|note| note.time.not_nan())
+        .max()
+        .unwrap_or_default()
+        * (60. / pgr.bpm / 32.)
+}
+
+/// Extends the height animation of a line parsed with a smaller `max_time`, so that it matches the batch parse.
+/// `last_speed` is the value of the line's last speed event.
+fn extend_judge_line(line: &mut JudgeLine, parsed_max_time: f32, max_time: f32, last_speed: f32) {
+    if max_time <= parsed_max_time {
+        return;
+    }
+    let kfs = &mut line.height.keyframes;
+    let n = kfs.len();
+    if n >= 2 {
+        // the last speed event may start after `parsed_max_time`, so the final keyframe can't be extrapolated from the
+        // previous one, and is computed the way `parse_speed_events` does instead
+        let (t0, v0) = (kfs[n - 2].time, kfs[n - 2].value);
+        kfs[n - 1].time = max_time;
+        kfs[n - 1].value = v0 + (max_time - t0) * last_speed / HEIGHT_RATIO;
+        // forces the cursor to be recomputed
+        line.height.time = f32::NAN;
+        line.height.cursor = 0;
+    }
+    for note in &mut line.notes {
+        if let NoteKind::Hold { end_time, end_height, .. } = &mut note.kind {
+            if *end_time > parsed_max_time {
+                line.height.set_time(*end_time);
+                *end_height = line.height.now();
+            }
+        }
+    }
+}
+
+struct PgrLinesSeed<'a, F> {
+    lines: &'a mut Vec<(JudgeLine, f32, f32)>,
+    on_line: F,
+}
+
+impl<'de, 'a, F: FnMut(usize)> DeserializeSeed<'de> for PgrLinesSeed<'a, F> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a, F: FnMut(usize)> Visitor<'de> for PgrLinesSeed<'a, F> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a list of judge lines")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<Self::Value, A::Error> {
+        while let Some(pgr) = seq.next_element::<PgrJudgeLine>()? {
+            let id = self.lines.len();
+            let max_time = line_max_time(&pgr) + 1.;
+            // events with invalid ranges are dropped when parsing
+            let last_speed = pgr.speed_events.iter().rev().find(|it| it.start_time <= it.end_time).map_or(0., |it| it.value);
+            let line = parse_judge_line(pgr, max_time)
+                .with_context(|| ptl!("judge-line-location", "jlid" => id))
+                .map_err(|err| <A::Error as de::Error>::custom(format!("{err:?}")))?;
+            self.lines.push((line, max_time, last_speed));
+            (self.on_line)(id);
+        }
+        Ok(())
+    }
+}
+
+struct PgrChartVisitor<'a, F> {
+    lines: &'a mut Vec<(JudgeLine, f32, f32)>,
+    on_line: F,
+}
+
+impl<'de, 'a, F: FnMut(usize)> Visitor<'de> for PgrChartVisitor<'a, F> {
+    type Value = (f32, PgrMeta);
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a phigros chart")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut offset = None;
+        let mut meta = PgrMeta::default();
+        let mut on_line = Some(self.on_line);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "offset" => offset = Some(map.next_value()?),
+                "META" => meta = map.next_value()?,
+                "judgeLineList" => {
+                    let Some(on_line) = on_line.take() else {
+                        return Err(de::Error::duplicate_field("judgeLineList"));
+                    };
+                    map.next_value_seed(PgrLinesSeed {
+                        lines: &mut *self.lines,
+                        on_line,
+                    })?;
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok((offset.ok_or_else(|| de::Error::missing_field("offset"))?, meta))
+    }
+}
+
+/// Parses a phigros chart line by line, calling `on_line` with the index of every judge line parsed.
+///
+/// Unlike [`parse_phigros`], judge lines are parsed as soon as they are deserialized, so the raw chart
+/// is never held in memory as a whole. The resulting chart is the same as the one from [`parse_phigros`].
+pub fn parse_phigros_incremental(source: &str, extra: ChartExtra, on_line: impl FnMut(usize)) -> Result<Chart> {
+    let source = super::clean_source(source);
+    let mut lines = Vec::new();
+    let (offset, meta) = serde_json::Deserializer::from_str(source)
+        .deserialize_map(PgrChartVisitor { lines: &mut lines, on_line })
+        .with_context(|| ptl!("json-parse-failed"))?;
+    let max_time = lines.iter().map(|it| it.1.not_nan()).max().map_or(1., |it| *it);
+    let mut lines = lines
+        .into_iter()
+        .map(|(mut line, parsed_max_time, last_speed)| {
+            extend_judge_line(&mut line, parsed_max_time, max_time, last_speed);
+            line
+        })
+        .collect::<Vec<_>>();
+    process_lines(&mut lines);
+    Ok(Chart::new(offset, lines, BpmList::default(), meta.settings(), extra, HashMap::new()))
+}
+
 pub fn parse_phigros(source: &str, extra: ChartExtra) -> Result<Chart> {
+    Ok(parse_phigros_with(source, extra, &ParseOptions::default())?.0)
+}
+
+/// Parses a phigros chart, also returning how long each stage took if [`ParseOptions::timing`] is set.
+pub fn parse_phigros_with(source: &str, extra: ChartExtra, options: &ParseOptions) -> Result<(Chart, Option<ParseTiming>)> {
+    let mut timer = ParseTimer::new(options);
+    let source = super::clean_source(source);
     let pgr: PgrChart = serde_json::from_str(source).with_context(|| ptl!("json-parse-failed"))?;
+    timer.lap(|it| &mut it.deserialize);
     let max_time = *pgr
         .judge_line_list
         .iter()
@@ -250,6 +491,137 @@ pub fn parse_phigros(source: &str, extra: ChartExtra) -> Result<Chart> {
         .enumerate()
         .map(|(id, pgr)| parse_judge_line(pgr, max_time).with_context(|| ptl!("judge-line-location", "jlid" => id)))
         .collect::<Result<Vec<_>>>()?;
+    timer.lap(|it| &mut it.events);
     process_lines(&mut lines);
-    Ok(Chart::new(pgr.offset, lines, BpmList::default(), ChartSettings::default(), extra, HashMap::new()))
-}
\ No newline at end of file
+    timer.lap(|it| &mut it.process_lines);
+    let chart = Chart::new(pgr.offset, lines, BpmList::default(), pgr.meta.settings(), extra, HashMap::new());
+    Ok((chart, timer.finish()))
+}
+
+/// Checks the floor positions stored with the notes of a phigros chart against the ones integrated from the speed events of
+/// their lines, returning the notes that disagree.
+pub fn check_phigros_floor_positions(source: &str) -> Result<Vec<FloorPositionMismatch>> {
+    let source = super::clean_source(source);
+    let pgr: PgrChart = serde_json::from_str(source).with_context(|| ptl!("json-parse-failed"))?;
+    let mut mismatches = Vec::new();
+    for (id, line) in pgr.judge_line_list.into_iter().enumerate() {
+        let r = 60. / line.bpm / 32.;
+        let max_time = line_max_time(&line) + 1.;
+        let (_, mut height) = parse_speed_events(r, line.speed_events, max_time).with_context(|| ptl!("judge-line-location", "jlid" => id))?;
+        for (above, notes) in [(true, &line.notes_above), (false, &line.notes_below)] {
+            for (note, pgr) in notes.iter().enumerate() {
+                if let Some(expected) = floor_position_mismatch(pgr.floor_position, &mut height, pgr.time * r) {
+                    mismatches.push(FloorPositionMismatch {
+                        line: id,
+                        above,
+                        note,
+                        expected,
+                        actual: pgr.floor_position,
+                    });
+                }
+            }
+        }
+    }
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHART: &str = r#"{
+        "formatVersion": 3,
+        "offset": 0,
+        "judgeLineList": [
+            {
+                "bpm": 120,
+                "judgeLineDisappearEvents": [{ "startTime": 0, "endTime": 1e9, "start": 1, "end": 1 }],
+                "judgeLineRotateEvents": [{ "startTime": 0, "endTime": 1e9, "start": 0, "end": 0 }],
+                "judgeLineMoveEvents": [{ "startTime": 0, "endTime": 1e9, "start": 0.5, "end": 0.5, "start2": 0.5, "end2": 0.5 }],
+                "speedEvents": [{ "startTime": 0, "endTime": 192, "value": 1 }, { "startTime": 192, "endTime": 1e9, "value": 2 }],
+                "notesAbove": [{ "type": 3, "time": 64, "positionX": 0, "holdTime": 128, "speed": 1, "floorPosition": 1 }],
+                "notesBelow": []
+            },
+            {
+                "bpm": 120,
+                "judgeLineDisappearEvents": [{ "startTime": 0, "endTime": 1e9, "start": 1, "end": 1 }],
+                "judgeLineRotateEvents": [{ "startTime": 0, "endTime": 1e9, "start": 0, "end": 0 }],
+                "judgeLineMoveEvents": [{ "startTime": 0, "endTime": 1e9, "start": 0.5, "end": 0.5, "start2": 0.5, "end2": 0.5 }],
+                "speedEvents": [{ "startTime": 0, "endTime": 1e9, "value": 1 }],
+                "notesAbove": [{ "type": 1, "time": 640, "positionX": 0, "holdTime": 0, "speed": 1, "floorPosition": 10 }],
+                "notesBelow": []
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn incremental_matches_batch() {
+        let batch = parse_phigros(CHART, ChartExtra::default()).unwrap();
+        let mut parsed = Vec::new();
+        let incremental = parse_phigros_incremental(CHART, ChartExtra::default(), |id| parsed.push(id)).unwrap();
+        assert_eq!(parsed, [0, 1]);
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+        for (a, b) in batch.lines.iter().zip(&incremental.lines) {
+            let (a_kfs, b_kfs) = (&a.height.keyframes, &b.height.keyframes);
+            assert_eq!(a_kfs.len(), b_kfs.len());
+            for (a, b) in a_kfs.iter().zip(b_kfs.iter()) {
+                assert!(close(a.time, b.time) && close(a.value, b.value), "{} {} vs {} {}", a.time, a.value, b.time, b.value);
+            }
+            for (a, b) in a.notes.iter().zip(&b.notes) {
+                assert!(close(a.height, b.height));
+                if let (NoteKind::Hold { end_height: a, .. }, NoteKind::Hold { end_height: b, .. }) = (&a.kind, &b.kind) {
+                    assert!(close(*a, *b));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mismatched_floor_position_is_flagged() {
+        assert!(check_phigros_floor_positions(CHART).unwrap().is_empty());
+        let source = CHART.replace(r#""floorPosition": 10"#, r#""floorPosition": 12"#);
+        let mismatches = check_phigros_floor_positions(&source).unwrap();
+        assert_eq!(mismatches.len(), 1);
+        let FloorPositionMismatch {
+            line,
+            above,
+            note,
+            expected,
+            actual,
+        } = mismatches[0];
+        assert_eq!((line, above, note, actual), (1, true, 0, 12.));
+        assert!((expected - 10.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn meta_settings_apply() {
+        let source = CHART.replacen(r#""offset": 0,"#, r#""offset": 0, "META": { "holdPartialCover": true, "noteScale": 1.5, "unknown": 1 },"#, 1);
+        let charts = [
+            parse_phigros(&source, ChartExtra::default()).unwrap(),
+            parse_phigros_incremental(&source, ChartExtra::default(), |_| {}).unwrap(),
+        ];
+        for chart in charts {
+            assert!(chart.settings.hold_partial_cover);
+            assert_eq!(chart.settings.note_scale, 1.5);
+        }
+        assert_eq!(parse_phigros(CHART, ChartExtra::default()).unwrap().settings.note_scale, 1.);
+    }
+
+    #[test]
+    fn meta_line_color() {
+        let source = CHART.replacen(r#""offset": 0,"#, r#""offset": 0, "META": { "lineColor": [255, 0, 128] },"#, 1);
+        let chart = parse_phigros(&source, ChartExtra::default()).unwrap();
+        assert_eq!(chart.settings.line_color, Some(Color::from_rgba(255, 0, 128, 255)));
+        assert_eq!(parse_phigros(CHART, ChartExtra::default()).unwrap().settings.line_color, None);
+    }
+
+    #[test]
+    fn timing_only_when_asked() {
+        let (_, timing) = parse_phigros_with(CHART, ChartExtra::default(), &ParseOptions::default()).unwrap();
+        assert!(timing.is_none());
+        let (chart, timing) = parse_phigros_with(CHART, ChartExtra::default(), &ParseOptions { timing: true }).unwrap();
+        let timing = timing.unwrap();
+        assert!(timing.deserialize + timing.events + timing.process_lines <= timing.total);
+        assert_eq!(chart.lines.len(), 2);
+    }
+}
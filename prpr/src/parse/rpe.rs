@@ -1,11 +1,11 @@
 crate::tl_file!("parser" ptl);
 
-use super::{process_lines, RPE_TWEEN_MAP};
+use super::{process_lines, ParseOptions, ParseTimer, ParseTiming, RPE_TWEEN_MAP};
 use crate::{
     core::{
-        Anim, AnimFloat, AnimVector, BezierTween, BpmList, Chart, ChartExtra, ChartSettings, ClampedTween, CtrlObject, GifFrames, HitSoundMap,
-        JudgeLine, JudgeLineCache, JudgeLineKind, Keyframe, Note, NoteKind, Object, StaticTween, Triple, TweenFunction, Tweenable, UIElement, EPS,
-        HEIGHT_RATIO,
+        Anim, AnimFloat, AnimVector, BezierTween, BlendMode, BpmList, Chart, ChartExtra, ChartSettings, ClampedTween, CtrlObject, GifFrames,
+        HitSoundMap, HitSoundParams, HoldKind, JudgeLine, JudgeLineCache, JudgeLineKind, Keyframe, Note, NoteKind, Object, SpawnDirection,
+        StaticTween, Triple, TweenFunction, Tweenable, UIElement, EPS, HEIGHT_RATIO, TWEEN_FUNCTIONS,
     },
     ext::{NotNanExt, SafeTexture},
     fs::FileSystem,
@@ -13,9 +13,11 @@ use crate::{
 };
 use anyhow::{Context, Result};
 use image::{codecs::gif, AnimationDecoder, DynamicImage, ImageError};
-use macroquad::prelude::{Color, WHITE};
+use macroquad::prelude::{get_internal_gl, Color, WHITE};
+use miniquad::TextureWrap;
 use sasa::AudioClip;
 use serde::Deserialize;
+use serde_json::{json, Value};
 use std::{cell::RefCell, collections::HashMap, future::IntoFuture, rc::Rc, str::FromStr, time::Duration};
 use tracing::debug;
 
@@ -98,11 +100,14 @@ impl From<RGBColor> for Color {
 struct RPEExtendedEvents {
     color_events: Option<Vec<RPEEvent<RGBColor>>>,
     text_events: Option<Vec<RPEEvent<String>>>,
+    text_rotate_events: Option<Vec<RPEEvent>>,
     scale_x_events: Option<Vec<RPEEvent>>,
     scale_y_events: Option<Vec<RPEEvent>>,
     incline_events: Option<Vec<RPEEvent>>,
     paint_events: Option<Vec<RPEEvent>>,
     gif_events: Option<Vec<RPEEvent>>,
+    z_order_events: Option<Vec<RPEEvent>>,
+    visible_events: Option<Vec<RPEEvent<bool>>>,
 }
 
 #[derive(Deserialize)]
@@ -122,6 +127,30 @@ struct RPENote {
     speed: f32,
     is_fake: u8,
     visible_time: f32,
+    #[serde(default)]
+    anchor: Option<(usize, usize)>,
+    #[serde(default)]
+    hold_kind: HoldKind,
+    #[serde(default)]
+    link: Option<u32>,
+    #[serde(default = "f32_one")]
+    weight: f32,
+    #[serde(default)]
+    flick_direction: Option<f32>,
+    #[serde(default)]
+    z_order: i8,
+    #[serde(default)]
+    no_autoplay: bool,
+    #[serde(default)]
+    spawn_time: Option<Triple>,
+    #[serde(default)]
+    invisible: bool,
+    #[serde(default)]
+    release_grace: Option<f32>,
+    #[serde(default = "f32_one")]
+    volume: f32,
+    #[serde(default = "f32_zero")]
+    pan: f32,
 }
 
 #[derive(Deserialize)]
@@ -143,6 +172,36 @@ struct RPEJudgeLine {
     z_order: i32,
     #[serde(rename = "attachUI")]
     attach_ui: Option<UIElement>,
+    #[serde(default)]
+    blend_mode: BlendMode,
+    /// Scales the horizontal positions of this line's notes, relative to the usual note spacing
+    note_width_ratio: Option<f32>,
+    #[serde(default)]
+    spawn_from: Option<SpawnDirection>,
+    #[serde(default)]
+    time_offset: f32,
+    #[serde(default)]
+    hide_when_empty: bool,
+    #[serde(default)]
+    tile_texture: bool,
+    #[serde(default)]
+    clip_to_playfield: bool,
+    #[serde(default)]
+    appear_distance: Option<f32>,
+    #[serde(default)]
+    reverse_notes: bool,
+    #[serde(default)]
+    attach_note: Option<(usize, usize)>,
+    #[serde(default)]
+    cull_back_face: bool,
+    #[serde(default = "f32_one")]
+    note_speed_scale: f32,
+    #[serde(default)]
+    note_offset: f32,
+    #[serde(default)]
+    note_tint_above: Option<RGBColor>,
+    #[serde(default)]
+    note_tint_below: Option<RGBColor>,
 
     #[serde(default)]
     pos_control: Vec<RPECtrlEvent>,
@@ -194,6 +253,7 @@ fn parse_events<T: Tweenable, V: Clone + Into<T>>(
         kfs.push(Keyframe {
             time: r.time(&e.start_time),
             value: e.start.clone().into(),
+            beat: Some(e.start_time.beats()),
             tween: {
                 let tween = RPE_TWEEN_MAP.get(e.easing_type.max(1) as usize).copied().unwrap_or(RPE_TWEEN_MAP[0]);
                 if e.bezier != 0 {
@@ -205,7 +265,7 @@ fn parse_events<T: Tweenable, V: Clone + Into<T>>(
                 }
             },
         });
-        kfs.push(Keyframe::new(r.time(&e.end_time), e.end.clone().into(), 0));
+        kfs.push(Keyframe::new(r.time(&e.end_time), e.end.clone().into(), 0).at_beat(e.end_time.beats()));
     }
     Ok(Anim::new(kfs))
 }
@@ -264,12 +324,14 @@ fn parse_speed_events(r: &mut BpmList, rpe: &[RPEEventLayer], max_time: f32) ->
                 time: now_time,
                 value: height,
                 tween: Rc::new(ClampedTween::new(7 /*quadOut*/, 0.0..(1. - end_speed / speed))),
+                beat: None,
             }
         } else {
             Keyframe {
                 time: now_time,
                 value: height,
                 tween: Rc::new(ClampedTween::new(6 /*quadIn*/, (speed / end_speed)..1.)),
+                beat: None,
             }
         });
         height += (speed + end_speed) * (end_time - now_time) / 2.;
@@ -303,6 +365,7 @@ fn parse_gif_events<V: Clone + Into<f32>>(r: &mut BpmList, rpe: &[RPEEvent<V>],
                     Rc::new(ClampedTween::new(tween, e.easing_left..e.easing_right))
                 }
             },
+            beat: None,
         });
         kfs.push(Keyframe::new(r.time(&e.end_time), e.end.clone().into(), 2));
         next_rep_time = (r.time(&e.end_time) * 1000. + gif.total_time() as f32 * (1. - e.end.clone().into())).round() as u128;
@@ -323,6 +386,7 @@ async fn parse_notes(
     rpe: Vec<RPENote>,
     fs: &mut dyn FileSystem,
     height: &mut AnimFloat,
+    width_ratio: f32,
     hitsounds: &mut HitSoundMap,
 ) -> Result<Vec<Note>> {
     let mut notes = Vec::new();
@@ -339,6 +403,7 @@ async fn parse_notes(
                 NoteKind::Hold {
                     end_time,
                     end_height: height.now(),
+                    kind: note.hold_kind,
                 }
             }
             3 => NoteKind::Flick,
@@ -380,7 +445,7 @@ async fn parse_notes(
                     let alpha = note.alpha.min(255) as f32 / 255.;
                     AnimFloat::new(vec![Keyframe::new(0.0, 0.0, 0), Keyframe::new(time - note.visible_time, alpha, 0)])
                 },
-                translation: AnimVector(AnimFloat::fixed(note.position_x / (RPE_WIDTH / 2.)), AnimFloat::fixed(y_offset)),
+                translation: AnimVector(AnimFloat::fixed(note.position_x / (RPE_WIDTH / 2.) * width_ratio), AnimFloat::fixed(y_offset)),
                 scale: AnimVector(
                     if note.size == 1.0 {
                         AnimFloat::default()
@@ -396,6 +461,23 @@ async fn parse_notes(
             time,
             height: note_height,
             speed: note.speed,
+            last_real_time: 0.0,
+            judged_time: None,
+            approach_inside: None,
+            subdivision: 0,
+            anchor: note.anchor,
+            link: note.link,
+            weight: note.weight,
+            flick_dir: note.flick_direction,
+            z: note.z_order,
+            no_autoplay: note.no_autoplay,
+            spawn_time: note.spawn_time.as_ref().map(|it| r.time(it)),
+            invisible: note.invisible,
+            release_grace: note.release_grace,
+            sound: HitSoundParams {
+                volume: note.volume,
+                pan: note.pan,
+            },
 
             above: note.above == 1,
             multiple_hint: false,
@@ -406,6 +488,18 @@ async fn parse_notes(
     Ok(notes)
 }
 
+/// Visibility switches at the start of each event and holds until the next one; easings and `end` are ignored.
+fn parse_visible_events(r: &mut BpmList, rpe: &[RPEEvent<bool>]) -> Anim<bool> {
+    let mut kfs = Vec::new();
+    if rpe.first().map_or(true, |it| it.start_time.beats() != 0.0) {
+        kfs.push(Keyframe::new(0.0, true, 0));
+    }
+    for e in rpe {
+        kfs.push(Keyframe::new(r.time(&e.start_time), e.start, 0).at_beat(e.start_time.beats()));
+    }
+    Anim::new(kfs)
+}
+
 fn parse_ctrl_events(rpe: &[RPECtrlEvent], key: &str) -> AnimFloat {
     let vals: Vec<_> = rpe.iter().map(|it| it.value[key]).collect();
     if rpe.is_empty() || (rpe.len() == 2 && rpe[0].easing == 1 && (vals[0] - 1.).abs() < 1e-4) {
@@ -419,6 +513,21 @@ fn parse_ctrl_events(rpe: &[RPECtrlEvent], key: &str) -> AnimFloat {
     )
 }
 
+async fn load_line_texture(fs: &mut dyn FileSystem, path: &str, tile: bool) -> Result<SafeTexture> {
+    let texture = SafeTexture::from(image::load_from_memory(
+        &fs.load_file(path)
+            .await
+            .with_context(|| ptl!("illustration-load-failed", "path" => path.to_owned()))?,
+    )?)
+    .with_mipmap();
+    if tile {
+        // every line loads its own texture, so changing the wrap mode here doesn't affect anything else
+        let ctx = unsafe { get_internal_gl() }.quad_context;
+        texture.raw_miniquad_texture_handle().set_wrap(ctx, TextureWrap::Repeat);
+    }
+    Ok(texture)
+}
+
 async fn parse_judge_line(
     r: &mut BpmList,
     rpe: RPEJudgeLine,
@@ -446,7 +555,7 @@ async fn parse_judge_line(
         Ok(res)
     }
     let mut height = parse_speed_events(r, &event_layers, max_time)?;
-    let mut notes = parse_notes(r, rpe.notes.unwrap_or_default(), fs, &mut height, hitsounds).await?;
+    let mut notes = parse_notes(r, rpe.notes.unwrap_or_default(), fs, &mut height, rpe.note_width_ratio.unwrap_or(1.), hitsounds).await?;
     let cache = JudgeLineCache::new(&mut notes);
     Ok(JudgeLine {
         object: Object {
@@ -516,7 +625,14 @@ async fn parse_judge_line(
                 )
             } else if let Some(extended) = rpe.extended.as_ref() {
                 if let Some(events) = extended.text_events.as_ref() {
-                    JudgeLineKind::Text(parse_events(r, events, Some(String::new()), bezier_map).with_context(|| ptl!("text-events-parse-failed"))?)
+                    JudgeLineKind::Text(
+                        parse_events(r, events, Some(String::new()), bezier_map).with_context(|| ptl!("text-events-parse-failed"))?,
+                        if let Some(events) = extended.text_rotate_events.as_ref() {
+                            parse_events(r, events, Some(0.), bezier_map).with_context(|| ptl!("text-rotate-events-parse-failed"))?
+                        } else {
+                            AnimFloat::default()
+                        },
+                    )
                 } else {
                     JudgeLineKind::Normal
                 }
@@ -549,26 +665,10 @@ async fn parse_judge_line(
                 let events = parse_gif_events(r, events, bezier_map, &frames).with_context(|| ptl!("gif-events-parse-failed"))?;
                 JudgeLineKind::TextureGif(events, frames, rpe.texture.clone())
             } else {
-                JudgeLineKind::Texture(
-                    SafeTexture::from(image::load_from_memory(
-                        &fs.load_file(&rpe.texture)
-                            .await
-                            .with_context(|| ptl!("illustration-load-failed", "path" => rpe.texture.clone()))?,
-                    )?)
-                    .with_mipmap(),
-                    rpe.texture.clone(),
-                )
+                JudgeLineKind::Texture(load_line_texture(fs, &rpe.texture, rpe.tile_texture).await?, rpe.texture.clone())
             }
         } else {
-            JudgeLineKind::Texture(
-                SafeTexture::from(image::load_from_memory(
-                    &fs.load_file(&rpe.texture)
-                        .await
-                        .with_context(|| ptl!("illustration-load-failed", "path" => rpe.texture.clone()))?,
-                )?)
-                .with_mipmap(),
-                rpe.texture.clone(),
-            )
+            JudgeLineKind::Texture(load_line_texture(fs, &rpe.texture, rpe.tile_texture).await?, rpe.texture.clone())
         },
         color: if let Some(events) = rpe.extended.as_ref().and_then(|e| e.color_events.as_ref()) {
             parse_events(r, events, Some(WHITE), bezier_map).with_context(|| ptl!("color-events-parse-failed"))?
@@ -584,8 +684,33 @@ async fn parse_judge_line(
             }
         },
         z_index: rpe.z_order,
+        z_index_anim: if let Some(events) = rpe.extended.as_ref().and_then(|e| e.z_order_events.as_ref()) {
+            parse_events(r, events, None, bezier_map).with_context(|| ptl!("z-order-events-parse-failed"))?
+        } else {
+            AnimFloat::default()
+        },
         show_below: rpe.is_cover != 1,
         attach_ui: rpe.attach_ui,
+        blend_mode: rpe.blend_mode,
+        spawn_from: rpe.spawn_from,
+        time_offset: rpe.time_offset,
+        notes_disabled: false,
+        hide_when_empty: rpe.hide_when_empty,
+        tile_texture: rpe.tile_texture,
+        mirrored: false,
+        clip_to_playfield: rpe.clip_to_playfield,
+        appear_distance: rpe.appear_distance,
+        reverse_notes: rpe.reverse_notes,
+        attach_note: rpe.attach_note,
+        cull_back_face: rpe.cull_back_face,
+        note_speed_scale: rpe.note_speed_scale,
+        note_offset: rpe.note_offset,
+        visible: if let Some(events) = rpe.extended.as_ref().and_then(|e| e.visible_events.as_ref()) {
+            parse_visible_events(r, events)
+        } else {
+            Anim::default()
+        },
+        note_tints: (rpe.note_tint_above.map(Color::from), rpe.note_tint_below.map(Color::from)),
 
         cache,
     })
@@ -620,7 +745,20 @@ fn get_bezier_map(rpe: &RPEChart) -> BezierMap {
 }
 
 pub async fn parse_rpe(source: &str, fs: &mut dyn FileSystem, extra: ChartExtra) -> Result<Chart> {
+    Ok(parse_rpe_with(source, fs, extra, &ParseOptions::default()).await?.0)
+}
+
+/// Parses a RPE chart, also returning how long each stage took if [`ParseOptions::timing`] is set.
+pub async fn parse_rpe_with(
+    source: &str,
+    fs: &mut dyn FileSystem,
+    extra: ChartExtra,
+    options: &ParseOptions,
+) -> Result<(Chart, Option<ParseTiming>)> {
+    let mut timer = ParseTimer::new(options);
+    let source = super::clean_source(source);
     let rpe: RPEChart = serde_json::from_str(source).with_context(|| ptl!("json-parse-failed"))?;
+    timer.lap(|it| &mut it.deserialize);
     let bezier_map = get_bezier_map(&rpe);
     let mut r = BpmList::new(rpe.bpm_list.into_iter().map(|it| (it.start_time.beats(), it.bpm)).collect());
     fn vec<T>(v: &Option<Vec<T>>) -> impl Iterator<Item = &T> {
@@ -668,6 +806,423 @@ pub async fn parse_rpe(source: &str, fs: &mut dyn FileSystem, extra: ChartExtra)
                 .with_context(move || ptl!("judge-line-location-name", "jlid" => id, "name" => name))?,
         );
     }
+    timer.lap(|it| &mut it.events);
     process_lines(&mut lines);
-    Ok(Chart::new(rpe.meta.offset as f32 / 1000.0, lines, r, ChartSettings::default(), extra, hitsounds))
-}
\ No newline at end of file
+    timer.lap(|it| &mut it.process_lines);
+    let chart = Chart::new(rpe.meta.offset as f32 / 1000.0, lines, r, ChartSettings::default(), extra, hitsounds);
+    Ok((chart, timer.finish()))
+}
+
+const DUMP_MAX_DENOMINATOR: u32 = 960;
+
+fn dump_beats(beats: f32) -> Value {
+    let whole = beats.floor();
+    let frac = beats - whole;
+    let den = (1..DUMP_MAX_DENOMINATOR)
+        .find(|den| ((frac * *den as f32).round() / *den as f32 - frac).abs() < 1e-3)
+        .unwrap_or(DUMP_MAX_DENOMINATOR);
+    json!([whole as i32, (frac * den as f32).round() as u32, den])
+}
+
+/// Finds the RPE easing type closest to the given tween, or `None` if it's a hold tween (x |-> 0).
+fn dump_easing(tween: &dyn TweenFunction) -> Option<usize> {
+    const SAMPLES: [f32; 5] = [0.1, 0.25, 0.5, 0.75, 0.9];
+    if SAMPLES.iter().chain(std::iter::once(&1.)).all(|x| tween.y(*x).abs() < EPS) {
+        return None;
+    }
+    (1..RPE_TWEEN_MAP.len()).min_by_key(|i| {
+        let f = TWEEN_FUNCTIONS[RPE_TWEEN_MAP[*i] as usize];
+        SAMPLES.iter().map(|x| (f(*x) - tween.y(*x)).abs()).sum::<f32>().not_nan()
+    })
+}
+
+fn dump_events<T: Tweenable>(r: &mut BpmList, anim: &Anim<T>, value: &impl Fn(&T) -> Value) -> Vec<Value> {
+    let event = |r: &mut BpmList, start: &Keyframe<T>, end: &Keyframe<T>| {
+        let (end_value, easing) = match dump_easing(start.tween.as_ref()) {
+            Some(easing) => (&end.value, easing),
+            None => (&start.value, 1),
+        };
+        json!({
+            "easingType": easing,
+            "start": value(&start.value),
+            "end": value(end_value),
+            "startTime": dump_beats(r.beat(start.time)),
+            "endTime": dump_beats(r.beat(end.time)),
+        })
+    };
+    let kfs = &anim.keyframes;
+    // zero-length segments are jumps, which the start of the next event already accounts for
+    let events: Vec<_> = kfs
+        .windows(2)
+        .filter(|it| it[0].time < it[1].time)
+        .map(|it| event(r, &it[0], &it[1]))
+        .collect();
+    if events.is_empty() {
+        kfs.first().map(|kf| vec![event(r, kf, kf)]).unwrap_or_default()
+    } else {
+        events
+    }
+}
+
+/// Dumps every element of an anim chain, which correspond to the event layers.
+fn dump_layers(r: &mut BpmList, anim: &AnimFloat, factor: f32) -> Vec<Vec<Value>> {
+    let mut res = Vec::new();
+    let mut anim = Some(anim);
+    while let Some(now) = anim {
+        res.push(dump_events(r, now, &|v| json!(v / factor)));
+        anim = now.next.as_deref();
+    }
+    res
+}
+
+fn dump_speed_events(r: &mut BpmList, height: &AnimFloat) -> Vec<Value> {
+    const DELTA: f32 = 1e-3;
+    height
+        .keyframes
+        .windows(2)
+        .filter(|it| it[0].time < it[1].time)
+        .map(|it| {
+            let (start, end) = (&it[0], &it[1]);
+            let speed = (end.value - start.value) / (end.time - start.time) / SPEED_RATIO;
+            let tween = start.tween.as_ref();
+            // the slopes at both ends of the tween give us the speed ramp the parser would produce
+            let (start_speed, end_speed) = if dump_easing(tween).is_some() {
+                (speed * tween.y(DELTA) / DELTA, speed * (1. - tween.y(1. - DELTA)) / DELTA)
+            } else {
+                (speed, speed)
+            };
+            json!({
+                "start": start_speed,
+                "end": end_speed,
+                "startTime": dump_beats(r.beat(start.time)),
+                "endTime": dump_beats(r.beat(end.time)),
+            })
+        })
+        .collect()
+}
+
+fn dump_ctrl_events(anim: &AnimFloat, key: &str) -> Vec<Value> {
+    anim.keyframes
+        .iter()
+        .map(|kf| {
+            json!({
+                "easing": dump_easing(kf.tween.as_ref()).unwrap_or(1),
+                "x": kf.time,
+                key: kf.value,
+            })
+        })
+        .collect()
+}
+
+fn dump_note(r: &mut BpmList, note: &Note) -> Value {
+    let first_value = |anim: &AnimFloat, default: f32| anim.keyframes.first().map_or(default, |it| it.value);
+    let alpha = &note.object.alpha.keyframes;
+    let (alpha, visible_time) = if alpha.len() == 2 && alpha[0].value == 0. {
+        (alpha[1].value, note.time - alpha[1].time)
+    } else {
+        (first_value(&note.object.alpha, 1.), 999999.)
+    };
+    let (kind, end_time, hold_kind) = match &note.kind {
+        NoteKind::Click => (1, note.time, HoldKind::default()),
+        NoteKind::Hold { end_time, kind, .. } => (2, *end_time, *kind),
+        NoteKind::Flick => (3, note.time, HoldKind::default()),
+        NoteKind::Drag => (4, note.time, HoldKind::default()),
+    };
+    let y_offset = first_value(&note.object.translation.1, 0.);
+    json!({
+        "type": kind,
+        "above": if note.above { 1 } else { 0 },
+        "startTime": dump_beats(r.beat(note.time)),
+        "endTime": dump_beats(r.beat(end_time)),
+        "positionX": first_value(&note.object.translation.0, 0.) * (RPE_WIDTH / 2.),
+        "yOffset": if note.speed == 0. { 0. } else { y_offset * (RPE_HEIGHT / 2.) / note.speed },
+        "alpha": (alpha * 255.).round() as u16,
+        "size": first_value(&note.object.scale.0, 1.),
+        "speed": note.speed,
+        "isFake": if note.fake { 1 } else { 0 },
+        "visibleTime": visible_time,
+        "holdKind": hold_kind,
+        "link": note.link,
+        "weight": note.weight,
+        "flickDirection": note.flick_dir,
+        "zOrder": note.z,
+        "noAutoplay": note.no_autoplay,
+        "spawnTime": note.spawn_time.map(|it| dump_beats(r.beat(it))),
+        "invisible": note.invisible,
+        "releaseGrace": note.release_grace,
+        "volume": note.sound.volume,
+        "pan": note.sound.pan,
+    })
+}
+
+fn dump_judge_line(r: &mut BpmList, id: usize, line: &JudgeLine) -> Result<Value> {
+    fn opt(events: Vec<Value>) -> Option<Vec<Value>> {
+        Some(events).filter(|it| !it.is_empty())
+    }
+    let object = &line.object;
+    let mut alpha = dump_layers(r, &object.alpha, 1. / 255.).into_iter();
+    let mut rotate = dump_layers(r, &object.rotation, -1.).into_iter();
+    let mut move_x = dump_layers(r, &object.translation.0, 2. / RPE_WIDTH).into_iter();
+    let mut move_y = dump_layers(r, &object.translation.1, 2. / RPE_HEIGHT).into_iter();
+    let mut speed = opt(dump_speed_events(r, &line.height));
+    let layer_count = [alpha.len(), rotate.len(), move_x.len(), move_y.len()]
+        .into_iter()
+        .max()
+        .unwrap_or_default()
+        .max(1);
+    let event_layers: Vec<_> = (0..layer_count)
+        .map(|_| {
+            json!({
+                "alphaEvents": alpha.next().and_then(opt),
+                "moveXEvents": move_x.next().and_then(opt),
+                "moveYEvents": move_y.next().and_then(opt),
+                "rotateEvents": rotate.next().and_then(opt),
+                "speedEvents": speed.take(),
+            })
+        })
+        .collect();
+    let mut extended = json!({});
+    match &line.kind {
+        JudgeLineKind::Normal => {}
+        JudgeLineKind::Texture(_) => ptl!(bail "dump-texture-line-unsupported", "jlid" => id),
+        JudgeLineKind::Text(anim, rotation) => {
+            extended["textEvents"] = json!(dump_events(r, anim, &|v| json!(v)));
+            if !rotation.keyframes.is_empty() {
+                extended["textRotateEvents"] = json!(dump_events(r, rotation, &|v| json!(v)));
+            }
+        }
+        JudgeLineKind::Paint(anim, _) => extended["paintEvents"] = json!(dump_events(r, anim, &|v| json!(v))),
+    }
+    if !line.z_index_anim.keyframes.is_empty() {
+        extended["zOrderEvents"] = json!(dump_events(r, &line.z_index_anim, &|v| json!(v)));
+    }
+    let scale_x_factor = if matches!(line.kind, JudgeLineKind::Text(..)) || line.attach_ui.is_some() {
+        1.
+    } else {
+        0.5
+    };
+    extended["scaleXEvents"] = json!(opt(dump_events(r, &object.scale.0, &|v| json!(v / scale_x_factor))));
+    extended["scaleYEvents"] = json!(opt(dump_events(r, &object.scale.1, &|v| json!(v))));
+    extended["inclineEvents"] = json!(opt(dump_events(r, &line.incline, &|v| json!(v))));
+    let rgb = |c: &Color| {
+        let int = |v: f32| (v * 255.).round() as u8;
+        json!([int(c.r), int(c.g), int(c.b)])
+    };
+    extended["colorEvents"] = json!(opt(dump_events(r, &line.color, &rgb)));
+    // one event per switch, see `parse_visible_events`; segments would lose the last one
+    let kfs = &line.visible.keyframes;
+    let visible = kfs
+        .iter()
+        .enumerate()
+        .map(|(i, kf)| {
+            let end = kfs.get(i + 1).map_or(kf.time, |it| it.time);
+            json!({
+                "easingType": 1,
+                "start": kf.value,
+                "end": kf.value,
+                "startTime": dump_beats(r.beat(kf.time)),
+                "endTime": dump_beats(r.beat(end)),
+            })
+        })
+        .collect();
+    extended["visibleEvents"] = json!(opt(visible));
+    let ctrl_obj = line.ctrl_obj.borrow();
+    Ok(json!({
+        "Name": format!("line {id}"),
+        "Texture": "line.png",
+        "father": line.parent.map_or(-1, |it| it as isize),
+        "eventLayers": event_layers,
+        "extended": extended,
+        "notes": line.notes.iter().map(|note| dump_note(r, note)).collect::<Vec<_>>(),
+        "isCover": if line.show_below { 0 } else { 1 },
+        "zOrder": line.z_index,
+        "attachUI": line.attach_ui,
+        "blendMode": line.blend_mode,
+        "spawnFrom": line.spawn_from,
+        "timeOffset": line.time_offset,
+        "hideWhenEmpty": line.hide_when_empty,
+        "tileTexture": line.tile_texture,
+        "clipToPlayfield": line.clip_to_playfield,
+        "appearDistance": line.appear_distance,
+        "reverseNotes": line.reverse_notes,
+        "attachNote": line.attach_note,
+        "cullBackFace": line.cull_back_face,
+        "noteSpeedScale": line.note_speed_scale,
+        "noteOffset": line.note_offset,
+        "noteTintAbove": line.note_tints.0.as_ref().map(rgb),
+        "noteTintBelow": line.note_tints.1.as_ref().map(rgb),
+        "posControl": dump_ctrl_events(&ctrl_obj.pos, "pos"),
+        "sizeControl": dump_ctrl_events(&ctrl_obj.size, "size"),
+        "alphaControl": dump_ctrl_events(&ctrl_obj.alpha, "alpha"),
+        "yControl": dump_ctrl_events(&ctrl_obj.y, "y"),
+    }))
+}
+
+/// Serializes the chart to RPE's JSON format, so that [`parse_rpe`] gives back an equivalent chart.
+///
+/// Easings are matched to the closest RPE easing type, and lines with custom textures aren't supported since the paths
+/// of their textures aren't kept.
+pub fn dump_rpe(chart: &Chart) -> Result<String> {
+    let mut bpm_list = chart.bpm_list.borrow_mut();
+    // charts from other formats don't have BPMs, so we make one beat a second
+    let mut fallback = BpmList::new(vec![(0., 60.)]);
+    let r = if bpm_list.is_empty() { &mut fallback } else { &mut *bpm_list };
+    let bpm_items: Vec<_> = r
+        .ranges()
+        .map(|(beats, bpm)| json!({ "bpm": bpm, "startTime": dump_beats(beats) }))
+        .collect();
+    let lines = chart
+        .lines
+        .iter()
+        .enumerate()
+        .map(|(id, line)| dump_judge_line(r, id, line))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(serde_json::to_string(&json!({
+        "META": { "offset": (chart.offset * 1000.).round() as i32 },
+        "BPMList": bpm_items,
+        "judgeLineList": lines,
+    }))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_pec;
+    use async_trait::async_trait;
+    use std::any::Any;
+
+    struct NoFiles;
+
+    #[async_trait]
+    impl FileSystem for NoFiles {
+        async fn load_file(&mut self, path: &str) -> Result<Vec<u8>> {
+            anyhow::bail!("no such file: {path}")
+        }
+
+        async fn exists(&mut self, _path: &str) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn list_root(&self) -> Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+
+        fn clone_box(&self) -> Box<dyn FileSystem> {
+            Box::new(NoFiles)
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    fn parse(source: &str) -> Chart {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(parse_rpe(source, &mut NoFiles, ChartExtra::default()))
+            .unwrap()
+    }
+
+    fn pec(notes: &str) -> Chart {
+        parse_pec(&format!("0\nbp 0 120\n{notes}"), ChartExtra::default()).unwrap()
+    }
+
+    /// The RPE JSON of a PEC chart with the given notes, to be modified by tests.
+    fn rpe(notes: &str) -> Value {
+        serde_json::from_str(&dump_rpe(&pec(notes)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn dump_beats_as_fractions() {
+        assert_eq!(dump_beats(3.), json!([3, 0, 1]));
+        assert_eq!(dump_beats(2.5), json!([2, 1, 2]));
+        assert_eq!(dump_beats(1.75), json!([1, 3, 4]));
+        assert_eq!(dump_beats(1. / 3.), json!([0, 1, 3]));
+    }
+
+    #[test]
+    fn dump_easing_finds_rpe_types() {
+        assert_eq!(dump_easing(&StaticTween(0)), None);
+        for (i, tween) in RPE_TWEEN_MAP.iter().enumerate().skip(1) {
+            let easing = dump_easing(&StaticTween(*tween)).unwrap();
+            assert_eq!(RPE_TWEEN_MAP[easing], *tween, "easing type {i}");
+        }
+    }
+
+    #[test]
+    fn dump_round_trips() {
+        let chart = pec("n1 0 1 256 1 0\nn2 0 2 3 -256 0 0\nn4 0 4.5 0 1 0\n");
+        let parsed = parse(&dump_rpe(&chart).unwrap());
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+        let x = |note: &Note| note.object.translation.0.keyframes.first().map_or(0., |it| it.value);
+        assert_eq!(chart.lines.len(), parsed.lines.len());
+        for (a, b) in chart.lines.iter().zip(&parsed.lines) {
+            assert_eq!(a.notes.len(), b.notes.len());
+            for (a, b) in a.notes.iter().zip(&b.notes) {
+                assert!(close(a.time, b.time), "{} vs {}", a.time, b.time);
+                assert!(close(x(a), x(b)), "{} vs {}", x(a), x(b));
+                assert_eq!((a.above, a.fake), (b.above, b.fake));
+                match (&a.kind, &b.kind) {
+                    (NoteKind::Hold { end_time: a, .. }, NoteKind::Hold { end_time: b, .. }) => assert!(close(*a, *b)),
+                    (a, b) => assert_eq!(std::mem::discriminant(a), std::mem::discriminant(b)),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn note_width_ratio_scales_positions() {
+        let mut rpe = rpe("n1 0 1 256 1 0\nn1 1 1 256 1 0\n");
+        rpe["judgeLineList"][1]["noteWidthRatio"] = json!(2.);
+        let chart = parse(&rpe.to_string());
+        let x = |line: usize| chart.lines[line].notes[0].object.translation.0.keyframes[0].value;
+        assert!(x(0) > 0.);
+        assert!((x(1) - 2. * x(0)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn text_rotation_round_trips() {
+        let mut chart = pec("n1 0 1 0 1 0\n");
+        let rotation = AnimFloat::new(vec![Keyframe::new(0., 0., 2), Keyframe::new(1., 90., 2)]);
+        chart.lines[0].kind = JudgeLineKind::Text(Anim::fixed("text".to_owned()), rotation);
+        let mut parsed = parse(&dump_rpe(&chart).unwrap());
+        let JudgeLineKind::Text(text, rotation) = &mut parsed.lines[0].kind else {
+            panic!("expected a text line");
+        };
+        text.set_time(0.5);
+        rotation.set_time(0.5);
+        assert_eq!(text.now(), "text");
+        assert!((rotation.now() - 45.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn release_grace_round_trips() {
+        let mut rpe = rpe("n2 0 1 3 0 1 0\nn2 0 4 5 0 1 0\n");
+        rpe["judgeLineList"][0]["notes"][1]["releaseGrace"] = json!(0.3);
+        let chart = parse(&rpe.to_string());
+        let grace = |chart: &Chart| chart.lines[0].notes.iter().map(|it| it.release_grace).collect::<Vec<_>>();
+        assert_eq!(grace(&chart), [None, Some(0.3)]);
+        assert_eq!(grace(&parse(&dump_rpe(&chart).unwrap())), [None, Some(0.3)]);
+    }
+
+    #[test]
+    fn reverse_holds_are_parsed() {
+        let mut rpe = rpe("n2 0 1 3 0 1 0\n");
+        rpe["judgeLineList"][0]["notes"][0]["holdKind"] = json!("reverse");
+        let chart = parse(&rpe.to_string());
+        assert!(matches!(chart.lines[0].notes[0].kind, NoteKind::Hold { kind: HoldKind::Reverse, .. }));
+        let parsed = parse(&dump_rpe(&chart).unwrap());
+        assert!(matches!(parsed.lines[0].notes[0].kind, NoteKind::Hold { kind: HoldKind::Reverse, .. }));
+    }
+
+    #[test]
+    fn time_offset_is_parsed() {
+        let mut rpe = rpe("n1 0 1 0 1 0\nn1 1 1 0 1 0\n");
+        rpe["judgeLineList"][1]["timeOffset"] = json!(0.25);
+        let chart = parse(&rpe.to_string());
+        assert_eq!(chart.lines[0].time_offset, 0.);
+        assert_eq!(chart.lines[1].time_offset, 0.25);
+        assert_eq!(parse(&dump_rpe(&chart).unwrap()).lines[1].time_offset, 0.25);
+    }
+}
@@ -5,12 +5,12 @@ crate::tl_file!("game");
 use super::{draw_background, ending::RecordUpdateState, request_input, return_input, show_message, take_input, EndingScene, NextScene, Scene};
 use crate::{
     config::Config,
-    core::{copy_fbo, BadNote, Chart, ChartExtra, Effect, Point, Resource, UIElement, Vector, JUDGE_LINE_GOOD_COLOR, JUDGE_LINE_PERFECT_COLOR},
+    core::{copy_fbo, hit_fx_step, BadNote, Chart, ChartExtra, Effect, Point, Resource, UIElement, Vector},
     ext::{screen_aspect, RectExt, SafeTexture},
     fs::FileSystem,
     info::{ChartFormat, ChartInfo},
     judge::Judge,
-    parse::{parse_extra, parse_pec, parse_phigros, parse_rpe},
+    parse::{parse_bms, parse_extra, parse_pec, parse_phigros, parse_rpe},
     task::Task,
     time::TimeManager,
     ui::{RectButton, Ui},
@@ -40,6 +40,7 @@ pub static FFMPEG_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
 
 const WAIT_TIME: f32 = 0.5;
 const AFTER_TIME: f32 = 0.7;
+const EARLY_LATE_TIME: f32 = 0.5;
 
 fn fmt_time(t: f32) -> String {
     let f = t < 0.;
@@ -112,8 +113,8 @@ macro_rules! reset {
     ($self:ident, $res:expr, $tm:ident) => {{
         $self.bad_notes.clear();
         $self.judge.reset();
-        $self.chart.reset();
-        $res.judge_line_color = JUDGE_LINE_PERFECT_COLOR;
+        $self.chart.reset_play_state();
+        $res.judge_line_color = $res.line_colors.all_perfect;
         $self.music.pause()?;
         $self.music.seek_to(0.)?;
         $tm.reset();
@@ -156,23 +157,15 @@ impl GameScene {
             ChartExtra::default()
         };
         let text = String::from_utf8(Self::load_chart_bytes(fs, info).await.context("Failed to load chart")?)?;
-        let format = info.format.clone().unwrap_or_else(|| {
-            if text.starts_with('{') {
-                if text.contains("\"META\"") {
-                    ChartFormat::Rpe
-                } else {
-                    ChartFormat::Pgr
-                }
-            } else {
-                ChartFormat::Pec
-            }
-        });
+        let format = info.format.clone().unwrap_or_else(|| ChartFormat::infer(&text));
         let mut chart = match format {
             ChartFormat::Rpe => parse_rpe(&text, fs, extra).await,
             ChartFormat::Pgr => parse_phigros(&text, extra),
             ChartFormat::Pec => parse_pec(&text, extra),
+            ChartFormat::Bms => parse_bms(&text, extra),
         }?;
         chart.settings.hold_partial_cover = info.hold_partial_cover;
+        chart.assign_subdivisions();
         Ok((chart, text, format))
     }
 
@@ -210,9 +203,11 @@ impl GameScene {
         let mut res = Resource::new(config, info, fs, avatar, background, illustration, chart.extra.effects.is_empty() && effects.is_empty())
             .await
             .context("Failed to load resources")?;
+        res.apply_chart_settings(&chart.settings);
         let exercise_range = (chart.offset + info_offset + res.config.offset)..res.track_length;
 
-        let judge = Judge::new(&chart);
+        let mut judge = Judge::new(&chart);
+        judge.combo_milestone = res.config.combo_milestone;
 
         let music = Self::new_music(&mut res)?;
         Ok(Self {
@@ -353,6 +348,14 @@ impl GameScene {
                     .draw();
             });
         }
+        if let Some((number, alpha)) = self.chart.countdown(res.time) {
+            ui.text(number.to_string())
+                .pos(0., 0.)
+                .anchor(0.5, 0.5)
+                .size(2.)
+                .color(Color::new(1., 1., 1., alpha * c.a))
+                .draw();
+        }
         let lf = -1. + margin;
         let bt = -top - eps * 2.8;
         self.chart.with_element(ui, res, UIElement::Name, |ui, color, scale| {
@@ -373,6 +376,23 @@ impl GameScene {
                 .scale(scale)
                 .draw();
         });
+        if res.config.show_early_late {
+            if let Some(err) = self.judge.last_error.filter(|it| res.time - it.time < EARLY_LATE_TIME) {
+                let line = &self.chart.lines[err.line_id];
+                let (pos, _) = line.screen_transform(res, &self.chart.lines);
+                let a = 1. - (res.time - err.time) / EARLY_LATE_TIME;
+                ui.text(if err.diff < 0. { "EARLY" } else { "LATE" })
+                    .pos(pos.x, pos.y - 0.05)
+                    .anchor(0.5, 1.)
+                    .size(0.4)
+                    .color(if err.diff < 0. {
+                        Color::new(0.4, 0.7, 1., a * c.a)
+                    } else {
+                        Color::new(1., 0.5, 0.4, a * c.a)
+                    })
+                    .draw();
+            }
+        }
         let hw = 0.003;
         let height = eps * 1.2;
         let dest = 2. * res.time / res.track_length;
@@ -782,23 +802,27 @@ impl Scene for GameScene {
                 self.res.track_length
             }
         };
-        let time = (time - offset).max(0.);
-        self.res.time = time;
+        let time = if matches!(self.state, State::Playing) {
+            // straight from the time manager, so that no precision is lost
+            self.chart.time_at(tm.now(), self.res.config.offset + self.info_offset)
+        } else {
+            (time - offset).max(0.) as f64
+        };
+        self.res.advance_time(time);
         if !tm.paused() && self.pause_rewind.is_none() {
             self.gl.quad_gl.viewport(self.res.camera.viewport);
             self.judge.update(&mut self.res, &mut self.chart, &mut self.bad_notes);
             self.gl.quad_gl.viewport(None);
         }
-        let counts = self.judge.counts();
-        self.res.judge_line_color = if counts[2] + counts[3] == 0 {
-            if counts[1] == 0 {
-                JUDGE_LINE_PERFECT_COLOR
-            } else {
-                JUDGE_LINE_GOOD_COLOR
+        if self.judge.take_milestone().is_some() {
+            // bursts around the combo number
+            let y = -1. / self.res.aspect_ratio + 0.1;
+            let color = self.res.hit_colors.perfect;
+            for x in [-0.2, 0., 0.2] {
+                self.res.emit_at(vec2(x, y), 0., color);
             }
-        } else {
-            WHITE
-        };
+        }
+        self.res.judge_line_color = self.res.line_colors.of(self.judge.all_perfect(), self.judge.full_combo());
         self.res.judge_line_color.a *= self.res.alpha;
         self.chart.update(&mut self.res);
         let res = &mut self.res;
@@ -948,7 +972,7 @@ impl Scene for GameScene {
         let t = tm.real_time();
         let dt = (t - std::mem::replace(&mut self.last_update_time, t)) as f32;
         if res.config.particle {
-            res.emitter.draw(dt);
+            res.emitter.draw(hit_fx_step(&res.config, dt));
         }
         self.ui(ui, tm)?;
         self.overlay_ui(ui, tm)?;